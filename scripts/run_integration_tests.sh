@@ -19,4 +19,5 @@ trap on_exit EXIT
 # --test '*' means only run the integration tests
 # https://github.com/rust-lang/cargo/issues/8396#issuecomment-713126649
 # --nocapture is used so that we see the commands being executed interleaved within the logged info
-GIT_DAEMON_BASE_PATH_TRACKER="$git_daemon_base_path_tracker" cargo test --test '*' -- --nocapture
+# --test-threads=1 is used because the tests of a binary share the same log file
+GIT_DAEMON_BASE_PATH_TRACKER="$git_daemon_base_path_tracker" cargo test --test '*' -- --nocapture --test-threads=1
@@ -0,0 +1,353 @@
+use hyper::{Body, Method, Request, Response, StatusCode};
+use ring::constant_time::verify_slices_are_equal;
+use serde_json::json;
+use snafu::OptionExt;
+use std::collections::BTreeMap;
+
+use crate::{
+	config::MainConfig,
+	db,
+	error::*,
+	event_queue, lock,
+	webhook::{cleanup_pr, AppState, MergeRequest, PullRequestCleanupReason},
+	Result,
+};
+
+/*
+	The admin API is served under /admin/. It's only enabled if ADMIN_TOKEN is
+	set, in which case requests have to be authenticated with the
+	"Authorization: Bearer {ADMIN_TOKEN}" header.
+
+	- POST /admin/events/{delivery_id}/replay processes a stored webhook delivery
+		again (see event_queue.rs)
+	- GET /admin/merge-requests lists the pending merge requests, including their
+		dependencies
+	- GET /admin/merge-requests/{owner}/{repo}/{number} shows a single pull
+		request's merge request
+	- POST /admin/merge-requests/{owner}/{repo}/{number}/cancel cancels a pending
+		merge like `bot merge cancel` does
+	- GET /admin/dependency-graph shows which pull requests each pending merge
+		request is waiting on and which ones are waiting on it (see
+		dependency_graph)
+*/
+
+fn json_response(
+	status: StatusCode,
+	body: serde_json::Value,
+) -> Result<Response<Body>> {
+	Response::builder()
+		.status(status)
+		.header("Content-Type", "application/json")
+		.body(Body::from(body.to_string()))
+		.ok()
+		.context(Message {
+			msg: "Error building response".to_owned(),
+		})
+}
+
+fn error_response(status: StatusCode, msg: &str) -> Result<Response<Body>> {
+	json_response(status, json!({ "error": msg }))
+}
+
+fn is_authorized(req: &Request<Body>, token: &str) -> bool {
+	req.headers()
+		.get("authorization")
+		.and_then(|value| value.to_str().ok())
+		.and_then(|value| value.strip_prefix("Bearer "))
+		.map(|provided| {
+			verify_slices_are_equal(provided.as_bytes(), token.as_bytes())
+				.is_ok()
+		})
+		.unwrap_or(false)
+}
+
+/// The error response for a request which isn't authorized with the admin
+/// token, if any. The endpoints guarded by the token (the admin API and the
+/// dashboard) are disabled while it's not configured.
+pub(crate) fn reject_unauthorized(
+	req: &Request<Body>,
+	config: &MainConfig,
+) -> Option<Result<Response<Body>>> {
+	match &config.admin_token {
+		Some(token) if is_authorized(req, token) => None,
+		Some(_) => {
+			Some(error_response(StatusCode::UNAUTHORIZED, "Unauthorized"))
+		}
+		None => Some(error_response(StatusCode::NOT_FOUND, "Not found")),
+	}
+}
+
+/// The error response for a request to /metrics which doesn't send the metrics
+/// token, if it's configured. Unlike the admin endpoints, /metrics is served
+/// without a token otherwise, which is only meant for deployments where it's not
+/// reachable from outside of the internal network.
+pub(crate) fn reject_unauthorized_metrics(
+	req: &Request<Body>,
+	config: &MainConfig,
+) -> Option<Result<Response<Body>>> {
+	match &config.metrics_token {
+		Some(token) if !is_authorized(req, token) => {
+			Some(error_response(StatusCode::UNAUTHORIZED, "Unauthorized"))
+		}
+		_ => None,
+	}
+}
+
+fn pull_request_id(owner: &str, repo: &str, number: i64) -> String {
+	format!("{}/{}#{}", owner, repo, number)
+}
+
+/// The pull requests involved in the pending merge requests, keyed by
+/// "{owner}/{repo}#{number}", with the pull requests they depend on and the
+/// ones which depend on them. Dependencies which don't have a pending merge
+/// request of their own (e.g. because they're not yet ready to be merged)
+/// are included with `is_pending` set to false.
+fn dependency_graph(mrs: &[MergeRequest]) -> serde_json::Value {
+	let mut nodes = BTreeMap::new();
+
+	for mr in mrs {
+		let id = pull_request_id(&mr.owner, &mr.repo, mr.number);
+		let node = nodes.entry(id).or_insert_with(|| {
+			json!({
+				"html_url": mr.html_url,
+				"dependencies": [],
+				"dependents": [],
+			})
+		});
+		node["sha"] = json!(mr.sha);
+		node["is_pending"] = json!(true);
+	}
+
+	for mr in mrs {
+		let id = pull_request_id(&mr.owner, &mr.repo, mr.number);
+		for dependency in mr.dependencies.iter().flatten() {
+			let dependency_id = pull_request_id(
+				&dependency.owner,
+				&dependency.repo,
+				dependency.number,
+			);
+			nodes.entry(dependency_id.clone()).or_insert_with(|| {
+				json!({
+					"html_url": dependency.html_url,
+					"sha": dependency.sha,
+					"is_pending": false,
+					"dependencies": [],
+					"dependents": [],
+				})
+			})["dependents"]
+				.as_array_mut()
+				.unwrap()
+				.push(json!(id));
+			nodes.get_mut(&id).unwrap()["dependencies"]
+				.as_array_mut()
+				.unwrap()
+				.push(json!(dependency_id));
+		}
+	}
+
+	json!(nodes)
+}
+
+async fn cancel_merge(
+	state: &AppState,
+	owner: &str,
+	repo: &str,
+	number: i64,
+) -> Result<Response<Body>> {
+	let AppState { db, github_bot, .. } = state;
+
+	let mr = match db::get_merge_request(db, owner, repo, number)? {
+		Some(mr) => mr,
+		None => {
+			return error_response(
+				StatusCode::NOT_FOUND,
+				&format!(
+					"There's no pending merge for {}",
+					pull_request_id(owner, repo, number)
+				),
+			)
+		}
+	};
+
+	log::info!(
+		"Deleting merge request for {} as requested by an admin",
+		mr.html_url
+	);
+	cleanup_pr(
+		state,
+		&mr.sha,
+		owner,
+		repo,
+		number,
+		&PullRequestCleanupReason::Cancelled,
+	)
+	.await?;
+
+	if let Err(err) = github_bot
+		.create_issue_comment(
+			owner,
+			repo,
+			number,
+			"Merge cancelled by an administrator.",
+		)
+		.await
+	{
+		log::error!("Failed to post comment on {} due to {}", mr.html_url, err);
+	}
+
+	json_response(StatusCode::OK, json!(mr))
+}
+
+pub async fn handle_request(
+	req: Request<Body>,
+	state: &AppState,
+) -> Result<Response<Body>> {
+	let AppState { db, config, .. } = state;
+
+	if let Some(response) = reject_unauthorized(&req, config) {
+		return response;
+	}
+
+	let path = req.uri().path().trim_start_matches("/admin/");
+	let segments = path.split('/').collect::<Vec<_>>();
+	match (req.method(), &segments[..]) {
+		(&Method::POST, ["events", delivery_id, "replay"]) => {
+			match event_queue::replay(db, delivery_id) {
+				Ok(true) => json_response(
+					StatusCode::ACCEPTED,
+					json!({ "delivery_id": delivery_id }),
+				),
+				Ok(false) => error_response(
+					StatusCode::NOT_FOUND,
+					&format!("Delivery {} was not found", delivery_id),
+				),
+				Err(err) => {
+					log::error!(
+						"Failed to replay delivery {} due to {:?}",
+						delivery_id,
+						err
+					);
+					error_response(
+						StatusCode::INTERNAL_SERVER_ERROR,
+						&err.to_string(),
+					)
+				}
+			}
+		}
+		(&Method::GET, ["merge-requests"]) => {
+			json_response(StatusCode::OK, json!(db::get_merge_requests(db)))
+		}
+		(&Method::GET, ["merge-requests", owner, repo, number]) => {
+			let number = match number.parse() {
+				Ok(number) => number,
+				Err(_) => {
+					return error_response(
+						StatusCode::BAD_REQUEST,
+						"Invalid pull request number",
+					)
+				}
+			};
+			match db::get_merge_request(db, owner, repo, number)? {
+				Some(mr) => json_response(StatusCode::OK, json!(mr)),
+				None => error_response(
+					StatusCode::NOT_FOUND,
+					&format!(
+						"There's no pending merge for {}",
+						pull_request_id(owner, repo, number)
+					),
+				),
+			}
+		}
+		(&Method::POST, ["merge-requests", owner, repo, number, "cancel"]) => {
+			let number = match number.parse() {
+				Ok(number) => number,
+				Err(_) => {
+					return error_response(
+						StatusCode::BAD_REQUEST,
+						"Invalid pull request number",
+					)
+				}
+			};
+			let result = lock::with_locks(
+				vec![lock::pull_request_key(owner, repo, number)],
+				cancel_merge(state, owner, repo, number),
+			)
+			.await;
+			result.or_else(|err| {
+				log::error!(
+					"Failed to cancel the merge of {} due to {:?}",
+					pull_request_id(owner, repo, number),
+					err
+				);
+				error_response(
+					StatusCode::INTERNAL_SERVER_ERROR,
+					&err.to_string(),
+				)
+			})
+		}
+		(&Method::GET, ["dependency-graph"]) => json_response(
+			StatusCode::OK,
+			dependency_graph(&db::get_merge_requests(db)),
+		),
+		_ => error_response(StatusCode::NOT_FOUND, "Not found"),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::MergeRequestBuilder;
+
+	#[test]
+	fn test_dependency_graph() {
+		let graph = dependency_graph(&[
+			MergeRequestBuilder::new("polkadot", 1)
+				.dependencies(vec![
+					MergeRequestBuilder::new("substrate", 1).dependency()
+				])
+				.build(),
+			MergeRequestBuilder::new("cumulus", 1)
+				.dependencies(vec![
+					MergeRequestBuilder::new("polkadot", 1).dependency(),
+					MergeRequestBuilder::new("substrate", 1).dependency(),
+				])
+				.build(),
+		]);
+
+		assert_eq!(
+			graph["paritytech/substrate#1"],
+			json!({
+				"html_url": "https://github.com/paritytech/substrate/pull/1",
+				"sha": "substrate1",
+				"is_pending": false,
+				"dependencies": [],
+				"dependents": ["paritytech/polkadot#1", "paritytech/cumulus#1"],
+			})
+		);
+		assert_eq!(
+			graph["paritytech/polkadot#1"]["dependents"],
+			json!(["paritytech/cumulus#1"])
+		);
+		assert_eq!(graph["paritytech/polkadot#1"]["is_pending"], json!(true));
+		assert_eq!(
+			graph["paritytech/cumulus#1"]["dependencies"],
+			json!(["paritytech/polkadot#1", "paritytech/substrate#1"])
+		);
+	}
+
+	#[test]
+	fn test_is_authorized() {
+		let request = |authorization: Option<&str>| {
+			let mut req = Request::builder();
+			if let Some(authorization) = authorization {
+				req = req.header("authorization", authorization);
+			}
+			req.body(Body::empty()).unwrap()
+		};
+
+		assert!(is_authorized(&request(Some("Bearer secret")), "secret"));
+		assert!(!is_authorized(&request(Some("Bearer other")), "secret"));
+		assert!(!is_authorized(&request(Some("secret")), "secret"));
+		assert!(!is_authorized(&request(None), "secret"));
+	}
+}
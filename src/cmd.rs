@@ -1,9 +1,10 @@
-use crate::{error::*, Result};
+use crate::{error::*, metrics, Result};
 use snafu::ResultExt;
 use std::ffi::OsStr;
 use std::fmt::{Debug, Display};
 use std::path::Path;
 use std::process::{Output, Stdio};
+use std::time::Instant;
 use tokio::process::Command;
 
 #[derive(PartialEq)]
@@ -28,11 +29,12 @@ where
 	Dir: AsRef<Path> + Debug,
 {
 	before_cmd(&cmd, args, Some(&dir), &logging);
+	let name = cmd.to_string();
 
 	#[allow(unused_mut)]
 	let mut init_cmd = Command::new(cmd);
 	let cmd = init_cmd.args(args).current_dir(dir).stderr(Stdio::piped());
-	let result = cmd.output().await.context(Tokio)?;
+	let result = output(cmd, &name, args).await?;
 
 	handle_cmd_result(cmd, result, &logging)
 }
@@ -46,11 +48,12 @@ where
 	Cmd: AsRef<OsStr> + Display,
 {
 	before_cmd::<&Cmd, String>(&cmd, args, None, &logging);
+	let name = cmd.to_string();
 
 	#[allow(unused_mut)]
 	let mut init_cmd = Command::new(cmd);
 	let cmd = init_cmd.args(args).stderr(Stdio::piped());
-	let result = cmd.output().await.context(Tokio)?;
+	let result = output(cmd, &name, args).await?;
 
 	handle_cmd_result(cmd, result, &logging)
 }
@@ -66,6 +69,7 @@ where
 	Dir: AsRef<Path> + Debug,
 {
 	before_cmd(&cmd, args, Some(&dir), &logging);
+	let name = cmd.to_string();
 
 	#[allow(unused_mut)]
 	let mut init_cmd = Command::new(cmd);
@@ -74,11 +78,41 @@ where
 		.current_dir(dir)
 		.stdin(Stdio::piped())
 		.stderr(Stdio::piped());
-	let result = cmd.output().await.context(Tokio)?;
+	let result = output(cmd, &name, args).await?;
 
 	handle_cmd_result(cmd, result, &logging)
 }
 
+/// The command's first argument, or git's subcommand, which comes after the
+/// configuration passed with `-c` (see companion::git_auth_config).
+fn subcommand<'a>(name: &str, args: &[&'a str]) -> Option<&'a str> {
+	let mut args = args.iter();
+	while let Some(arg) = args.next() {
+		if name == "git" && *arg == "-c" {
+			args.next();
+		} else {
+			return Some(arg);
+		}
+	}
+	None
+}
+
+/// Wait for the command's output, recording how long it took.
+async fn output(
+	cmd: &mut Command,
+	name: &str,
+	args: &[&str],
+) -> Result<Output> {
+	let started_at = Instant::now();
+	let result = cmd.output().await.context(Tokio);
+	metrics::record_command_duration(
+		name,
+		subcommand(name, args),
+		started_at.elapsed(),
+	);
+	result
+}
+
 fn before_cmd<'a, Cmd, Dir>(
 	cmd: Cmd,
 	args: &[&str],
@@ -168,3 +202,25 @@ fn handle_cmd_result<'a>(
 		})
 	}
 }
+
+/// Run a git command in `repo_dir`, returning its trimmed standard output.
+pub async fn git(
+	repo_dir: &Path,
+	args: &[&str],
+	secrets_to_hide: Option<&[&str]>,
+) -> Result<String> {
+	let output = run_cmd_with_output(
+		"git",
+		args,
+		repo_dir,
+		CommandMessage::Configured(CommandMessageConfiguration {
+			secrets_to_hide,
+			are_errors_silenced: false,
+		}),
+	)
+	.await?;
+	Ok(String::from_utf8(output.stdout)
+		.context(Utf8)?
+		.trim()
+		.to_string())
+}
@@ -1,9 +1,14 @@
-use std::path::PathBuf;
+use std::{collections::HashMap, path::PathBuf};
 
 #[derive(Debug, Clone)]
 pub struct MainConfig {
+	// The account whose installation is used for requests which don't concern a
+	// repository or organization (see http::Client::auth_key_for)
 	pub installation_login: String,
-	pub webhook_secret: String,
+	// Deliveries signed with any of these are accepted, which allows for rotating
+	// the secret
+	pub webhook_secrets: Vec<String>,
+	pub webhook_allow_sha1: bool,
 	pub webhook_port: String,
 	pub db_path: PathBuf,
 	pub repos_path: PathBuf,
@@ -12,10 +17,31 @@ pub struct MainConfig {
 	pub github_app_id: usize,
 	pub disable_org_check: bool,
 	pub github_api_url: String,
+	// The web and git host, from which the repositories are cloned and the pull
+	// request links are derived
+	pub github_url: String,
 	pub companion_status_settle_delay: u64,
 	pub merge_command_delay: u64,
 	pub github_source_prefix: String,
 	pub github_source_suffix: String,
+	pub merge_priority_maintainers: Vec<String>,
+	pub merge_priority_label: String,
+	// Maximum batch size by "owner/repo" for the repositories where batch merging
+	// is enabled
+	pub batch_merge_repositories: HashMap<String, usize>,
+	// The admin API is disabled unless this is set
+	pub admin_token: Option<String>,
+	// /metrics is served without authentication unless this is set
+	pub metrics_token: Option<String>,
+	// How long webhook deliveries are remembered for, both for skipping duplicate
+	// deliveries and for replaying them
+	pub delivery_ttl_hours: u64,
+	// How long the work in progress is waited for when shutting down
+	pub shutdown_timeout_secs: u64,
+	// How many times idempotent GitHub API requests are retried after transient
+	// failures, and the delay before the first retry, which doubles afterwards
+	pub github_api_max_retries: u32,
+	pub github_api_retry_base_delay_ms: u64,
 }
 
 impl MainConfig {
@@ -30,8 +56,26 @@ impl MainConfig {
 
 		let installation_login =
 			dotenv::var("INSTALLATION_LOGIN").expect("INSTALLATION_LOGIN");
-		let webhook_secret =
-			dotenv::var("WEBHOOK_SECRET").expect("WEBHOOK_SECRET");
+		let webhook_secrets = dotenv::var("WEBHOOK_SECRET")
+			.expect("WEBHOOK_SECRET")
+			.split(',')
+			.map(|secret| secret.trim().to_owned())
+			.filter(|secret| !secret.is_empty())
+			.collect::<Vec<_>>();
+		assert!(
+			!webhook_secrets.is_empty(),
+			"WEBHOOK_SECRET should not be empty"
+		);
+		let webhook_allow_sha1 = dotenv::var("WEBHOOK_ALLOW_SHA1")
+			.ok()
+			.map(|value| match value.as_str() {
+				"true" => true,
+				"false" => false,
+				_ => {
+					panic!("WEBHOOK_ALLOW_SHA1 should be \"true\" or \"false\"")
+				}
+			})
+			.unwrap_or(false);
 		let webhook_port = dotenv::var("WEBHOOK_PORT").expect("WEBHOOK_PORT");
 
 		let db_path = dotenv::var("DB_PATH").expect("DB_PATH");
@@ -76,19 +120,98 @@ impl MainConfig {
 			})
 			.unwrap_or(false);
 
-		let github_api_url = "https://api.github.com".to_owned();
-		let github_source_prefix = dotenv::var("GITHUB_SOURCE_PREFIX")
+		let github_api_url = dotenv::var("GITHUB_API_URL")
+			.map(|url| url.trim_end_matches('/').to_owned())
+			.unwrap_or_else(|_| "https://api.github.com".to_string());
+		let github_url = dotenv::var("GITHUB_URL")
+			.map(|url| url.trim_end_matches('/').to_owned())
 			.unwrap_or_else(|_| "https://github.com".to_string());
+		let github_source_prefix = dotenv::var("GITHUB_SOURCE_PREFIX")
+			.unwrap_or_else(|_| github_url.clone());
 		let github_source_suffix = dotenv::var("GITHUB_SOURCE_SUFFIX")
 			.unwrap_or_else(|_| "".to_string());
 
+		let merge_priority_maintainers =
+			dotenv::var("MERGE_PRIORITY_MAINTAINERS")
+				.map(|value| {
+					value
+						.split(',')
+						.map(|login| login.trim().to_owned())
+						.filter(|login| !login.is_empty())
+						.collect()
+				})
+				.unwrap_or_else(|_| vec![]);
+		let merge_priority_label = dotenv::var("MERGE_PRIORITY_LABEL")
+			.unwrap_or_else(|_| "A-merge-priority".to_string());
+
+		let batch_merge_repositories = dotenv::var("BATCH_MERGE_REPOSITORIES")
+			.map(|value| {
+				value
+					.split(',')
+					.map(|item| item.trim())
+					.filter(|item| !item.is_empty())
+					.map(|item| {
+						let (repository, size) = item.rsplit_once(':').expect(
+							"BATCH_MERGE_REPOSITORIES items should be in the form owner/repo:size",
+						);
+						let size = size.parse::<usize>().expect(
+							"BATCH_MERGE_REPOSITORIES batch sizes should be numbers",
+						);
+						(repository.to_owned(), size)
+					})
+					.collect()
+			})
+			.unwrap_or_default();
+
+		let admin_token = dotenv::var("ADMIN_TOKEN")
+			.ok()
+			.filter(|token| !token.is_empty());
+
+		let metrics_token = dotenv::var("METRICS_TOKEN")
+			.ok()
+			.filter(|token| !token.is_empty());
+
+		let delivery_ttl_hours = dotenv::var("DELIVERY_TTL_HOURS")
+			.map(|value| {
+				value
+					.parse::<u64>()
+					.expect("DELIVERY_TTL_HOURS should be a number")
+			})
+			.unwrap_or(7 * 24);
+
+		let shutdown_timeout_secs = dotenv::var("SHUTDOWN_TIMEOUT_SECONDS")
+			.map(|value| {
+				value
+					.parse::<u64>()
+					.expect("SHUTDOWN_TIMEOUT_SECONDS should be a number")
+			})
+			.unwrap_or(120);
+
+		let github_api_max_retries = dotenv::var("GITHUB_API_MAX_RETRIES")
+			.map(|value| {
+				value
+					.parse::<u32>()
+					.expect("GITHUB_API_MAX_RETRIES should be a number")
+			})
+			.unwrap_or(3);
+
+		let github_api_retry_base_delay_ms =
+			dotenv::var("GITHUB_API_RETRY_BASE_DELAY_MS")
+				.map(|value| {
+					value.parse::<u64>().expect(
+						"GITHUB_API_RETRY_BASE_DELAY_MS should be a number",
+					)
+				})
+				.unwrap_or(1000);
+
 		let merge_command_delay = 4096;
 
 		let companion_status_settle_delay = 4096;
 
 		Self {
 			installation_login,
-			webhook_secret,
+			webhook_secrets,
+			webhook_allow_sha1,
 			webhook_port,
 			db_path,
 			private_key,
@@ -96,11 +219,21 @@ impl MainConfig {
 			github_app_id,
 			disable_org_check,
 			github_api_url,
+			github_url,
 			merge_command_delay,
 			companion_status_settle_delay,
 			repos_path,
 			github_source_prefix,
 			github_source_suffix,
+			merge_priority_maintainers,
+			merge_priority_label,
+			batch_merge_repositories,
+			admin_token,
+			metrics_token,
+			delivery_ttl_hours,
+			shutdown_timeout_secs,
+			github_api_max_retries,
+			github_api_retry_base_delay_ms,
 		}
 	}
 }
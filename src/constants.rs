@@ -1,5 +1,17 @@
-// Note: the old database will be *DELETED* when changing this constant
-// Do not change this without checking the implementation first
-pub const DATABASE_VERSION: &str = "v3.0";
+// Note: processbot will refuse to start on a database from a previous version
+// unless there's a migration for it. Do not change this without adding a
+// migration to db/migrations.rs first.
+pub const DATABASE_VERSION: &str = "v8.0";
 
 pub const PR_CUSTOM_REVIEW_STATUS: &str = "Check reviews";
+
+// How often the pending merge requests are polled for (see main.rs)
+pub const MERGE_REQUESTS_POLL_INTERVAL: std::time::Duration =
+	std::time::Duration::from_secs(30 * 60);
+
+// Once fewer requests than this remain in the GitHub API rate limit, the
+// requests are spread out until the rate limit resets (see http.rs)
+pub const GITHUB_RATE_LIMIT_RESERVE: i64 = 250;
+
+// How many times a request is retried after hitting a GitHub API rate limit
+pub const GITHUB_RATE_LIMIT_MAX_RETRIES: u32 = 3;
@@ -0,0 +1,362 @@
+use chrono::{DateTime, Duration, Utc};
+use futures::stream::{self, StreamExt};
+use html_escape::{encode_double_quoted_attribute, encode_text};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use crate::{
+	db,
+	github_bot::GithubBot,
+	webhook::{
+		get_latest_checks_state, get_latest_statuses_state, AppState,
+		MergeRequest, MergeState,
+	},
+	Result, Status,
+};
+
+/*
+	The dashboard is a page served at /dashboard which shows the pending merges
+	grouped by repository, who requested them and for how long they have been
+	waiting, along with their companions (the pull requests they depend on and
+	the ones which depend on them). The state of the statuses and checks of each
+	pull request is fetched from GitHub whenever the page is rendered, since the
+	page is meant to be looked at before acting on it, e.g. before cutting a
+	release. At most FETCH_CONCURRENCY pull requests are fetched at a time so
+	that a page load doesn't burst through the installation's rate limit. Like
+	the admin API, the page is only served to requests authorized with
+	ADMIN_TOKEN (see admin.rs).
+*/
+
+const FETCH_CONCURRENCY: usize = 4;
+
+struct PullRequestRef<'a> {
+	owner: &'a str,
+	repo: &'a str,
+	number: i64,
+	sha: &'a str,
+	html_url: &'a str,
+}
+
+fn pull_request_id(owner: &str, repo: &str, number: i64) -> String {
+	format!("{}/{}#{}", owner, repo, number)
+}
+
+struct CiState {
+	statuses: &'static str,
+	checks: &'static str,
+}
+
+fn describe(
+	result: Result<Status>,
+	what: &str,
+	html_url: &str,
+) -> &'static str {
+	match result {
+		Ok(Status::Success) => "success",
+		Ok(Status::Pending) => "pending",
+		Ok(Status::Failure) => "failure",
+		Err(err) => {
+			log::error!(
+				"Failed to fetch the {} of {} for the dashboard due to {:?}",
+				what,
+				html_url,
+				err
+			);
+			"unknown"
+		}
+	}
+}
+
+async fn fetch_ci_state(
+	github_bot: &GithubBot,
+	pr: &PullRequestRef<'_>,
+) -> CiState {
+	let statuses = get_latest_statuses_state(
+		github_bot,
+		pr.owner,
+		pr.repo,
+		pr.sha,
+		pr.html_url,
+	)
+	.await
+	.map(|(status, _)| status);
+	let checks = get_latest_checks_state(
+		github_bot,
+		pr.owner,
+		pr.repo,
+		pr.sha,
+		pr.html_url,
+	)
+	.await;
+	CiState {
+		statuses: describe(statuses, "statuses", pr.html_url),
+		checks: describe(checks, "checks", pr.html_url),
+	}
+}
+
+/// The pull requests shown on the dashboard, keyed by their ID: the pending
+/// merges and their dependencies, which might not be pending themselves.
+fn pull_requests(mrs: &[MergeRequest]) -> BTreeMap<String, PullRequestRef<'_>> {
+	let mut prs = BTreeMap::new();
+	for mr in mrs {
+		for dependency in mr.dependencies.iter().flatten() {
+			let pr = PullRequestRef {
+				owner: &dependency.owner,
+				repo: &dependency.repo,
+				number: dependency.number,
+				sha: &dependency.sha,
+				html_url: &dependency.html_url,
+			};
+			prs.entry(pull_request_id(pr.owner, pr.repo, pr.number))
+				.or_insert(pr);
+		}
+	}
+	// The pending merges' own records take precedence since they're up to date
+	for mr in mrs {
+		let pr = PullRequestRef {
+			owner: &mr.owner,
+			repo: &mr.repo,
+			number: mr.number,
+			sha: &mr.sha,
+			html_url: &mr.html_url,
+		};
+		prs.insert(pull_request_id(pr.owner, pr.repo, pr.number), pr);
+	}
+	prs
+}
+
+fn format_duration(duration: Duration) -> String {
+	if duration.num_days() > 0 {
+		format!("{}d {}h", duration.num_days(), duration.num_hours() % 24)
+	} else if duration.num_hours() > 0 {
+		format!("{}h {}m", duration.num_hours(), duration.num_minutes() % 60)
+	} else {
+		format!("{}m", duration.num_minutes().max(0))
+	}
+}
+
+fn describe_state(state: &MergeState) -> String {
+	match state {
+		MergeState::Queued => "waiting for dependencies".to_owned(),
+		MergeState::Updating { .. } => "updating".to_owned(),
+		MergeState::AwaitingChecks => "waiting for checks".to_owned(),
+		MergeState::Merging => "merging".to_owned(),
+		MergeState::Merged => "merged".to_owned(),
+		MergeState::Failed { reason } => format!("failed: {}", reason),
+	}
+}
+
+fn render_page(
+	mrs: &[MergeRequest],
+	ci_states: &HashMap<String, CiState>,
+	now: DateTime<Utc>,
+) -> String {
+	let prs = pull_requests(mrs);
+
+	let mut dependents: HashMap<String, Vec<String>> = HashMap::new();
+	for mr in mrs {
+		let id = pull_request_id(&mr.owner, &mr.repo, mr.number);
+		for dependency in mr.dependencies.iter().flatten() {
+			let dependency_id = pull_request_id(
+				&dependency.owner,
+				&dependency.repo,
+				dependency.number,
+			);
+			dependents
+				.entry(dependency_id)
+				.or_default()
+				.push(id.clone());
+		}
+	}
+
+	let mut by_repository: BTreeMap<String, Vec<&MergeRequest>> =
+		BTreeMap::new();
+	for mr in mrs {
+		by_repository
+			.entry(format!("{}/{}", mr.owner, mr.repo))
+			.or_default()
+			.push(mr);
+	}
+
+	let link = |id: &str| match prs.get(id) {
+		Some(pr) => format!(
+			"<a href=\"{}\">{}</a>",
+			encode_double_quoted_attribute(pr.html_url),
+			encode_text(id)
+		),
+		None => encode_text(id).into_owned(),
+	};
+	let ci_state = |id: &str| match ci_states.get(id) {
+		Some(state) => (state.statuses, state.checks),
+		None => ("unknown", "unknown"),
+	};
+
+	let mut html = String::new();
+	html.push_str(
+		"<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>processbot: pending merges</title>
+<style>
+body { font-family: sans-serif; }
+table { border-collapse: collapse; }
+th, td { border: 1px solid #ccc; padding: 4px 8px; text-align: left; vertical-align: top; }
+ul { margin: 0; padding-left: 16px; }
+.success { color: green; }
+.pending { color: darkorange; }
+.failure { color: red; }
+</style>
+</head>
+<body>
+<h1>Pending merges</h1>
+",
+	);
+	let _ = writeln!(
+		html,
+		"<p>{} pending merges as of {}</p>",
+		mrs.len(),
+		now.format("%Y-%m-%d %H:%M:%S UTC")
+	);
+
+	for (repository, mrs) in by_repository {
+		let _ = writeln!(html, "<h2>{}</h2>", encode_text(&repository));
+		html.push_str("<table>\n<tr><th>Pull request</th><th>Requested by</th><th>Waiting for</th><th>State</th><th>Statuses</th><th>Checks</th><th>Companions</th></tr>\n");
+		let mut mrs = mrs;
+		mrs.sort_by_key(|mr| mr.number);
+		for mr in mrs {
+			let id = pull_request_id(&mr.owner, &mr.repo, mr.number);
+			let (statuses, checks) = ci_state(&id);
+			let waiting = match mr.requested_at {
+				Some(requested_at) => format_duration(now - requested_at),
+				None => "unknown".to_owned(),
+			};
+
+			let mut companions = String::new();
+			let dependency_ids =
+				mr.dependencies.iter().flatten().map(|dependency| {
+					(
+						pull_request_id(
+							&dependency.owner,
+							&dependency.repo,
+							dependency.number,
+						),
+						"depends on",
+					)
+				});
+			let dependent_ids = dependents
+				.get(&id)
+				.into_iter()
+				.flatten()
+				.map(|dependent| (dependent.clone(), "dependent"));
+			for (companion, relation) in dependency_ids.chain(dependent_ids) {
+				let (statuses, checks) = ci_state(&companion);
+				let _ = write!(
+					companions,
+					"<li>{} ({}): statuses <span class=\"{}\">{}</span>, checks <span class=\"{}\">{}</span></li>",
+					link(&companion),
+					relation,
+					statuses,
+					statuses,
+					checks,
+					checks
+				);
+			}
+			let companions = if companions.is_empty() {
+				"None".to_owned()
+			} else {
+				format!("<ul>{}</ul>", companions)
+			};
+
+			let _ = writeln!(
+				html,
+				"<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td class=\"{}\">{}</td><td class=\"{}\">{}</td><td>{}</td></tr>",
+				link(&id),
+				encode_text(&mr.requested_by),
+				waiting,
+				encode_text(&describe_state(&mr.state)),
+				statuses,
+				statuses,
+				checks,
+				checks,
+				companions
+			);
+		}
+		html.push_str("</table>\n");
+	}
+
+	html.push_str("</body>\n</html>\n");
+	html
+}
+
+/// Render the dashboard with the current state of the pending merges.
+pub async fn render(state: &AppState) -> String {
+	let AppState { db, github_bot, .. } = state;
+
+	let mrs = db::get_merge_requests(db);
+	let prs = pull_requests(&mrs);
+	let fetches = prs
+		.iter()
+		.map(|(id, pr)| async move {
+			(id.clone(), fetch_ci_state(github_bot, pr).await)
+		})
+		.collect::<Vec<_>>();
+	let ci_states = stream::iter(fetches)
+		.buffer_unordered(FETCH_CONCURRENCY)
+		.collect()
+		.await;
+
+	render_page(&mrs, &ci_states, Utc::now())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::MergeRequestBuilder;
+
+	#[test]
+	fn test_format_duration() {
+		assert_eq!(format_duration(Duration::seconds(59)), "0m");
+		assert_eq!(format_duration(Duration::minutes(125)), "2h 5m");
+		assert_eq!(format_duration(Duration::hours(50)), "2d 2h");
+	}
+
+	#[test]
+	fn test_render_page() {
+		let now = Utc::now();
+		let substrate = MergeRequestBuilder::new("substrate", 1)
+			.requested_by("<foo>")
+			.requested_at(now - Duration::minutes(90))
+			.build();
+		let polkadot = MergeRequestBuilder::new("polkadot", 2)
+			.requested_by("<foo>")
+			.dependencies(vec![
+				MergeRequestBuilder::new("substrate", 1).dependency()
+			])
+			.build();
+		let mut ci_states = HashMap::new();
+		ci_states.insert(
+			"paritytech/substrate#1".to_owned(),
+			CiState {
+				statuses: "success",
+				checks: "pending",
+			},
+		);
+
+		let html = render_page(&[substrate, polkadot], &ci_states, now);
+
+		assert!(html.contains("<h2>paritytech/polkadot</h2>"));
+		assert!(html.contains("<h2>paritytech/substrate</h2>"));
+		// Requesters are escaped
+		assert!(html.contains("<td>&lt;foo&gt;</td>"));
+		assert!(
+			html.contains("<td>1h 30m</td><td>waiting for dependencies</td>")
+		);
+		assert!(html.contains(
+			"<a href=\"https://github.com/paritytech/polkadot/pull/2\">paritytech/polkadot#2</a> (dependent)"
+		));
+		assert!(html.contains(
+			"<a href=\"https://github.com/paritytech/substrate/pull/1\">paritytech/substrate#1</a> (depends on): statuses <span class=\"success\">success</span>, checks <span class=\"pending\">pending</span>"
+		));
+	}
+}
@@ -0,0 +1,787 @@
+use rocksdb::{IteratorMode, WriteBatch, DB};
+use snafu::ResultExt;
+
+use super::{
+	merge_request_key, prefix_iterator, sha_index_key, MERGE_QUEUE_PREFIX,
+	MERGE_REQUEST_PREFIX,
+};
+use crate::{error::*, Result};
+
+/*
+	Records are encoded with bincode, which is not self-describing, therefore a
+	record can only be read back with the exact same struct it was written with.
+	Each version's record types are frozen in their own module below so that
+	migrations keep working after the types in webhook.rs change.
+
+	When changing the shape of MergeRequest or Dependency (or the storage layout):
+	1. Bump constants::DATABASE_VERSION
+	2. Freeze the new shape in a module for the new version
+	3. Append a Migration from the previous version to MIGRATIONS
+*/
+
+mod v3 {
+	use serde::{Deserialize, Serialize};
+
+	#[derive(Debug, Serialize, Deserialize, Clone)]
+	pub struct Dependency {
+		pub sha: String,
+		pub owner: String,
+		pub repo: String,
+		pub number: i64,
+		pub html_url: String,
+		pub is_directly_referenced: bool,
+	}
+
+	#[derive(Debug, Serialize, Deserialize, Clone)]
+	pub struct MergeRequest {
+		pub sha: String,
+		pub was_updated: bool,
+		pub owner: String,
+		pub repo: String,
+		pub number: i64,
+		pub html_url: String,
+		pub requested_by: String,
+		pub dependencies: Option<Vec<Dependency>>,
+	}
+}
+
+// v4.0 changed the storage layout of merge requests (see db/mod.rs), not the
+// records, and added the merge queues
+mod v4 {
+	use serde::{Deserialize, Serialize};
+
+	pub use super::v3::MergeRequest;
+
+	#[derive(Debug, Serialize, Deserialize, Clone)]
+	pub struct MergeQueueEntry {
+		pub number: i64,
+		pub html_url: String,
+		pub requested_by: String,
+	}
+
+	#[derive(Debug, Serialize, Deserialize, Clone)]
+	pub struct MergeQueue {
+		pub owner: String,
+		pub repo: String,
+		pub base_branch: String,
+		pub entries: Vec<MergeQueueEntry>,
+	}
+}
+
+// v5.0 added priority to the merge queue entries. The merge batches were
+// introduced later on without a version bump since no existing records were
+// affected.
+mod v5 {
+	use serde::{Deserialize, Serialize};
+
+	pub use super::v4::MergeRequest;
+
+	#[derive(Debug, Serialize, Deserialize, Clone)]
+	pub struct MergeQueueEntry {
+		pub number: i64,
+		pub html_url: String,
+		pub requested_by: String,
+		pub is_priority: bool,
+	}
+
+	#[derive(Debug, Serialize, Deserialize, Clone)]
+	pub struct MergeQueue {
+		pub owner: String,
+		pub repo: String,
+		pub base_branch: String,
+		pub entries: Vec<MergeQueueEntry>,
+	}
+
+	// Only read back by the tests since no migration targets it yet
+	#[allow(dead_code)]
+	#[derive(Debug, Serialize, Deserialize, Clone)]
+	pub struct MergeBatch {
+		pub owner: String,
+		pub repo: String,
+		pub base_branch: String,
+		pub base_sha: String,
+		pub candidate_sha: String,
+		pub numbers: Vec<i64>,
+		pub pending: Vec<Vec<i64>>,
+	}
+}
+
+// v6.0 added the merge method override to the merge requests
+mod v6 {
+	use serde::{Deserialize, Serialize};
+
+	pub use super::v3::Dependency;
+	pub use super::v5::{MergeBatch, MergeQueue, MergeQueueEntry};
+
+	#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+	pub enum MergeMethod {
+		Merge,
+		Squash,
+		Rebase,
+	}
+
+	#[derive(Debug, Serialize, Deserialize, Clone)]
+	pub struct MergeRequest {
+		pub sha: String,
+		pub was_updated: bool,
+		pub owner: String,
+		pub repo: String,
+		pub number: i64,
+		pub html_url: String,
+		pub requested_by: String,
+		pub dependencies: Option<Vec<Dependency>>,
+		pub merge_method: Option<MergeMethod>,
+	}
+}
+
+// v7.0 added the time at which the merge was requested to the merge requests
+mod v7 {
+	use chrono::{DateTime, Utc};
+	use serde::{Deserialize, Serialize};
+
+	pub use super::v6::{Dependency, MergeMethod};
+
+	#[derive(Debug, Serialize, Deserialize, Clone)]
+	pub struct MergeRequest {
+		pub sha: String,
+		pub was_updated: bool,
+		pub owner: String,
+		pub repo: String,
+		pub number: i64,
+		pub html_url: String,
+		pub requested_by: String,
+		pub dependencies: Option<Vec<Dependency>>,
+		pub merge_method: Option<MergeMethod>,
+		pub requested_at: Option<DateTime<Utc>>,
+	}
+}
+
+// v8.0 added the state of the merge to the merge requests
+mod v8 {
+	use chrono::{DateTime, Utc};
+	use serde::{Deserialize, Serialize};
+
+	// Only read back by the tests since no migration targets them yet
+	#[allow(unused_imports)]
+	pub use super::v6::{MergeBatch, MergeQueue, MergeQueueEntry};
+	pub use super::v7::{Dependency, MergeMethod};
+
+	#[derive(Debug, Serialize, Deserialize, Clone)]
+	pub enum MergeState {
+		Queued,
+		Updating { pushed_sha: Option<String> },
+		AwaitingChecks,
+		Merging,
+		Merged,
+		Failed { reason: String },
+	}
+
+	#[derive(Debug, Serialize, Deserialize, Clone)]
+	pub struct MergeRequest {
+		pub sha: String,
+		pub was_updated: bool,
+		pub owner: String,
+		pub repo: String,
+		pub number: i64,
+		pub html_url: String,
+		pub requested_by: String,
+		pub dependencies: Option<Vec<Dependency>>,
+		pub merge_method: Option<MergeMethod>,
+		pub requested_at: Option<DateTime<Utc>>,
+		pub state: MergeState,
+	}
+}
+
+pub struct Migration {
+	pub from: &'static str,
+	pub to: &'static str,
+	pub run: fn(&DB) -> Result<()>,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+	Migration {
+		from: "v3.0",
+		to: "v4.0",
+		run: v3_to_v4,
+	},
+	Migration {
+		from: "v4.0",
+		to: "v5.0",
+		run: v4_to_v5,
+	},
+	Migration {
+		from: "v5.0",
+		to: "v6.0",
+		run: v5_to_v6,
+	},
+	Migration {
+		from: "v6.0",
+		to: "v7.0",
+		run: v6_to_v7,
+	},
+	Migration {
+		from: "v7.0",
+		to: "v8.0",
+		run: v7_to_v8,
+	},
+];
+
+/// Find the sequence of migrations which leads from version `from` to version
+/// `to`, if any.
+pub fn migration_path(from: &str, to: &str) -> Option<Vec<&'static Migration>> {
+	let mut path = vec![];
+	let mut current = from;
+	while current != to {
+		let migration = MIGRATIONS.iter().find(|m| m.from == current)?;
+		path.push(migration);
+		current = migration.to;
+	}
+	Some(path)
+}
+
+/// Move the records from their bare head SHA to the "pr/" keys and build the SHA
+/// index.
+fn v3_to_v4(db: &DB) -> Result<()> {
+	let mut batch = WriteBatch::default();
+	let mut migrated_count = 0;
+
+	for (key, value) in db.iterator(IteratorMode::Start) {
+		batch.delete(&key);
+
+		let mr = match bincode::deserialize::<v3::MergeRequest>(&value) {
+			Ok(mr) => mr,
+			Err(err) => {
+				log::error!(
+					"Dropping key {} during migration because it could not be deserialized: {:?}",
+					String::from_utf8_lossy(&key),
+					err
+				);
+				continue;
+			}
+		};
+		log::info!(
+			"Migrating merge request of {} (sha {})",
+			mr.html_url,
+			mr.sha
+		);
+
+		// The records themselves are unchanged in v4.0
+		let mr: v4::MergeRequest = mr;
+		batch.put(
+			merge_request_key(&mr.owner, &mr.repo, mr.number).as_bytes(),
+			bincode::serialize(&mr).context(Bincode)?,
+		);
+		batch.put(
+			sha_index_key(&mr.sha, &mr.owner, &mr.repo, mr.number).as_bytes(),
+			b"",
+		);
+		migrated_count += 1;
+	}
+
+	db.write(batch).context(Db)?;
+	log::info!("Migrated {} merge requests", migrated_count);
+
+	Ok(())
+}
+
+/// Mark all the existing merge queue entries as normal priority.
+fn v4_to_v5(db: &DB) -> Result<()> {
+	let mut batch = WriteBatch::default();
+
+	for (key, value) in prefix_iterator(db, MERGE_QUEUE_PREFIX) {
+		let queue = match bincode::deserialize::<v4::MergeQueue>(&value) {
+			Ok(queue) => queue,
+			Err(err) => {
+				log::error!(
+					"Dropping key {} during migration because it could not be deserialized: {:?}",
+					String::from_utf8_lossy(&key),
+					err
+				);
+				batch.delete(&key);
+				continue;
+			}
+		};
+
+		let queue = v5::MergeQueue {
+			owner: queue.owner,
+			repo: queue.repo,
+			base_branch: queue.base_branch,
+			entries: queue
+				.entries
+				.into_iter()
+				.map(|entry| v5::MergeQueueEntry {
+					number: entry.number,
+					html_url: entry.html_url,
+					requested_by: entry.requested_by,
+					is_priority: false,
+				})
+				.collect(),
+		};
+		batch.put(&key, bincode::serialize(&queue).context(Bincode)?);
+	}
+
+	db.write(batch).context(Db)
+}
+
+/// Add the merge method override to the merge requests, which keep using the
+/// repository's merge method.
+fn v5_to_v6(db: &DB) -> Result<()> {
+	let mut batch = WriteBatch::default();
+
+	for (key, value) in prefix_iterator(db, MERGE_REQUEST_PREFIX) {
+		let mr = match bincode::deserialize::<v5::MergeRequest>(&value) {
+			Ok(mr) => mr,
+			Err(err) => {
+				log::error!(
+					"Dropping key {} during migration because it could not be deserialized: {:?}",
+					String::from_utf8_lossy(&key),
+					err
+				);
+				// The SHA index entry is left dangling, which is tolerated
+				batch.delete(&key);
+				continue;
+			}
+		};
+
+		let mr = v6::MergeRequest {
+			sha: mr.sha,
+			was_updated: mr.was_updated,
+			owner: mr.owner,
+			repo: mr.repo,
+			number: mr.number,
+			html_url: mr.html_url,
+			requested_by: mr.requested_by,
+			dependencies: mr.dependencies,
+			merge_method: None,
+		};
+		batch.put(&key, bincode::serialize(&mr).context(Bincode)?);
+	}
+
+	db.write(batch).context(Db)
+}
+
+/// Add the time at which the merge was requested to the merge requests, which
+/// is not known for the existing ones.
+fn v6_to_v7(db: &DB) -> Result<()> {
+	let mut batch = WriteBatch::default();
+
+	for (key, value) in prefix_iterator(db, MERGE_REQUEST_PREFIX) {
+		let mr = match bincode::deserialize::<v6::MergeRequest>(&value) {
+			Ok(mr) => mr,
+			Err(err) => {
+				log::error!(
+					"Dropping key {} during migration because it could not be deserialized: {:?}",
+					String::from_utf8_lossy(&key),
+					err
+				);
+				// The SHA index entry is left dangling, which is tolerated
+				batch.delete(&key);
+				continue;
+			}
+		};
+
+		let mr = v7::MergeRequest {
+			sha: mr.sha,
+			was_updated: mr.was_updated,
+			owner: mr.owner,
+			repo: mr.repo,
+			number: mr.number,
+			html_url: mr.html_url,
+			requested_by: mr.requested_by,
+			dependencies: mr.dependencies,
+			merge_method: mr.merge_method,
+			requested_at: None,
+		};
+		batch.put(&key, bincode::serialize(&mr).context(Bincode)?);
+	}
+
+	db.write(batch).context(Db)
+}
+
+/// Add the state of the merge to the merge requests. The existing ones are
+/// either waiting for their dependencies to be merged or, since their
+/// dependencies were merged or they had none, for their checks.
+fn v7_to_v8(db: &DB) -> Result<()> {
+	let mut batch = WriteBatch::default();
+
+	for (key, value) in prefix_iterator(db, MERGE_REQUEST_PREFIX) {
+		let mr = match bincode::deserialize::<v7::MergeRequest>(&value) {
+			Ok(mr) => mr,
+			Err(err) => {
+				log::error!(
+					"Dropping key {} during migration because it could not be deserialized: {:?}",
+					String::from_utf8_lossy(&key),
+					err
+				);
+				// The SHA index entry is left dangling, which is tolerated
+				batch.delete(&key);
+				continue;
+			}
+		};
+
+		let state = if mr.dependencies.is_some() {
+			v8::MergeState::Queued
+		} else {
+			v8::MergeState::AwaitingChecks
+		};
+		let mr = v8::MergeRequest {
+			sha: mr.sha,
+			was_updated: mr.was_updated,
+			owner: mr.owner,
+			repo: mr.repo,
+			number: mr.number,
+			html_url: mr.html_url,
+			requested_by: mr.requested_by,
+			dependencies: mr.dependencies,
+			merge_method: mr.merge_method,
+			requested_at: mr.requested_at,
+			state,
+		};
+		batch.put(&key, bincode::serialize(&mr).context(Bincode)?);
+	}
+
+	db.write(batch).context(Db)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		constants::DATABASE_VERSION,
+		db::{self, get_merge_request, get_merge_requests_by_sha},
+		github, merge_batch, merge_queue, webhook,
+	};
+	use std::{fs, path::Path};
+
+	// Creates a database as it would've been left by processbot at `version`
+	fn fixture_database(
+		version: &str,
+		records: &[(&[u8], Vec<u8>)],
+	) -> tempfile::TempDir {
+		let dir = tempfile::tempdir().unwrap();
+		{
+			let db = DB::open_default(dir.path()).unwrap();
+			for (key, value) in records {
+				db.put(key, value).unwrap();
+			}
+		}
+		fs::write(dir.path().join(db::VERSION_FILE_NAME), version).unwrap();
+		dir
+	}
+
+	fn read_version(dir: &Path) -> String {
+		fs::read_to_string(dir.join(db::VERSION_FILE_NAME)).unwrap()
+	}
+
+	fn v3_merge_request(
+		repo: &str,
+		number: i64,
+		sha: &str,
+	) -> v3::MergeRequest {
+		v3::MergeRequest {
+			sha: sha.into(),
+			was_updated: false,
+			owner: "paritytech".into(),
+			repo: repo.into(),
+			number,
+			html_url: format!(
+				"https://github.com/paritytech/{}/pull/{}",
+				repo, number
+			),
+			requested_by: "foo".into(),
+			dependencies: Some(vec![v3::Dependency {
+				sha: "dependency_sha".into(),
+				owner: "paritytech".into(),
+				repo: "substrate".into(),
+				number: 1,
+				html_url: "https://github.com/paritytech/substrate/pull/1"
+					.into(),
+				is_directly_referenced: true,
+			}]),
+		}
+	}
+
+	#[test]
+	fn test_migration_path_reaches_current_version() {
+		assert!(migration_path("v3.0", DATABASE_VERSION).is_some());
+		assert!(migration_path(DATABASE_VERSION, DATABASE_VERSION)
+			.unwrap()
+			.is_empty());
+		assert!(migration_path("v2.0", DATABASE_VERSION).is_none());
+	}
+
+	#[test]
+	fn test_current_records_match_latest_frozen_records() {
+		// If this fails, the shape of MergeRequest was changed without a migration
+		let mr = v3_merge_request("polkadot", 2, "sha");
+		let mr = v8::MergeRequest {
+			sha: mr.sha,
+			was_updated: mr.was_updated,
+			owner: mr.owner,
+			repo: mr.repo,
+			number: mr.number,
+			html_url: mr.html_url,
+			requested_by: mr.requested_by,
+			dependencies: mr.dependencies,
+			merge_method: Some(v8::MergeMethod::Rebase),
+			requested_at: Some(chrono::Utc::now()),
+			state: v8::MergeState::Updating {
+				pushed_sha: Some("pushed".into()),
+			},
+		};
+		let current: webhook::MergeRequest =
+			bincode::deserialize(&bincode::serialize(&mr).unwrap()).unwrap();
+		assert_eq!(current.merge_method, Some(github::MergeMethod::Rebase));
+		let roundtrip: v8::MergeRequest =
+			bincode::deserialize(&bincode::serialize(&current).unwrap())
+				.unwrap();
+		assert_eq!(
+			bincode::serialize(&roundtrip).unwrap(),
+			bincode::serialize(&mr).unwrap()
+		);
+
+		// Likewise for MergeQueue
+		let queue = v8::MergeQueue {
+			owner: "paritytech".into(),
+			repo: "polkadot".into(),
+			base_branch: "master".into(),
+			entries: vec![v8::MergeQueueEntry {
+				number: 2,
+				html_url: "https://github.com/paritytech/polkadot/pull/2"
+					.into(),
+				requested_by: "foo".into(),
+				is_priority: true,
+			}],
+		};
+		let current: merge_queue::MergeQueue =
+			bincode::deserialize(&bincode::serialize(&queue).unwrap()).unwrap();
+		let roundtrip: v8::MergeQueue =
+			bincode::deserialize(&bincode::serialize(&current).unwrap())
+				.unwrap();
+		assert_eq!(
+			bincode::serialize(&roundtrip).unwrap(),
+			bincode::serialize(&queue).unwrap()
+		);
+
+		// Likewise for MergeBatch
+		let batch = v8::MergeBatch {
+			owner: "paritytech".into(),
+			repo: "polkadot".into(),
+			base_branch: "master".into(),
+			base_sha: "base".into(),
+			candidate_sha: "candidate".into(),
+			numbers: vec![1, 2],
+			pending: vec![vec![3], vec![4, 5]],
+		};
+		let current: merge_batch::MergeBatch =
+			bincode::deserialize(&bincode::serialize(&batch).unwrap()).unwrap();
+		let roundtrip: v8::MergeBatch =
+			bincode::deserialize(&bincode::serialize(&current).unwrap())
+				.unwrap();
+		assert_eq!(
+			bincode::serialize(&roundtrip).unwrap(),
+			bincode::serialize(&batch).unwrap()
+		);
+	}
+
+	#[test]
+	fn test_open_migrates_v4_merge_queues() {
+		let queue = v4::MergeQueue {
+			owner: "paritytech".into(),
+			repo: "polkadot".into(),
+			base_branch: "master".into(),
+			entries: vec![v4::MergeQueueEntry {
+				number: 2,
+				html_url: "https://github.com/paritytech/polkadot/pull/2"
+					.into(),
+				requested_by: "foo".into(),
+			}],
+		};
+		let dir = fixture_database(
+			"v4.0",
+			&[(
+				b"queue/paritytech/polkadot/master",
+				bincode::serialize(&queue).unwrap(),
+			)],
+		);
+
+		let db = db::open(dir.path()).unwrap();
+
+		assert_eq!(read_version(dir.path()), DATABASE_VERSION);
+		let migrated =
+			db::get_merge_queue(&db, "paritytech", "polkadot", "master")
+				.unwrap();
+		assert_eq!(migrated.position(2), Some(1));
+		assert!(!migrated.entries[0].is_priority);
+	}
+
+	#[test]
+	fn test_open_migrates_v5_merge_requests() {
+		let mr: v5::MergeRequest = v3_merge_request("polkadot", 2, "abc");
+		let dir = fixture_database(
+			"v5.0",
+			&[
+				(
+					b"pr/paritytech/polkadot/2",
+					bincode::serialize(&mr).unwrap(),
+				),
+				(b"sha/abc/paritytech/polkadot/2", vec![]),
+			],
+		);
+
+		let db = db::open(dir.path()).unwrap();
+
+		assert_eq!(read_version(dir.path()), DATABASE_VERSION);
+		let migrated = get_merge_request(&db, "paritytech", "polkadot", 2)
+			.unwrap()
+			.unwrap();
+		assert_eq!(migrated.sha, "abc");
+		assert_eq!(migrated.merge_method, None);
+		assert_eq!(get_merge_requests_by_sha(&db, "abc").unwrap().len(), 1);
+	}
+
+	#[test]
+	fn test_open_migrates_v6_merge_requests() {
+		let mr = v3_merge_request("polkadot", 2, "abc");
+		let mr = v6::MergeRequest {
+			sha: mr.sha,
+			was_updated: mr.was_updated,
+			owner: mr.owner,
+			repo: mr.repo,
+			number: mr.number,
+			html_url: mr.html_url,
+			requested_by: mr.requested_by,
+			dependencies: mr.dependencies,
+			merge_method: Some(v6::MergeMethod::Squash),
+		};
+		let dir = fixture_database(
+			"v6.0",
+			&[
+				(
+					b"pr/paritytech/polkadot/2",
+					bincode::serialize(&mr).unwrap(),
+				),
+				(b"sha/abc/paritytech/polkadot/2", vec![]),
+			],
+		);
+
+		let db = db::open(dir.path()).unwrap();
+
+		assert_eq!(read_version(dir.path()), DATABASE_VERSION);
+		let migrated = get_merge_request(&db, "paritytech", "polkadot", 2)
+			.unwrap()
+			.unwrap();
+		assert_eq!(migrated.merge_method, Some(github::MergeMethod::Squash));
+		assert_eq!(migrated.requested_at, None);
+	}
+
+	#[test]
+	fn test_open_migrates_v7_merge_requests() {
+		let mr = v3_merge_request("polkadot", 2, "abc");
+		let v7_merge_request =
+			|number: i64, dependencies: Option<Vec<v7::Dependency>>| {
+				v7::MergeRequest {
+					sha: mr.sha.clone(),
+					was_updated: mr.was_updated,
+					owner: mr.owner.clone(),
+					repo: mr.repo.clone(),
+					number,
+					html_url: mr.html_url.clone(),
+					requested_by: mr.requested_by.clone(),
+					dependencies,
+					merge_method: None,
+					requested_at: None,
+				}
+			};
+		let dir = fixture_database(
+			"v7.0",
+			&[
+				(
+					b"pr/paritytech/polkadot/2",
+					bincode::serialize(&v7_merge_request(
+						2,
+						mr.dependencies.clone(),
+					))
+					.unwrap(),
+				),
+				(
+					b"pr/paritytech/polkadot/3",
+					bincode::serialize(&v7_merge_request(3, None)).unwrap(),
+				),
+			],
+		);
+
+		let db = db::open(dir.path()).unwrap();
+
+		assert_eq!(read_version(dir.path()), DATABASE_VERSION);
+		let migrated = get_merge_request(&db, "paritytech", "polkadot", 2)
+			.unwrap()
+			.unwrap();
+		assert_eq!(migrated.state, webhook::MergeState::Queued);
+		let migrated = get_merge_request(&db, "paritytech", "polkadot", 3)
+			.unwrap()
+			.unwrap();
+		assert_eq!(migrated.state, webhook::MergeState::AwaitingChecks);
+	}
+
+	#[test]
+	fn test_open_migrates_v3_database() {
+		let mr = v3_merge_request("polkadot", 2, "abc");
+		let dir = fixture_database(
+			"v3.0",
+			&[
+				(b"abc", bincode::serialize(&mr).unwrap()),
+				(b"garbage", b"not a merge request".to_vec()),
+			],
+		);
+
+		let db = db::open(dir.path()).unwrap();
+
+		assert_eq!(read_version(dir.path()), DATABASE_VERSION);
+		assert!(db.get(b"abc").unwrap().is_none());
+		assert!(db.get(b"garbage").unwrap().is_none());
+		let migrated = get_merge_request(&db, "paritytech", "polkadot", 2)
+			.unwrap()
+			.unwrap();
+		assert_eq!(migrated.sha, "abc");
+		assert_eq!(migrated.requested_by, "foo");
+		assert_eq!(
+			migrated.dependencies.unwrap()[0].html_url,
+			"https://github.com/paritytech/substrate/pull/1"
+		);
+		assert_eq!(get_merge_requests_by_sha(&db, "abc").unwrap().len(), 1);
+	}
+
+	#[test]
+	fn test_open_refuses_unknown_version() {
+		let mr = v3_merge_request("polkadot", 2, "abc");
+		let dir = fixture_database(
+			"v2.0",
+			&[(b"abc", bincode::serialize(&mr).unwrap())],
+		);
+
+		assert!(db::open(dir.path()).is_err());
+
+		// The database should be left untouched
+		assert_eq!(read_version(dir.path()), "v2.0");
+		let db = DB::open_default(dir.path()).unwrap();
+		assert!(db.get(b"abc").unwrap().is_some());
+	}
+
+	#[test]
+	fn test_open_refuses_unversioned_database() {
+		let dir = fixture_database("does not matter", &[(b"abc", vec![])]);
+		fs::remove_file(dir.path().join(db::VERSION_FILE_NAME)).unwrap();
+
+		assert!(db::open(dir.path()).is_err());
+	}
+
+	#[test]
+	fn test_open_creates_empty_database() {
+		let dir = tempfile::tempdir().unwrap();
+
+		db::open(dir.path()).unwrap();
+
+		assert_eq!(read_version(dir.path()), DATABASE_VERSION);
+	}
+}
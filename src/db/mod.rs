@@ -0,0 +1,784 @@
+use chrono::{DateTime, Utc};
+use rocksdb::{ColumnFamily, Direction, IteratorMode, Options, WriteBatch, DB};
+use snafu::{OptionExt, ResultExt};
+use std::{
+	fs,
+	path::Path,
+	sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+	constants::DATABASE_VERSION,
+	error::*,
+	event_queue::{EventStatus, StoredEvent},
+	merge_batch::MergeBatch,
+	merge_queue::MergeQueue,
+	webhook::MergeRequest,
+	Result,
+};
+
+pub mod migrations;
+
+pub const VERSION_FILE_NAME: &str = "__PROCESSBOT_VERSION__";
+
+/*
+	Storage layout (since v4.0)
+
+	Merge requests are stored under "pr/{owner}/{repo}/{number}", which uniquely
+	identifies a pull request across all repositories.
+
+	Since status and check events only tell us about a commit SHA, a secondary
+	index is kept under "sha/{sha}/{owner}/{repo}/{number}" (with an empty
+	value) for finding the pull requests which have a given SHA as their head.
+	Keeping the pull request as part of the index's key means that two pull
+	requests from different repositories which happen to have the same head SHA
+	can be told apart.
+
+	Merge queues are stored under "queue/{owner}/{repo}/{base_branch}", one
+	record per queue, so that the order of its entries is updated atomically.
+
+	The batch being tested for a merge queue, if any, is stored under
+	"batch/{owner}/{repo}/{base_branch}".
+
+	The health checks (see check) write short-lived records under "health/",
+	which are deleted right after they're read back.
+
+	Webhook deliveries (see event_queue.rs) are stored in their own column family,
+	EVENTS_COLUMN_FAMILY, keyed by their X-GitHub-Delivery ID. They're kept apart
+	from the records above since they're written for every event which is
+	received, and they're not subject to DATABASE_VERSION's migrations: the
+	column family is created when it's missing.
+
+	The events are scheduled in another column family,
+	EVENT_SCHEDULE_COLUMN_FAMILY, with an empty value under keys which sort by
+	time:
+	- "due/{next_attempt_at}/{delivery_id}" for the pending events, so that the
+		events which are due can be found without going through the processed,
+		dead-lettered or not yet due ones
+	- "expiry/{received_at}/{delivery_id}" for all events, so that the expired
+		ones can be found likewise
+	The times are milliseconds since the epoch, zero-padded to 20 digits. The
+	schedule is written together with the events (see put_event) and built from
+	the stored events if it's missing (see schedule_stored_events).
+*/
+pub const MERGE_REQUEST_PREFIX: &str = "pr/";
+pub const SHA_INDEX_PREFIX: &str = "sha/";
+pub const MERGE_QUEUE_PREFIX: &str = "queue/";
+pub const MERGE_BATCH_PREFIX: &str = "batch/";
+pub const HEALTH_CHECK_PREFIX: &str = "health/";
+
+pub const EVENTS_COLUMN_FAMILY: &str = "events";
+pub const EVENT_SCHEDULE_COLUMN_FAMILY: &str = "event_schedule";
+const DUE_EVENT_PREFIX: &str = "due/";
+const EVENT_EXPIRY_PREFIX: &str = "expiry/";
+
+fn open_with_column_families(db_path: &Path) -> Result<DB> {
+	let mut opts = Options::default();
+	opts.create_if_missing(true);
+	opts.create_missing_column_families(true);
+	// Listing fails if the database doesn't exist yet, in which case there are
+	// no events to schedule
+	let is_schedule_missing = DB::list_cf(&opts, db_path)
+		.map(|cfs| !cfs.iter().any(|cf| cf == EVENT_SCHEDULE_COLUMN_FAMILY))
+		.unwrap_or(false);
+	let db = DB::open_cf(
+		&opts,
+		db_path,
+		[EVENTS_COLUMN_FAMILY, EVENT_SCHEDULE_COLUMN_FAMILY],
+	)
+	.context(Db)?;
+	if is_schedule_missing {
+		schedule_stored_events(&db)?;
+	}
+	Ok(db)
+}
+
+/// Open the database at `db_path`, migrating it to DATABASE_VERSION if it was
+/// created by a previous version. Existing data is never deleted: if there is no
+/// migration path from the database's version, this function errors out instead.
+pub fn open(db_path: &Path) -> Result<DB> {
+	let version_path = db_path.join(VERSION_FILE_NAME);
+
+	let version = if version_path.exists() {
+		fs::read_to_string(&version_path)
+			.context(Io)?
+			.trim()
+			.to_string()
+	} else {
+		if fs::read_dir(db_path).context(Io)?.next().is_some() {
+			return Err(Error::Message {
+				msg: format!(
+					"The database at {:?} has no {} file, therefore it's not known how to migrate it. Refusing to start.",
+					db_path, VERSION_FILE_NAME
+				),
+			});
+		}
+		log::info!("Creating database at version {}", DATABASE_VERSION);
+		fs::write(&version_path, DATABASE_VERSION).context(Io)?;
+		DATABASE_VERSION.to_string()
+	};
+
+	if version == DATABASE_VERSION {
+		return open_with_column_families(db_path);
+	}
+
+	let path = migrations::migration_path(&version, DATABASE_VERSION)
+		.context(Message {
+			msg: format!(
+				"There's no migration path from database version {} to {}. Refusing to start.",
+				version, DATABASE_VERSION
+			),
+		})?;
+
+	let db = open_with_column_families(db_path)?;
+	for migration in path {
+		log::info!(
+			"Migrating database from version {} to {}",
+			migration.from,
+			migration.to
+		);
+		(migration.run)(&db)?;
+		// Record each step as soon as it's done so that an interrupted migration resumes
+		// from the last version which was fully migrated
+		fs::write(&version_path, migration.to).context(Io)?;
+	}
+
+	Ok(db)
+}
+
+pub fn merge_request_key(owner: &str, repo: &str, number: i64) -> String {
+	format!("{}{}/{}/{}", MERGE_REQUEST_PREFIX, owner, repo, number)
+}
+
+fn sha_index_prefix(sha: &str) -> String {
+	format!("{}{}/", SHA_INDEX_PREFIX, sha)
+}
+
+fn sha_index_key(sha: &str, owner: &str, repo: &str, number: i64) -> String {
+	format!("{}{}/{}/{}", sha_index_prefix(sha), owner, repo, number)
+}
+
+fn prefix_iterator<'a>(
+	db: &'a DB,
+	prefix: &'a str,
+) -> impl Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a {
+	db.iterator(IteratorMode::From(prefix.as_bytes(), Direction::Forward))
+		.take_while(move |(key, _)| key.starts_with(prefix.as_bytes()))
+}
+
+pub fn get_merge_request(
+	db: &DB,
+	owner: &str,
+	repo: &str,
+	number: i64,
+) -> Result<Option<MergeRequest>> {
+	match db
+		.get(merge_request_key(owner, repo, number).as_bytes())
+		.context(Db)?
+	{
+		Some(bytes) => Ok(Some(bincode::deserialize(&bytes).context(Bincode)?)),
+		None => Ok(None),
+	}
+}
+
+/// Find the merge requests whose head SHA is `sha` through the SHA index.
+pub fn get_merge_requests_by_sha(
+	db: &DB,
+	sha: &str,
+) -> Result<Vec<MergeRequest>> {
+	let prefix = sha_index_prefix(sha);
+	let mut mrs = vec![];
+	for (key, _) in prefix_iterator(db, &prefix) {
+		let key = String::from_utf8_lossy(&key);
+		let pr_key = format!(
+			"{}{}",
+			MERGE_REQUEST_PREFIX,
+			key.trim_start_matches(prefix.as_str())
+		);
+		match db.get(pr_key.as_bytes()).context(Db)? {
+			Some(bytes) => {
+				let mr: MergeRequest =
+					bincode::deserialize(&bytes).context(Bincode)?;
+				// The index might be stale if the record was updated without going through
+				// put_merge_request; only trust it if the SHA still matches
+				if mr.sha == sha {
+					mrs.push(mr);
+				}
+			}
+			None => {
+				log::info!("Dangling SHA index entry {}", key);
+			}
+		}
+	}
+	Ok(mrs)
+}
+
+/// Iterate over all the stored merge requests, skipping the secondary indexes.
+/// The raw key is yielded alongside the deserialization result so that
+/// malformed entries can be reported and deleted by the caller.
+pub fn iter_merge_requests(
+	db: &DB,
+) -> impl Iterator<Item = (Box<[u8]>, Result<MergeRequest>)> + '_ {
+	prefix_iterator(db, MERGE_REQUEST_PREFIX).map(|(key, value)| {
+		let mr = bincode::deserialize::<MergeRequest>(&value).context(Bincode);
+		(key, mr)
+	})
+}
+
+/// All the stored merge requests. Malformed entries are logged and skipped.
+pub fn get_merge_requests(db: &DB) -> Vec<MergeRequest> {
+	iter_merge_requests(db)
+		.filter_map(|(key, mr)| match mr {
+			Ok(mr) => Some(mr),
+			Err(err) => {
+				log::error!(
+					"Failed to deserialize key {} from the database due to {:?}",
+					String::from_utf8_lossy(&key),
+					err
+				);
+				None
+			}
+		})
+		.collect()
+}
+
+/// Check that the database is usable by writing a record, reading it back and
+/// deleting it. Each check uses its own key so that concurrent checks don't
+/// interfere with each other.
+pub fn check(db: &DB) -> Result<()> {
+	static CHECKS: AtomicU64 = AtomicU64::new(0);
+	let key = format!(
+		"{}{}",
+		HEALTH_CHECK_PREFIX,
+		CHECKS.fetch_add(1, Ordering::Relaxed)
+	);
+	let value = Utc::now().to_rfc3339();
+
+	db.put(key.as_bytes(), value.as_bytes()).context(Db)?;
+	let read = db.get(key.as_bytes()).context(Db)?;
+	db.delete(key.as_bytes()).context(Db)?;
+
+	if read.as_deref() == Some(value.as_bytes()) {
+		Ok(())
+	} else {
+		Err(Error::Message {
+			msg: format!("Read back {:?} instead of {:?}", read, value),
+		})
+	}
+}
+
+/// Create or update the record of a merge request, keeping the SHA index in
+/// sync with the record's head SHA.
+pub fn put_merge_request(db: &DB, mr: &MergeRequest) -> Result<()> {
+	let mut batch = WriteBatch::default();
+
+	if let Some(prev_mr) =
+		get_merge_request(db, &mr.owner, &mr.repo, mr.number)?
+	{
+		if prev_mr.sha != mr.sha {
+			batch.delete(
+				sha_index_key(&prev_mr.sha, &mr.owner, &mr.repo, mr.number)
+					.as_bytes(),
+			);
+		}
+	}
+
+	batch.put(
+		merge_request_key(&mr.owner, &mr.repo, mr.number).as_bytes(),
+		bincode::serialize(mr).context(Bincode)?,
+	);
+	batch.put(
+		sha_index_key(&mr.sha, &mr.owner, &mr.repo, mr.number).as_bytes(),
+		b"",
+	);
+
+	db.write(batch).context(Db)
+}
+
+/// Delete the record of a merge request along with its SHA index entry.
+/// Returns the record which was deleted, if any.
+pub fn delete_merge_request(
+	db: &DB,
+	owner: &str,
+	repo: &str,
+	number: i64,
+) -> Result<Option<MergeRequest>> {
+	let key = merge_request_key(owner, repo, number);
+	let bytes = match db.get(key.as_bytes()).context(Db)? {
+		Some(bytes) => bytes,
+		None => return Ok(None),
+	};
+
+	let mut batch = WriteBatch::default();
+	batch.delete(key.as_bytes());
+	let mr = match bincode::deserialize::<MergeRequest>(&bytes) {
+		Ok(mr) => {
+			batch
+				.delete(sha_index_key(&mr.sha, owner, repo, number).as_bytes());
+			Some(mr)
+		}
+		Err(err) => {
+			log::error!(
+				"Failed to deserialize {} while deleting it due to {:?}",
+				key,
+				err
+			);
+			None
+		}
+	};
+	db.write(batch).context(Db)?;
+
+	Ok(mr)
+}
+
+fn merge_queue_key(owner: &str, repo: &str, base_branch: &str) -> String {
+	format!("{}{}/{}/{}", MERGE_QUEUE_PREFIX, owner, repo, base_branch)
+}
+
+pub fn get_merge_queue(
+	db: &DB,
+	owner: &str,
+	repo: &str,
+	base_branch: &str,
+) -> Result<MergeQueue> {
+	match db
+		.get(merge_queue_key(owner, repo, base_branch).as_bytes())
+		.context(Db)?
+	{
+		Some(bytes) => bincode::deserialize(&bytes).context(Bincode),
+		None => Ok(MergeQueue {
+			owner: owner.into(),
+			repo: repo.into(),
+			base_branch: base_branch.into(),
+			entries: vec![],
+		}),
+	}
+}
+
+/// Store a merge queue; empty queues are deleted.
+pub fn put_merge_queue(db: &DB, queue: &MergeQueue) -> Result<()> {
+	let key = merge_queue_key(&queue.owner, &queue.repo, &queue.base_branch);
+	if queue.entries.is_empty() {
+		db.delete(key.as_bytes()).context(Db)
+	} else {
+		db.put(key.as_bytes(), bincode::serialize(queue).context(Bincode)?)
+			.context(Db)
+	}
+}
+
+/// Collect the merge queues of a repository (one for each base branch).
+pub fn get_repository_merge_queues(
+	db: &DB,
+	owner: &str,
+	repo: &str,
+) -> Result<Vec<MergeQueue>> {
+	let prefix = format!("{}{}/{}/", MERGE_QUEUE_PREFIX, owner, repo);
+	prefix_iterator(db, &prefix)
+		.map(|(_, value)| bincode::deserialize(&value).context(Bincode))
+		.collect()
+}
+
+/// Collect all the merge queues.
+pub fn get_merge_queues(db: &DB) -> Result<Vec<MergeQueue>> {
+	prefix_iterator(db, MERGE_QUEUE_PREFIX)
+		.map(|(_, value)| bincode::deserialize(&value).context(Bincode))
+		.collect()
+}
+
+fn merge_batch_key(owner: &str, repo: &str, base_branch: &str) -> String {
+	format!("{}{}/{}/{}", MERGE_BATCH_PREFIX, owner, repo, base_branch)
+}
+
+pub fn get_merge_batch(
+	db: &DB,
+	owner: &str,
+	repo: &str,
+	base_branch: &str,
+) -> Result<Option<MergeBatch>> {
+	match db
+		.get(merge_batch_key(owner, repo, base_branch).as_bytes())
+		.context(Db)?
+	{
+		Some(bytes) => bincode::deserialize(&bytes).context(Bincode).map(Some),
+		None => Ok(None),
+	}
+}
+
+pub fn put_merge_batch(db: &DB, batch: &MergeBatch) -> Result<()> {
+	db.put(
+		merge_batch_key(&batch.owner, &batch.repo, &batch.base_branch)
+			.as_bytes(),
+		bincode::serialize(batch).context(Bincode)?,
+	)
+	.context(Db)
+}
+
+pub fn delete_merge_batch(
+	db: &DB,
+	owner: &str,
+	repo: &str,
+	base_branch: &str,
+) -> Result<()> {
+	db.delete(merge_batch_key(owner, repo, base_branch).as_bytes())
+		.context(Db)
+}
+
+/// Collect all the batches being tested.
+pub fn get_merge_batches(db: &DB) -> Result<Vec<MergeBatch>> {
+	prefix_iterator(db, MERGE_BATCH_PREFIX)
+		.map(|(_, value)| bincode::deserialize(&value).context(Bincode))
+		.collect()
+}
+
+fn events_column_family(db: &DB) -> Result<&ColumnFamily> {
+	db.cf_handle(EVENTS_COLUMN_FAMILY).context(Message {
+		msg: format!("The {} column family is missing", EVENTS_COLUMN_FAMILY),
+	})
+}
+
+fn event_schedule_column_family(db: &DB) -> Result<&ColumnFamily> {
+	db.cf_handle(EVENT_SCHEDULE_COLUMN_FAMILY).context(Message {
+		msg: format!(
+			"The {} column family is missing",
+			EVENT_SCHEDULE_COLUMN_FAMILY
+		),
+	})
+}
+
+fn event_schedule_key(
+	prefix: &str,
+	time: DateTime<Utc>,
+	delivery_id: &str,
+) -> String {
+	format!("{}{:020}/{}", prefix, time.timestamp_millis(), delivery_id)
+}
+
+fn event_schedule_keys(event: &StoredEvent) -> Vec<String> {
+	let mut keys = vec![event_schedule_key(
+		EVENT_EXPIRY_PREFIX,
+		event.received_at,
+		&event.delivery_id,
+	)];
+	if event.status == EventStatus::Pending {
+		keys.push(event_schedule_key(
+			DUE_EVENT_PREFIX,
+			event.next_attempt_at,
+			&event.delivery_id,
+		));
+	}
+	keys
+}
+
+pub fn get_event(db: &DB, delivery_id: &str) -> Result<Option<StoredEvent>> {
+	match db
+		.get_cf(events_column_family(db)?, delivery_id.as_bytes())
+		.context(Db)?
+	{
+		Some(bytes) => bincode::deserialize(&bytes).context(Bincode).map(Some),
+		None => Ok(None),
+	}
+}
+
+/// Create or update the record of an event, keeping its schedule in sync.
+pub fn put_event(db: &DB, event: &StoredEvent) -> Result<()> {
+	let schedule_cf = event_schedule_column_family(db)?;
+	let mut batch = WriteBatch::default();
+
+	// A record which can't be deserialized is overwritten regardless
+	if let Ok(Some(prev_event)) = get_event(db, &event.delivery_id) {
+		for key in event_schedule_keys(&prev_event) {
+			batch.delete_cf(schedule_cf, key.as_bytes());
+		}
+	}
+	for key in event_schedule_keys(event) {
+		batch.put_cf(schedule_cf, key.as_bytes(), b"");
+	}
+	batch.put_cf(
+		events_column_family(db)?,
+		event.delivery_id.as_bytes(),
+		bincode::serialize(event).context(Bincode)?,
+	);
+
+	db.write(batch).context(Db)
+}
+
+/// Delete the record of an event along with its schedule.
+pub fn delete_event(db: &DB, delivery_id: &str) -> Result<()> {
+	let mut batch = WriteBatch::default();
+	if let Ok(Some(event)) = get_event(db, delivery_id) {
+		let schedule_cf = event_schedule_column_family(db)?;
+		for key in event_schedule_keys(&event) {
+			batch.delete_cf(schedule_cf, key.as_bytes());
+		}
+	}
+	batch.delete_cf(events_column_family(db)?, delivery_id.as_bytes());
+	db.write(batch).context(Db)
+}
+
+/// Collect the events scheduled under `prefix` up to `until`, in chronological
+/// order. Schedule entries whose event is gone and records which can't be
+/// deserialized are deleted since they'd otherwise never be processed.
+fn get_scheduled_events(
+	db: &DB,
+	prefix: &str,
+	until: DateTime<Utc>,
+) -> Result<Vec<StoredEvent>> {
+	let events_cf = events_column_family(db)?;
+	let schedule_cf = event_schedule_column_family(db)?;
+	let until = until.timestamp_millis();
+
+	let mut events = vec![];
+	let entries = db
+		.iterator_cf(
+			schedule_cf,
+			IteratorMode::From(prefix.as_bytes(), Direction::Forward),
+		)
+		.take_while(|(key, _)| key.starts_with(prefix.as_bytes()));
+	for (key, _) in entries {
+		let entry = String::from_utf8_lossy(&key[prefix.len()..]).into_owned();
+		let (time, delivery_id) = match entry.split_once('/') {
+			Some((time, delivery_id)) => {
+				(time.parse::<i64>().ok(), delivery_id)
+			}
+			None => (None, entry.as_str()),
+		};
+		match time {
+			Some(time) if time > until => break,
+			Some(_) => (),
+			None => {
+				log::error!(
+					"Deleting invalid event schedule entry {}",
+					String::from_utf8_lossy(&key)
+				);
+				db.delete_cf(schedule_cf, &key).context(Db)?;
+				continue;
+			}
+		}
+
+		match db.get_cf(events_cf, delivery_id.as_bytes()).context(Db)? {
+			Some(value) => match bincode::deserialize::<StoredEvent>(&value) {
+				Ok(event) => events.push(event),
+				Err(err) => {
+					log::error!(
+						"Failed to deserialize event {} from the database due to {:?}",
+						delivery_id,
+						err
+					);
+					db.delete_cf(events_cf, delivery_id.as_bytes())
+						.context(Db)?;
+					db.delete_cf(schedule_cf, &key).context(Db)?;
+				}
+			},
+			None => db.delete_cf(schedule_cf, &key).context(Db)?,
+		}
+	}
+	Ok(events)
+}
+
+/// Collect the pending events which are due for an attempt at `now`, in the
+/// order of their next attempt.
+pub fn get_due_events(db: &DB, now: DateTime<Utc>) -> Result<Vec<StoredEvent>> {
+	get_scheduled_events(db, DUE_EVENT_PREFIX, now)
+}
+
+/// Collect the events which were received up to `received_before`, whatever
+/// their status.
+pub fn get_events_received_before(
+	db: &DB,
+	received_before: DateTime<Utc>,
+) -> Result<Vec<StoredEvent>> {
+	get_scheduled_events(db, EVENT_EXPIRY_PREFIX, received_before)
+}
+
+/// Schedule the stored events, e.g. the ones which were stored before the
+/// schedule existed. Records which can't be deserialized are deleted since
+/// they'd otherwise never be processed.
+fn schedule_stored_events(db: &DB) -> Result<()> {
+	log::info!("Scheduling the stored events");
+	let events_cf = events_column_family(db)?;
+	let schedule_cf = event_schedule_column_family(db)?;
+	let mut batch = WriteBatch::default();
+	for (key, value) in db.iterator_cf(events_cf, IteratorMode::Start) {
+		match bincode::deserialize::<StoredEvent>(&value) {
+			Ok(event) => {
+				for key in event_schedule_keys(&event) {
+					batch.put_cf(schedule_cf, key.as_bytes(), b"");
+				}
+			}
+			Err(err) => {
+				log::error!(
+					"Failed to deserialize event {} from the database due to {:?}",
+					String::from_utf8_lossy(&key),
+					err
+				);
+				batch.delete_cf(events_cf, &key);
+			}
+		}
+	}
+	db.write(batch).context(Db)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::MergeRequestBuilder;
+
+	fn merge_request(repo: &str, number: i64, sha: &str) -> MergeRequest {
+		MergeRequestBuilder::new(repo, number).sha(sha).build()
+	}
+
+	#[test]
+	fn test_same_sha_in_different_repositories() {
+		let dir = tempfile::tempdir().unwrap();
+		let db = DB::open_default(dir.path()).unwrap();
+
+		put_merge_request(&db, &merge_request("substrate", 1, "abc")).unwrap();
+		put_merge_request(&db, &merge_request("polkadot", 2, "abc")).unwrap();
+
+		let mut repos = get_merge_requests_by_sha(&db, "abc")
+			.unwrap()
+			.into_iter()
+			.map(|mr| mr.repo)
+			.collect::<Vec<_>>();
+		repos.sort();
+		assert_eq!(repos, vec!["polkadot", "substrate"]);
+
+		delete_merge_request(&db, "paritytech", "substrate", 1).unwrap();
+		let mrs = get_merge_requests_by_sha(&db, "abc").unwrap();
+		assert_eq!(mrs.len(), 1);
+		assert_eq!(mrs[0].repo, "polkadot");
+	}
+
+	#[test]
+	fn test_sha_index_follows_updates() {
+		let dir = tempfile::tempdir().unwrap();
+		let db = DB::open_default(dir.path()).unwrap();
+
+		put_merge_request(&db, &merge_request("polkadot", 1, "old")).unwrap();
+		put_merge_request(&db, &merge_request("polkadot", 1, "new")).unwrap();
+
+		assert!(get_merge_requests_by_sha(&db, "old").unwrap().is_empty());
+		assert_eq!(get_merge_requests_by_sha(&db, "new").unwrap().len(), 1);
+		assert_eq!(iter_merge_requests(&db).count(), 1);
+	}
+
+	#[test]
+	fn test_check_leaves_no_records_behind() {
+		let dir = tempfile::tempdir().unwrap();
+		let db = DB::open_default(dir.path()).unwrap();
+
+		check(&db).unwrap();
+		check(&db).unwrap();
+
+		assert_eq!(prefix_iterator(&db, HEALTH_CHECK_PREFIX).count(), 0);
+	}
+
+	fn event(
+		delivery_id: &str,
+		status: EventStatus,
+		next_attempt_at: DateTime<Utc>,
+	) -> StoredEvent {
+		StoredEvent {
+			delivery_id: delivery_id.into(),
+			event_type: None,
+			body: b"{}".to_vec(),
+			received_at: next_attempt_at,
+			status,
+			attempts: 0,
+			next_attempt_at,
+			last_error: None,
+		}
+	}
+
+	fn delivery_ids(events: Vec<StoredEvent>) -> Vec<String> {
+		events.into_iter().map(|event| event.delivery_id).collect()
+	}
+
+	#[test]
+	fn test_event_schedule_follows_updates() {
+		let dir = tempfile::tempdir().unwrap();
+		let db = open(dir.path()).unwrap();
+		let now = Utc::now();
+
+		put_event(&db, &event("due", EventStatus::Pending, now)).unwrap();
+		put_event(
+			&db,
+			&event(
+				"later",
+				EventStatus::Pending,
+				now + chrono::Duration::hours(1),
+			),
+		)
+		.unwrap();
+		put_event(&db, &event("processed", EventStatus::Processed, now))
+			.unwrap();
+		put_event(&db, &event("dead", EventStatus::DeadLetter, now)).unwrap();
+		assert_eq!(
+			delivery_ids(get_due_events(&db, now).unwrap()),
+			vec!["due"]
+		);
+		assert_eq!(get_events_received_before(&db, now).unwrap().len(), 3);
+
+		// The previous attempt's entry is replaced
+		put_event(
+			&db,
+			&StoredEvent {
+				next_attempt_at: now + chrono::Duration::minutes(1),
+				..event("due", EventStatus::Pending, now)
+			},
+		)
+		.unwrap();
+		assert!(get_due_events(&db, now).unwrap().is_empty());
+		assert_eq!(
+			delivery_ids(
+				get_due_events(&db, now + chrono::Duration::hours(1)).unwrap()
+			),
+			vec!["due", "later"]
+		);
+
+		put_event(&db, &event("due", EventStatus::Processed, now)).unwrap();
+		delete_event(&db, "later").unwrap();
+		delete_event(&db, "processed").unwrap();
+		delete_event(&db, "dead").unwrap();
+		assert!(get_due_events(&db, now + chrono::Duration::hours(1))
+			.unwrap()
+			.is_empty());
+		let schedule = db
+			.iterator_cf(
+				event_schedule_column_family(&db).unwrap(),
+				IteratorMode::Start,
+			)
+			.map(|(key, _)| String::from_utf8_lossy(&key).into_owned())
+			.collect::<Vec<_>>();
+		assert_eq!(
+			schedule,
+			vec![event_schedule_key(EVENT_EXPIRY_PREFIX, now, "due")]
+		);
+	}
+
+	#[test]
+	fn test_stored_events_are_scheduled_when_the_schedule_is_missing() {
+		let dir = tempfile::tempdir().unwrap();
+		let now = Utc::now();
+		{
+			// A database from before the schedule existed
+			let mut opts = Options::default();
+			opts.create_if_missing(true);
+			opts.create_missing_column_families(true);
+			let db =
+				DB::open_cf(&opts, dir.path(), [EVENTS_COLUMN_FAMILY]).unwrap();
+			let event = event("1", EventStatus::Pending, now);
+			db.put_cf(
+				events_column_family(&db).unwrap(),
+				event.delivery_id.as_bytes(),
+				bincode::serialize(&event).unwrap(),
+			)
+			.unwrap();
+			fs::write(dir.path().join(VERSION_FILE_NAME), DATABASE_VERSION)
+				.unwrap();
+		}
+
+		let db = open(dir.path()).unwrap();
+		assert_eq!(delivery_ids(get_due_events(&db, now).unwrap()), vec!["1"]);
+	}
+}
@@ -61,6 +61,11 @@ pub enum Error {
 		source: rocksdb::Error,
 	},
 
+	#[snafu(display("Io: {}", source))]
+	Io {
+		source: std::io::Error,
+	},
+
 	#[snafu(display("Utf8: {}", source))]
 	Utf8 {
 		source: std::string::FromUtf8Error,
@@ -92,6 +97,29 @@ pub enum Error {
 		err: String,
 	},
 
+	#[snafu(display(
+		"Merge of {} failed due to conflicts in: {}",
+		base_branch,
+		files.join(", ")
+	))]
+	MergeConflict {
+		base_branch: String,
+		files: Vec<String>,
+	},
+
+	#[snafu(display(
+		"Rebase onto {} failed due to conflicts of commit {} in: {}",
+		base_branch,
+		commit_sha,
+		files.join(", ")
+	))]
+	RebaseConflict {
+		base_branch: String,
+		commit_sha: String,
+		commit_subject: String,
+		files: Vec<String>,
+	},
+
 	#[snafu(display(
 		"Encountered merge failure (would be solved later): {}",
 		msg
@@ -99,6 +127,18 @@ pub enum Error {
 	MergeFailureWillBeSolvedLater {
 		msg: String,
 	},
+
+	// The failure is expected to go away by itself, e.g. GitHub responded with a
+	// 502 or timed out even after retrying
+	#[snafu(display(
+		"Transient failure after {} attempts: {}",
+		attempts,
+		source
+	))]
+	Transient {
+		attempts: u32,
+		source: Box<Error>,
+	},
 }
 
 impl Error {
@@ -115,7 +155,16 @@ impl Error {
 		match self {
 			Self::WithIssue { source, .. } => source.stops_merge_attempt(),
 			Self::MergeFailureWillBeSolvedLater { .. } => false,
+			// The merge is attempted again later on, e.g. by the poll
+			Self::Transient { .. } => false,
 			_ => true,
 		}
 	}
+	pub fn is_transient(&self) -> bool {
+		match self {
+			Self::WithIssue { source, .. } => source.is_transient(),
+			Self::Transient { .. } => true,
+			_ => false,
+		}
+	}
 }
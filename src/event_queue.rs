@@ -0,0 +1,461 @@
+use chrono::{DateTime, Duration, Utc};
+use ring::digest;
+use rocksdb::DB;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, sync::Arc};
+use tokio::{sync::Notify, time::timeout};
+
+use crate::{
+	config::MainConfig,
+	db, http_cache, metrics, shutdown,
+	webhook::{handle_error, handle_event, AppState},
+	Result,
+};
+
+/*
+	Webhook deliveries are stored in the database before they're acknowledged
+	(see enqueue) and processed afterwards by run_worker, so that the events which
+	were received are not lost if processbot panics or is restarted while
+	handling them.
+
+	An attempt is recorded right before an event is processed, therefore an event
+	whose processing was interrupted is attempted again once its retry delay
+	elapses, up to MAX_ATTEMPTS times, after which it's dead-lettered. Errors
+	which are reported back to the pull request (see handle_error) don't count as
+	failed attempts since they're the outcome of the event, unlike transient
+	failures (see Error::Transient) after which the event is attempted again.
+
+	Events are kept for config.delivery_ttl_hours after they're received, which
+	serves two purposes: deliveries which are received again within that period
+	(GitHub and the webhook proxy might redeliver events) are skipped, and
+	processed or dead-lettered events can be replayed by their delivery ID
+	through the admin API (see admin.rs).
+
+	Events are encoded with bincode like the other records, so changing the shape
+	of StoredEvent requires the events column family to be migrated.
+*/
+
+pub const MAX_ATTEMPTS: u32 = 5;
+
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+lazy_static::lazy_static! {
+	static ref NEW_EVENTS: Notify = Notify::new();
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum EventStatus {
+	Pending,
+	Processed,
+	DeadLetter,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StoredEvent {
+	pub delivery_id: String,
+	// The X-GitHub-Event header, if it was sent
+	pub event_type: Option<String>,
+	// The payload exactly as it was received
+	pub body: Vec<u8>,
+	pub received_at: DateTime<Utc>,
+	pub status: EventStatus,
+	pub attempts: u32,
+	pub next_attempt_at: DateTime<Utc>,
+	pub last_error: Option<String>,
+}
+
+/// The delivery's ID according to its X-GitHub-Delivery header. Deliveries which
+/// don't have one (e.g. from a webhook proxy which doesn't forward it) are
+/// identified by the SHA-256 of their payload.
+pub fn delivery_id(header: Option<&str>, body: &[u8]) -> String {
+	match header {
+		Some(header) if !header.is_empty() => header.to_owned(),
+		_ => {
+			base16::encode_lower(digest::digest(&digest::SHA256, body).as_ref())
+		}
+	}
+}
+
+/// 30 seconds after the first attempt, doubling on each attempt, up to an hour.
+fn retry_delay(attempts: u32) -> Duration {
+	let delay = Duration::seconds(30 * 2_i64.pow(attempts.saturating_sub(1)));
+	delay.min(Duration::hours(1))
+}
+
+pub fn delivery_ttl(config: &MainConfig) -> Duration {
+	Duration::hours(config.delivery_ttl_hours as i64)
+}
+
+fn is_expired(event: &StoredEvent, ttl: Duration, now: DateTime<Utc>) -> bool {
+	event.received_at + ttl < now
+}
+
+/// Store a delivery for processing unless it was already received within the
+/// TTL. Returns whether it was stored.
+pub fn enqueue(
+	db: &DB,
+	ttl: Duration,
+	delivery_id: &str,
+	event_type: Option<&str>,
+	body: Vec<u8>,
+) -> Result<bool> {
+	let now = Utc::now();
+
+	if let Some(event) = db::get_event(db, delivery_id)? {
+		if !is_expired(&event, ttl, now) {
+			metrics::record_webhook_event(event_type, "duplicate");
+			log::info!(
+				"Skipping delivery {} ({:?}) since it was already received at {} ({:?} after {} attempts)",
+				delivery_id,
+				event_type,
+				event.received_at,
+				event.status,
+				event.attempts
+			);
+			return Ok(false);
+		}
+	}
+
+	log::info!("Storing delivery {} ({:?})", delivery_id, event_type);
+	db::put_event(
+		db,
+		&StoredEvent {
+			delivery_id: delivery_id.to_owned(),
+			event_type: event_type.map(|event_type| event_type.to_owned()),
+			body,
+			received_at: now,
+			status: EventStatus::Pending,
+			attempts: 0,
+			next_attempt_at: now,
+			last_error: None,
+		},
+	)?;
+	NEW_EVENTS.notify();
+	Ok(true)
+}
+
+/// Process a stored delivery again from scratch. Returns false if there's no
+/// such delivery.
+pub fn replay(db: &DB, delivery_id: &str) -> Result<bool> {
+	let mut event = match db::get_event(db, delivery_id)? {
+		Some(event) => event,
+		None => return Ok(false),
+	};
+	log::info!(
+		"Replaying delivery {} ({:?} after {} attempts)",
+		delivery_id,
+		event.status,
+		event.attempts
+	);
+	event.status = EventStatus::Pending;
+	event.attempts = 0;
+	event.next_attempt_at = Utc::now();
+	event.last_error = None;
+	db::put_event(db, &event)?;
+	NEW_EVENTS.notify();
+	Ok(true)
+}
+
+/// Collect the events which are due for an attempt, in the order they were
+/// received, and record the attempt. Events which ran out of attempts are
+/// dead-lettered and expired events are deleted along the way. Only the due and
+/// the expired events are read (see the event schedule in db/mod.rs).
+fn take_due_events(
+	db: &DB,
+	ttl: Duration,
+	now: DateTime<Utc>,
+	in_flight: &HashSet<String>,
+) -> Result<Vec<StoredEvent>> {
+	// Pending events are kept until they're processed or dead-lettered
+	for event in db::get_events_received_before(db, now - ttl)? {
+		if event.status != EventStatus::Pending && is_expired(&event, ttl, now)
+		{
+			db::delete_event(db, &event.delivery_id)?;
+		}
+	}
+
+	let mut due = vec![];
+	for mut event in db::get_due_events(db, now)? {
+		if in_flight.contains(&event.delivery_id) {
+			continue;
+		}
+
+		if event.attempts >= MAX_ATTEMPTS {
+			log::error!(
+				"Dead-lettering delivery {} after {} attempts; last error: {:?}",
+				event.delivery_id,
+				event.attempts,
+				event.last_error
+			);
+			event.status = EventStatus::DeadLetter;
+			db::put_event(db, &event)?;
+			metrics::record_webhook_event(
+				event.event_type.as_deref(),
+				"dead_letter",
+			);
+			continue;
+		}
+
+		event.attempts += 1;
+		event.next_attempt_at = now + retry_delay(event.attempts);
+		db::put_event(db, &event)?;
+		due.push(event);
+	}
+
+	due.sort_by_key(|event| event.received_at);
+	Ok(due)
+}
+
+/// Record the outcome of an attempt, unless the event was replayed while it was
+/// being processed. The attempt succeeded if the event was processed, whether or
+/// not an error was reported for it.
+fn finish_attempt(
+	db: &DB,
+	attempted: &StoredEvent,
+	outcome: std::result::Result<bool, String>,
+) -> Result<()> {
+	let mut event = match db::get_event(db, &attempted.delivery_id)? {
+		Some(event)
+			if event.status == EventStatus::Pending
+				&& event.attempts == attempted.attempts =>
+		{
+			event
+		}
+		_ => return Ok(()),
+	};
+
+	match outcome {
+		Ok(was_successful) => {
+			event.status = EventStatus::Processed;
+			event.last_error = None;
+			metrics::record_webhook_event(
+				event.event_type.as_deref(),
+				if was_successful {
+					"processed"
+				} else {
+					"failed"
+				},
+			);
+		}
+		Err(err) => {
+			metrics::record_webhook_event(
+				event.event_type.as_deref(),
+				"interrupted",
+			);
+			log::error!(
+				"Attempt {} of delivery {} failed due to {}",
+				event.attempts,
+				event.delivery_id,
+				err
+			);
+			if event.attempts >= MAX_ATTEMPTS {
+				log::error!(
+					"Dead-lettering delivery {} after {} attempts",
+					event.delivery_id,
+					event.attempts
+				);
+				event.status = EventStatus::DeadLetter;
+				metrics::record_webhook_event(
+					event.event_type.as_deref(),
+					"dead_letter",
+				);
+			}
+			event.last_error = Some(err);
+		}
+	}
+
+	db::put_event(db, &event)
+}
+
+/// Returns false if an error was reported for the event, or an error if the
+/// event should be attempted again.
+async fn process_event(
+	state: &AppState,
+	body: &[u8],
+) -> std::result::Result<bool, String> {
+	let (merge_cancel_outcome, result) =
+		http_cache::with_event_memo(handle_event(state, body)).await;
+	match result {
+		Ok(()) => Ok(true),
+		Err(err) if err.is_transient() => Err(err.to_string()),
+		Err(err) => {
+			handle_error(merge_cancel_outcome, err, state).await;
+			Ok(false)
+		}
+	}
+}
+
+fn process_due_events(
+	state: &Arc<AppState>,
+	in_flight: &Arc<parking_lot::Mutex<HashSet<String>>>,
+) -> Result<()> {
+	let AppState { db, config, .. } = &**state;
+	let events = take_due_events(
+		db,
+		delivery_ttl(config),
+		Utc::now(),
+		&in_flight.lock(),
+	)?;
+
+	// Events are processed concurrently; the processing of each pull request is
+	// serialized through its lock (see lock.rs)
+	for event in events {
+		in_flight.lock().insert(event.delivery_id.clone());
+		let state = state.clone();
+		let in_flight = in_flight.clone();
+		tokio::spawn(async move {
+			let _work = shutdown::start_work().await;
+			log::info!(
+				"Processing delivery {} (attempt {})",
+				event.delivery_id,
+				event.attempts
+			);
+
+			// Processed in a task of its own so that panics are caught by its
+			// JoinHandle and recorded as failed attempts
+			let outcome = {
+				let state = state.clone();
+				let body = event.body.clone();
+				tokio::spawn(async move { process_event(&state, &body).await })
+					.await
+					.map_err(|err| err.to_string())
+					.and_then(|outcome| outcome)
+			};
+
+			if let Err(err) = finish_attempt(&state.db, &event, outcome) {
+				log::error!(
+					"Failed to record the outcome of delivery {} due to {:?}",
+					event.delivery_id,
+					err
+				);
+			}
+			in_flight.lock().remove(&event.delivery_id);
+		});
+	}
+
+	Ok(())
+}
+
+/// Process the stored events as they're due until a shutdown is requested.
+pub async fn run_worker(state: Arc<AppState>) {
+	let in_flight = Arc::new(parking_lot::Mutex::new(HashSet::new()));
+	while !shutdown::is_requested() {
+		if let Err(err) = process_due_events(&state, &in_flight) {
+			log::error!("Failed to process the stored events due to {:?}", err);
+		}
+		let _ = timeout(POLL_INTERVAL, NEW_EVENTS.notified()).await;
+	}
+	log::info!("Stopped taking events from the queue");
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn ttl() -> Duration {
+		Duration::hours(24)
+	}
+
+	#[test]
+	fn test_events_are_retried_then_dead_lettered() {
+		let dir = tempfile::tempdir().unwrap();
+		let db = db::open(dir.path()).unwrap();
+		let in_flight = HashSet::new();
+
+		enqueue(&db, ttl(), "1", Some("status"), b"{}".to_vec()).unwrap();
+		let mut now = Utc::now();
+
+		for attempt in 1..=MAX_ATTEMPTS {
+			let due = take_due_events(&db, ttl(), now, &in_flight).unwrap();
+			assert_eq!(due.len(), 1);
+			assert_eq!(due[0].attempts, attempt);
+			// Not due again until the retry delay elapses
+			assert!(take_due_events(&db, ttl(), now, &in_flight)
+				.unwrap()
+				.is_empty());
+
+			finish_attempt(&db, &due[0], Err("panicked".into())).unwrap();
+			now += retry_delay(attempt);
+		}
+
+		let event = db::get_event(&db, "1").unwrap().unwrap();
+		assert_eq!(event.status, EventStatus::DeadLetter);
+		assert_eq!(event.last_error.as_deref(), Some("panicked"));
+		assert!(take_due_events(&db, ttl(), now, &in_flight)
+			.unwrap()
+			.is_empty());
+	}
+
+	#[test]
+	fn test_interrupted_attempts_are_retried() {
+		let dir = tempfile::tempdir().unwrap();
+		let db = db::open(dir.path()).unwrap();
+		let in_flight = HashSet::new();
+
+		enqueue(&db, ttl(), "1", None, b"{}".to_vec()).unwrap();
+		let now = Utc::now();
+		let due = take_due_events(&db, ttl(), now, &in_flight).unwrap();
+		assert_eq!(due.len(), 1);
+
+		// The attempt was never finished, e.g. because processbot was restarted
+		let due = take_due_events(&db, ttl(), now + retry_delay(1), &in_flight)
+			.unwrap();
+		assert_eq!(due.len(), 1);
+		assert_eq!(due[0].attempts, 2);
+
+		finish_attempt(&db, &due[0], Ok(true)).unwrap();
+		let event = db::get_event(&db, "1").unwrap().unwrap();
+		assert_eq!(event.status, EventStatus::Processed);
+	}
+
+	#[test]
+	fn test_replay() {
+		let dir = tempfile::tempdir().unwrap();
+		let db = db::open(dir.path()).unwrap();
+		let in_flight = HashSet::new();
+
+		enqueue(&db, ttl(), "1", None, b"{}".to_vec()).unwrap();
+		let due = take_due_events(&db, ttl(), Utc::now(), &in_flight).unwrap();
+
+		// Replayed while the attempt was in flight: the replay wins
+		assert!(replay(&db, "1").unwrap());
+		finish_attempt(&db, &due[0], Ok(true)).unwrap();
+		let event = db::get_event(&db, "1").unwrap().unwrap();
+		assert_eq!(event.status, EventStatus::Pending);
+		assert_eq!(event.attempts, 0);
+
+		assert!(!replay(&db, "2").unwrap());
+	}
+
+	#[test]
+	fn test_duplicate_deliveries_are_skipped() {
+		let dir = tempfile::tempdir().unwrap();
+		let db = db::open(dir.path()).unwrap();
+		let in_flight = HashSet::new();
+
+		assert!(enqueue(&db, ttl(), "1", None, b"{}".to_vec()).unwrap());
+		assert!(!enqueue(&db, ttl(), "1", None, b"{}".to_vec()).unwrap());
+
+		let now = Utc::now();
+		let due = take_due_events(&db, ttl(), now, &in_flight).unwrap();
+		finish_attempt(&db, &due[0], Ok(true)).unwrap();
+		assert!(!enqueue(&db, ttl(), "1", None, b"{}".to_vec()).unwrap());
+
+		// Expired events are forgotten
+		let later = now + ttl() + Duration::seconds(1);
+		assert!(take_due_events(&db, ttl(), later, &in_flight)
+			.unwrap()
+			.is_empty());
+		assert!(db::get_event(&db, "1").unwrap().is_none());
+		assert!(enqueue(&db, ttl(), "1", None, b"{}".to_vec()).unwrap());
+	}
+
+	#[test]
+	fn test_delivery_id() {
+		assert_eq!(delivery_id(Some("abc"), b"{}"), "abc");
+		assert_eq!(
+			delivery_id(None, b"{}"),
+			"44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+		);
+	}
+}
@@ -16,6 +16,7 @@ pub struct PullRequest {
 	pub url: String,
 	pub html_url: String,
 	pub number: i64,
+	pub title: String,
 	pub user: Option<User>,
 	pub body: Option<String>,
 	pub head: Head,
@@ -28,6 +29,7 @@ pub struct PullRequest {
 impl PullRequest {
 	pub fn parse_all_companions(
 		&self,
+		github_url: &str,
 		companion_reference_trail: &[CompanionReferenceTrailItem],
 	) -> Option<Vec<IssueDetailsWithRepositoryURL>> {
 		let mut next_trail =
@@ -39,7 +41,7 @@ impl PullRequest {
 		});
 		self.body
 			.as_ref()
-			.map(|body| parse_all_companions(&next_trail, body))
+			.map(|body| parse_all_companions(github_url, &next_trail, body))
 	}
 }
 
@@ -173,6 +175,16 @@ pub enum StatusState {
 	Unknown,
 }
 
+// https://docs.github.com/en/rest/reference/pulls#merge-a-pull-request
+#[derive(Default, Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeMethod {
+	Merge,
+	#[default]
+	Squash,
+	Rebase,
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct InstallationRepositories {
 	pub repositories: Vec<Repository>,
@@ -213,6 +225,14 @@ pub enum IssueCommentAction {
 	Unknown,
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PullRequestAction {
+	Labeled,
+	#[serde(other)]
+	Unknown,
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CheckRuns {
 	pub check_runs: Vec<CheckRun>,
@@ -289,12 +309,17 @@ pub enum Payload {
 		issue: WebhookIssueComment,
 		comment: Comment,
 	},
+	PullRequest {
+		action: PullRequestAction,
+		pull_request: Box<PullRequest>,
+		label: Option<Label>,
+		sender: User,
+	},
 	CommitStatus {
-		// FIXME: This payload also has a field `repository` for the repository where the status
-		// originated from which should be used *together* with commit SHA for indexing pull requests.
-		// Currently, because merge requests are indexed purely by their head SHA into the database,
-		// there's no way to disambiguate between two different PRs in two different repositories with
-		// the same head SHA.
+		// Note: This payload also has a field `repository` for the repository where the status
+		// originated from. It's not needed for finding the pull requests because the database's SHA
+		// index keeps track of all pull requests which have a given head SHA, even across different
+		// repositories (see db.rs).
 		sha: String,
 		state: StatusState,
 	},
@@ -306,6 +331,24 @@ pub enum Payload {
 	},
 }
 
+#[derive(Deserialize)]
+pub struct WebhookInstallationId {
+	pub id: i64,
+}
+
+#[derive(Deserialize)]
+pub struct WebhookInstallationRepository {
+	pub owner: User,
+}
+
+/// The installation a webhook payload was delivered for, which covers the
+/// account owning the payload's repository.
+#[derive(Deserialize)]
+pub struct WebhookInstallation {
+	pub installation: Option<WebhookInstallationId>,
+	pub repository: Option<WebhookInstallationRepository>,
+}
+
 #[derive(Deserialize)]
 struct DetectUserCommentPullRequestPullRequest {
 	pub html_url: Option<String>,
@@ -421,7 +464,7 @@ pub fn parse_issue_details_from_pr_html_url(
 pub fn parse_repository_full_name(full_name: &str) -> Option<(String, String)> {
 	let parts: Vec<&str> = full_name.split('/').collect();
 	parts
-		.get(0)
+		.first()
 		.and_then(|owner| {
 			parts.get(1).map(|repo_name| {
 				Some((owner.to_string(), repo_name.to_string()))
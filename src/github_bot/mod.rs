@@ -59,6 +59,19 @@ impl GithubBot {
 		self.client.get(url).await
 	}
 
+	pub async fn branch_ref(
+		&self,
+		owner: &str,
+		repo: &str,
+		branch: &str,
+	) -> Result<Ref> {
+		let url = &format!(
+			"{}/repos/{}/{}/git/ref/heads/{}",
+			self.github_api_url, owner, repo, branch
+		);
+		self.client.get(url).await
+	}
+
 	pub async fn org_member(&self, org: &str, username: &str) -> Result<bool> {
 		let url = &format!(
 			"{}/orgs/{}/members/{}",
@@ -1,9 +1,11 @@
+use chrono::Utc;
+
 use crate::{
 	companion::CompanionReferenceTrailItem,
 	error::Error,
 	github::*,
 	github_bot::MainConfig,
-	webhook::{Dependency, MergeRequest},
+	webhook::{Dependency, MergeRequest, MergeState},
 	Result,
 };
 
@@ -45,15 +47,25 @@ impl GithubBot {
 		repo: &str,
 		number: i64,
 		head_sha: &str,
+		merge_method: MergeMethod,
+		commit_title: Option<&str>,
+		commit_message: Option<&str>,
 	) -> Result<()> {
 		let url = format!(
 			"{}/repos/{}/{}/pulls/{}/merge",
 			self.github_api_url, owner, repo, number
 		);
-		let params = serde_json::json!({
+		let mut params = serde_json::json!({
 			"sha": head_sha,
-			"merge_method": "squash"
+			"merge_method": merge_method,
 		});
+		// GitHub's defaults are used for the fields which are not provided
+		if let Some(commit_title) = commit_title {
+			params["commit_title"] = commit_title.into();
+		}
+		if let Some(commit_message) = commit_message {
+			params["commit_message"] = commit_message.into();
+		}
 		self.client.put_response(&url, &params).await.map(|_| ())
 	}
 
@@ -64,11 +76,12 @@ impl GithubBot {
 		requested_by: &str,
 		companion_reference_trail: &[CompanionReferenceTrailItem],
 	) -> Result<Option<Vec<MergeRequest>>, Error> {
-		let companions =
-			match pr.parse_all_companions(companion_reference_trail) {
-				Some(companions) => companions,
-				None => return Ok(None),
-			};
+		let companions = match pr
+			.parse_all_companions(&config.github_url, companion_reference_trail)
+		{
+			Some(companions) => companions,
+			None => return Ok(None),
+		};
 
 		let parent_dependency = Dependency {
 			sha: (&pr.head.sha).into(),
@@ -95,6 +108,11 @@ impl GithubBot {
 					html_url: comp_html_url.into(),
 					requested_by: requested_by.into(),
 					dependencies: Some(vec![parent_dependency]),
+					// Companions are merged with the method configured for their own
+					// repository
+					merge_method: None,
+					requested_at: Some(Utc::now()),
+					state: MergeState::Queued,
 				}]
 			} else {
 				let base_dependencies = vec![parent_dependency];
@@ -194,6 +212,9 @@ impl GithubBot {
 						html_url: comp_html_url.into(),
 						requested_by: requested_by.into(),
 						dependencies: Some(dependencies),
+						merge_method: None,
+						requested_at: Some(Utc::now()),
+						state: MergeState::Queued,
 					})
 				}
 
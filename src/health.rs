@@ -0,0 +1,225 @@
+use chrono::{DateTime, Duration, Utc};
+use serde_json::json;
+use tokio::time::timeout;
+
+use crate::{constants::MERGE_REQUESTS_POLL_INTERVAL, db, webhook::AppState};
+
+/*
+	/health (liveness) and /ready (readiness) check the following components and
+	report each one's status as JSON, responding with 503 if any of them failed:
+
+	- database: a record can be written, read back and deleted (see db::check)
+	- poll: the thread which polls for pending merge requests (see main.rs) is
+		alive, i.e. it has started an iteration or made progress within the
+		last two intervals. An iteration might take longer than that, e.g. while
+		it's merging a pull request, so the heartbeat is recorded after each
+		pull request it processes rather than once the iteration succeeds.
+		The time of the latest successful iteration is reported alongside.
+	- github (readiness only): an installation token can be obtained (see
+		http::Client::auth_key), which fails e.g. if the private key is invalid
+
+	GitHub is only checked for readiness since restarting processbot would not
+	help if GitHub is unreachable.
+*/
+
+const GITHUB_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+lazy_static::lazy_static! {
+	static ref STARTED_AT: DateTime<Utc> = Utc::now();
+	static ref LAST_POLL_HEARTBEAT: parking_lot::Mutex<Option<DateTime<Utc>>> = {
+		parking_lot::Mutex::new(None)
+	};
+	static ref LAST_POLL_SUCCESS: parking_lot::Mutex<Option<DateTime<Utc>>> = {
+		parking_lot::Mutex::new(None)
+	};
+}
+
+/// Mark the start of the process, from which the first poll is expected to
+/// start within the same time as the following ones.
+pub fn init() {
+	lazy_static::initialize(&STARTED_AT);
+}
+
+/// Record that the poll has started an iteration or is still progressing.
+pub fn record_poll_heartbeat() {
+	*LAST_POLL_HEARTBEAT.lock() = Some(Utc::now());
+}
+
+pub fn record_poll_success() {
+	let now = Utc::now();
+	*LAST_POLL_HEARTBEAT.lock() = Some(now);
+	*LAST_POLL_SUCCESS.lock() = Some(now);
+}
+
+type ComponentStatus = std::result::Result<serde_json::Value, String>;
+
+fn check_database(state: &AppState) -> ComponentStatus {
+	db::check(&state.db)
+		.map(|_| json!({}))
+		.map_err(|err| err.to_string())
+}
+
+fn check_poll(
+	last_heartbeat: Option<DateTime<Utc>>,
+	last_success: Option<DateTime<Utc>>,
+	started_at: DateTime<Utc>,
+	now: DateTime<Utc>,
+) -> ComponentStatus {
+	let max_age = Duration::from_std(MERGE_REQUESTS_POLL_INTERVAL * 2).unwrap();
+	let since = last_heartbeat.unwrap_or(started_at);
+	let last_success = last_success.map(|time| time.to_rfc3339());
+	if now - since > max_age {
+		Err(match last_heartbeat {
+			Some(last_heartbeat) => format!(
+				"The poll has not made progress since {} (last success: {})",
+				last_heartbeat.to_rfc3339(),
+				last_success.as_deref().unwrap_or("none")
+			),
+			None => format!(
+				"The poll has not started since the start at {}",
+				started_at.to_rfc3339()
+			),
+		})
+	} else {
+		Ok(json!({
+			"last_heartbeat": last_heartbeat.map(|time| time.to_rfc3339()),
+			"last_success": last_success,
+		}))
+	}
+}
+
+async fn check_github(state: &AppState) -> ComponentStatus {
+	match timeout(GITHUB_TIMEOUT, state.github_bot.client.auth_key()).await {
+		Ok(Ok(_)) => Ok(json!({})),
+		Ok(Err(err)) => Err(err.to_string()),
+		Err(_) => Err(format!(
+			"Timed out after {:?} while getting an installation token",
+			GITHUB_TIMEOUT
+		)),
+	}
+}
+
+/// Returns whether all components are healthy along with the report.
+fn report(
+	components: Vec<(&str, ComponentStatus)>,
+) -> (bool, serde_json::Value) {
+	let mut is_healthy = true;
+	let mut report = serde_json::Map::new();
+	for (name, status) in components {
+		let status = match status {
+			Ok(mut details) => {
+				details["status"] = json!("ok");
+				details
+			}
+			Err(err) => {
+				log::error!("Health check of {} failed: {}", name, err);
+				is_healthy = false;
+				json!({ "status": "error", "error": err })
+			}
+		};
+		report.insert(name.to_owned(), status);
+	}
+	(
+		is_healthy,
+		json!({
+			"status": if is_healthy { "ok" } else { "error" },
+			"components": report,
+		}),
+	)
+}
+
+fn check_last_poll() -> ComponentStatus {
+	let last_heartbeat = *LAST_POLL_HEARTBEAT.lock();
+	let last_success = *LAST_POLL_SUCCESS.lock();
+	check_poll(last_heartbeat, last_success, *STARTED_AT, Utc::now())
+}
+
+pub fn liveness(state: &AppState) -> (bool, serde_json::Value) {
+	report(vec![
+		("database", check_database(state)),
+		("poll", check_last_poll()),
+	])
+}
+
+pub async fn readiness(state: &AppState) -> (bool, serde_json::Value) {
+	let database = check_database(state);
+	let poll = check_last_poll();
+	let github = check_github(state).await;
+	report(vec![
+		("database", database),
+		("poll", poll),
+		("github", github),
+	])
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_check_poll() {
+		let started_at = Utc::now();
+		let interval =
+			Duration::from_std(MERGE_REQUESTS_POLL_INTERVAL).unwrap();
+
+		// The first poll is given as long as the following ones
+		assert!(
+			check_poll(None, None, started_at, started_at + interval).is_ok()
+		);
+		assert!(
+			check_poll(None, None, started_at, started_at + interval * 3)
+				.is_err()
+		);
+
+		let last_success = started_at + interval * 10;
+		assert_eq!(
+			check_poll(
+				Some(last_success),
+				Some(last_success),
+				started_at,
+				last_success + interval
+			),
+			Ok(json!({
+				"last_heartbeat": last_success.to_rfc3339(),
+				"last_success": last_success.to_rfc3339(),
+			}))
+		);
+		assert!(check_poll(
+			Some(last_success),
+			Some(last_success),
+			started_at,
+			last_success + interval * 3
+		)
+		.is_err());
+
+		// An iteration which is still progressing, e.g. merging a pull request,
+		// is alive even though it hasn't succeeded for a while
+		let last_heartbeat = last_success + interval * 3;
+		assert!(check_poll(
+			Some(last_heartbeat),
+			Some(last_success),
+			started_at,
+			last_heartbeat + interval
+		)
+		.is_ok());
+	}
+
+	#[test]
+	fn test_report() {
+		let (is_healthy, report) = super::report(vec![
+			("database", Ok(json!({}))),
+			("github", Err("Bad credentials".to_owned())),
+		]);
+		assert!(!is_healthy);
+		assert_eq!(
+			report,
+			json!({
+				"status": "error",
+				"components": {
+					"database": { "status": "ok" },
+					"github": { "status": "error", "error": "Bad credentials" },
+				},
+			})
+		);
+	}
+}
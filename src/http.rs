@@ -1,24 +1,276 @@
 use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::SystemTime;
 
 use crate::{
 	config::MainConfig,
+	constants::{GITHUB_RATE_LIMIT_MAX_RETRIES, GITHUB_RATE_LIMIT_RESERVE},
 	error::{self, Error},
-	github, Result,
+	github, http_cache, metrics, Result,
 };
 
-use chrono::{DateTime, Duration, Utc};
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use hyperx::header::TypedHeaders;
-use reqwest::{header, IntoUrl, Method, RequestBuilder, Response};
+use reqwest::{
+	header::{self, HeaderMap},
+	IntoUrl, Method, RequestBuilder, Response, StatusCode,
+};
+use ring::rand::{SecureRandom, SystemRandom};
 use serde::Serialize;
 use snafu::ResultExt;
+use tokio::time::delay_for;
+
+/*
+	The GitHub API rate limits are accounted for per installation, since each
+	one has its own quota, as follows:
+	- The primary rate limit, as reported by the X-RateLimit-* headers of each
+		response, is tracked. Once fewer than GITHUB_RATE_LIMIT_RESERVE requests
+		remain, the requests are spread out evenly until the limit resets, one at a
+		time, so that a burst (e.g. resolving a big companion chain) can't exhaust
+		it.
+	- Requests rejected due to the primary rate limit are retried once it
+		resets; those rejected due to a secondary rate limit are retried after
+		Retry-After or, lacking it, with an exponential backoff starting at one
+		minute. Meanwhile the installation's other requests wait as well.
+
+	Requests which fail transiently (5xx responses, timeouts and connection
+	errors) are retried up to config.github_api_max_retries times with an
+	exponential backoff if they can be safely repeated (e.g. GET and PUT, but not
+	POST). If they still fail, Error::Transient is returned so that the merge is
+	not cancelled over it (see Error::stops_merge_attempt).
+
+	The app can be installed on several accounts (organizations or users), each
+	installation having its own tokens. Requests are authenticated with the token
+	of the installation on the account which owns the resource, i.e. the owner in
+	/repos/{owner}/.. or the organization in /orgs/{org}/.., falling back to
+	config.installation_login for other resources. The installation of each
+	account is taken from the webhook payloads (see register_installation) or
+	otherwise looked up among the app's installations.
+*/
+
+// The backoff for secondary rate limits without Retry-After, as recommended by
+// GitHub
+const SECONDARY_RATE_LIMIT_BACKOFF_SECONDS: i64 = 60;
+
+/// The GitHub API rate limit as of the latest response.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+	pub limit: i64,
+	pub remaining: i64,
+	pub reset: DateTime<Utc>,
+}
+
+impl RateLimit {
+	fn from_headers(headers: &HeaderMap) -> Option<Self> {
+		let get = |name: &str| -> Option<i64> {
+			headers.get(name)?.to_str().ok()?.parse().ok()
+		};
+		Some(Self {
+			limit: get("x-ratelimit-limit")?,
+			remaining: get("x-ratelimit-remaining")?,
+			reset: Utc.timestamp_opt(get("x-ratelimit-reset")?, 0).single()?,
+		})
+	}
+}
+
+#[derive(Default)]
+struct RateLimitState {
+	rate_limit: Option<RateLimit>,
+	// Set after hitting a rate limit; no requests are made until then
+	paused_until: Option<DateTime<Utc>>,
+}
+
+/// The rate limit state of each installation, keyed by installation ID like
+/// Client::tokens, since each installation has a quota of its own.
+#[derive(Default)]
+struct RateLimits {
+	states: parking_lot::Mutex<HashMap<i64, RateLimitState>>,
+	// Held while waiting on an installation's rate limit so that its requests
+	// are let through one at a time
+	throttles: parking_lot::Mutex<HashMap<i64, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl RateLimits {
+	fn rate_limit(&self, installation_id: i64) -> Option<RateLimit> {
+		self.states
+			.lock()
+			.get(&installation_id)
+			.and_then(|state| state.rate_limit)
+	}
+
+	fn throttle(&self, installation_id: i64) -> Arc<tokio::sync::Mutex<()>> {
+		self.throttles
+			.lock()
+			.entry(installation_id)
+			.or_default()
+			.clone()
+	}
+
+	fn delay(
+		&self,
+		installation_id: i64,
+		now: DateTime<Utc>,
+	) -> Option<Duration> {
+		throttle_delay(self.states.lock().get(&installation_id)?, now)
+	}
+
+	/// Account for a request until its response tells the actual count.
+	fn consume(&self, installation_id: i64) {
+		if let Some(rate_limit) = self
+			.states
+			.lock()
+			.get_mut(&installation_id)
+			.and_then(|state| state.rate_limit.as_mut())
+		{
+			rate_limit.remaining -= 1;
+		}
+	}
+
+	fn update(&self, installation_id: i64, rate_limit: RateLimit) {
+		self.states
+			.lock()
+			.entry(installation_id)
+			.or_default()
+			.rate_limit = Some(rate_limit);
+	}
+
+	fn pause(&self, installation_id: i64, until: DateTime<Utc>) {
+		let mut states = self.states.lock();
+		let state = states.entry(installation_id).or_default();
+		state.paused_until = state.paused_until.max(Some(until));
+	}
+}
+
+/// How long to wait before making a request, if at all.
+fn throttle_delay(
+	state: &RateLimitState,
+	now: DateTime<Utc>,
+) -> Option<Duration> {
+	if let Some(paused_until) = state.paused_until.filter(|t| *t > now) {
+		return Some(paused_until - now);
+	}
+	let rate_limit = state.rate_limit.filter(|limit| limit.reset > now)?;
+	if rate_limit.remaining <= 0 {
+		Some(rate_limit.reset - now)
+	} else if rate_limit.remaining < GITHUB_RATE_LIMIT_RESERVE {
+		Some((rate_limit.reset - now) / (rate_limit.remaining as i32 + 1))
+	} else {
+		None
+	}
+}
+
+#[derive(Debug, PartialEq)]
+enum RateLimited {
+	Primary { reset: DateTime<Utc> },
+	Secondary { retry_after: Duration },
+}
+
+/// Tell whether a failed response was due to a rate limit and when the request
+/// can be retried. `attempt` starts at 0.
+fn rate_limited(
+	status: StatusCode,
+	headers: &HeaderMap,
+	body: &str,
+	attempt: u32,
+) -> Option<RateLimited> {
+	if status != StatusCode::FORBIDDEN
+		&& status != StatusCode::TOO_MANY_REQUESTS
+	{
+		return None;
+	}
+
+	let retry_after = headers
+		.get(header::RETRY_AFTER)
+		.and_then(|value| value.to_str().ok())
+		.and_then(|value| value.parse::<i64>().ok())
+		.map(Duration::seconds);
+	if let Some(retry_after) = retry_after {
+		return Some(RateLimited::Secondary { retry_after });
+	}
+
+	if let Some(rate_limit) = RateLimit::from_headers(headers) {
+		if rate_limit.remaining == 0 {
+			return Some(RateLimited::Primary {
+				reset: rate_limit.reset,
+			});
+		}
+	}
+
+	let body = body.to_lowercase();
+	if status == StatusCode::TOO_MANY_REQUESTS
+		|| body.contains("secondary rate limit")
+		|| body.contains("abuse detection")
+	{
+		return Some(RateLimited::Secondary {
+			retry_after: Duration::seconds(
+				SECONDARY_RATE_LIMIT_BACKOFF_SECONDS << attempt.min(10),
+			),
+		});
+	}
+
+	None
+}
+
+/// Whether a request which failed without a response might succeed if it's
+/// made again, e.g. after timing out or the connection being reset.
+fn is_transient(err: &reqwest::Error) -> bool {
+	err.is_timeout() || err.is_connect() || err.is_request()
+}
+
+/// The delay before retrying after `attempt` (starting at 0) transient
+/// failures: `base_delay` doubled on each attempt, from which up to half is
+/// taken off according to `jitter` (between 0 and 1) so that the requests which
+/// failed at the same time are not retried at the same time.
+fn retry_delay(
+	base_delay: std::time::Duration,
+	attempt: u32,
+	jitter: f64,
+) -> std::time::Duration {
+	let delay = base_delay * 2u32.saturating_pow(attempt.min(16));
+	delay.mul_f64(1.0 - jitter / 2.0)
+}
+
+fn jitter() -> f64 {
+	let mut bytes = [0; 4];
+	if SystemRandom::new().fill(&mut bytes).is_err() {
+		return 0.0;
+	}
+	f64::from(u32::from_le_bytes(bytes)) / f64::from(u32::MAX)
+}
+
+/// The account whose installation should authenticate a request to `url`, if
+/// the resource belongs to one.
+fn installation_account<'a>(
+	github_api_url: &str,
+	url: &'a reqwest::Url,
+) -> Option<&'a str> {
+	let path = url.as_str().strip_prefix(github_api_url)?;
+	let path = path.split(&['?', '#'][..]).next()?;
+	let mut segments = path.split('/').filter(|segment| !segment.is_empty());
+	match (segments.next(), segments.next()) {
+		(Some("repos"), Some(account)) | (Some("orgs"), Some(account)) => {
+			Some(account)
+		}
+		_ => None,
+	}
+}
 
 pub struct Client {
 	pub client: reqwest::Client,
 	private_key: Vec<u8>,
+	// The account whose installation is used when a request is not specific to
+	// an account
 	installation_login: String,
+	// Installation IDs by account login, lowercased
+	installations: parking_lot::Mutex<HashMap<String, i64>>,
+	// Installation tokens by installation ID, along with their expiry
+	tokens: parking_lot::Mutex<HashMap<i64, (DateTime<Utc>, String)>>,
 	github_app_id: usize,
 	github_api_url: String,
+	max_retries: u32,
+	retry_base_delay: std::time::Duration,
+	rate_limits: RateLimits,
 }
 
 macro_rules! impl_methods_with_body {
@@ -46,62 +298,111 @@ macro_rules! impl_methods_with_body {
 				I: Into<Cow<'b, str>> + Clone,
 				B: Serialize + Clone,
 			{
-				// retry up to 5 times if request times out
-				let mut retries = 0;
-				'retry: loop {
-					let res = self.execute(
-						self.client
-						.$method(&*url.clone().into())
-						.json(&body.clone()),
-					)
-					.await;
-					// retry if timeout
-					if let Err(error::Error::Http { source: e, .. }) = res.as_ref() {
-						if e.is_timeout() && retries < 5 {
-							log::debug!("Request timed out; retrying");
-							retries += 1;
-							continue 'retry;
-						}
-					}
-					return res;
-				}
+				self.execute(
+					self.client
+					.$method(&*url.into())
+					.json(body),
+				)
+				.await
 			}
 
 		)*
 	}
 }
 
+fn response_error(status: StatusCode, text: String) -> Error {
+	// Try to decode the response error as JSON otherwise store
+	// it as plain text in a JSON object.
+	let body =
+		if let Ok(value) = serde_json::from_str(&text).context(error::Json) {
+			value
+		} else {
+			serde_json::json!({ "error_message": text })
+		};
+
+	Error::Response { status, body }
+}
+
 async fn handle_response(response: Response) -> Result<Response> {
 	log::debug!("response: {:?}", &response);
 
 	let status = response.status();
-	if status.is_success() {
+	// Only received for conditional requests (see Client::get)
+	if status.is_success() || status == StatusCode::NOT_MODIFIED {
 		Ok(response)
 	} else {
 		let text = response.text().await.context(error::Http)?;
-
-		// Try to decode the response error as JSON otherwise store
-		// it as plain text in a JSON object.
-		let body = if let Ok(value) =
-			serde_json::from_str(&text).context(error::Json)
-		{
-			value
-		} else {
-			serde_json::json!({ "error_message": text })
-		};
-
-		error::Response { status, body }.fail()
+		Err(response_error(status, text))
 	}
 }
 
+fn record_response(method: &Method, response: Option<&Response>) {
+	metrics::record_github_api_request(
+		method.as_str(),
+		response.map(|response| response.status().as_u16()),
+	);
+}
+
 impl Client {
 	pub fn new(config: &MainConfig) -> Self {
 		Self {
 			private_key: config.private_key.clone(),
 			installation_login: config.installation_login.clone(),
+			installations: parking_lot::Mutex::new(HashMap::new()),
+			tokens: parking_lot::Mutex::new(HashMap::new()),
 			github_app_id: config.github_app_id,
 			github_api_url: config.github_api_url.clone(),
 			client: reqwest::Client::default(),
+			max_retries: config.github_api_max_retries,
+			retry_base_delay: std::time::Duration::from_millis(
+				config.github_api_retry_base_delay_ms,
+			),
+			rate_limits: RateLimits::default(),
+		}
+	}
+
+	/// The GitHub API rate limit of the installation on `account` as of the
+	/// latest response, if any.
+	pub async fn rate_limit(&self, account: &str) -> Result<Option<RateLimit>> {
+		let installation_id = self.installation_id(account).await?;
+		Ok(self.rate_limits.rate_limit(installation_id))
+	}
+
+	/// Wait until a request can be made without exhausting the rate limit of
+	/// the installation on `account`.
+	async fn wait_for_rate_limit(&self, account: &str, installation_id: i64) {
+		let throttle = self.rate_limits.throttle(installation_id);
+		let _throttle = throttle.lock().await;
+
+		let delay = self.rate_limits.delay(installation_id, Utc::now());
+		if let Some(delay) = delay {
+			log::warn!(
+				"Delaying a GitHub API request for {} by {}s due to the rate limit ({:?})",
+				account,
+				delay.num_seconds(),
+				self.rate_limits.rate_limit(installation_id)
+			);
+			metrics::record_github_rate_limited("throttled");
+			delay_for(delay.to_std().unwrap_or_default()).await;
+		}
+
+		self.rate_limits.consume(installation_id);
+	}
+
+	fn update_rate_limit(
+		&self,
+		account: &str,
+		installation_id: i64,
+		headers: &HeaderMap,
+	) {
+		if let Some(rate_limit) = RateLimit::from_headers(headers) {
+			metrics::record_github_rate_limit(
+				account,
+				rate_limit.limit,
+				rate_limit.remaining,
+				rate_limit.reset.timestamp(),
+			);
+			self.rate_limits.update(installation_id, rate_limit);
 		}
 	}
 
@@ -120,21 +421,60 @@ impl Client {
 		self.client.request(method, url)
 	}
 
-	pub async fn auth_key(&self) -> Result<String> {
-		log::debug!("auth_key");
-		lazy_static::lazy_static! {
-			static ref TOKEN_CACHE: parking_lot::Mutex<Option<(DateTime<Utc>, String)>> = {
-				parking_lot::Mutex::new(None)
-			};
+	/// Record the installation which covers `account`, as given by a webhook
+	/// payload.
+	pub fn register_installation(&self, account: &str, installation_id: i64) {
+		self.installations
+			.lock()
+			.insert(account.to_lowercase(), installation_id);
+	}
+
+	async fn installation_id(&self, account: &str) -> Result<i64> {
+		let account = account.to_lowercase();
+		let known = self.installations.lock().get(&account).copied();
+		if let Some(installation_id) = known {
+			return Ok(installation_id);
 		}
 
+		let installations: Vec<github::Installation> = self
+			.jwt_get(&format!(
+				"{}/app/installations?per_page=100",
+				self.github_api_url
+			))
+			.await?;
+		let mut known = self.installations.lock();
+		for installation in installations {
+			known.insert(
+				installation.account.login.to_lowercase(),
+				installation.id,
+			);
+		}
+		known.get(&account).copied().ok_or_else(|| Error::Message {
+			msg: format!(
+				"Installation for login {} could not be found",
+				account
+			),
+		})
+	}
+
+	/// An installation token for config.installation_login.
+	pub async fn auth_key(&self) -> Result<String> {
+		self.auth_key_for(&self.installation_login).await
+	}
+
+	/// An installation token for the installation on `account`, which should be
+	/// the owner of the repositories the token is used for.
+	pub async fn auth_key_for(&self, account: &str) -> Result<String> {
+		log::debug!("auth_key_for {}", account);
+		let installation_id = self.installation_id(account).await?;
+
 		// Add some padding for avoiding token use just as it's about to expire
 		let installation_lease_with_padding =
 			Utc::now() + Duration::minutes(10);
 		let token = {
-			TOKEN_CACHE
+			self.tokens
 				.lock()
-				.as_ref()
+				.get(&installation_id)
 				// Ensure token is not expired if set.
 				.filter(|(time, _)| time > &installation_lease_with_padding)
 				.map(|(_, token)| token.clone())
@@ -144,29 +484,11 @@ impl Client {
 			return Ok(token);
 		}
 
-		let installations: Vec<github::Installation> = self
-			.jwt_get(&format!("{}/app/installations", self.github_api_url,))
-			.await?;
-
-		let installation = if let Some(installation) = installations
-			.iter()
-			.find(|inst| inst.account.login == self.installation_login)
-		{
-			installation
-		} else {
-			return Err(Error::Message {
-				msg: format!(
-					"Installation for login {} could not be found",
-					self.installation_login
-				),
-			});
-		};
-
 		let install_token: github::InstallationToken = self
 			.jwt_post(
 				&format!(
 					"{}/app/installations/{}/access_tokens",
-					self.github_api_url, installation.id
+					self.github_api_url, installation_id
 				),
 				&serde_json::json!({}),
 			)
@@ -178,14 +500,15 @@ impl Client {
 			.map_or(default_exp, |t| t.parse().unwrap_or(default_exp));
 		let token = install_token.token;
 
-		*TOKEN_CACHE.lock() = Some((expiry, token.clone()));
+		self.tokens
+			.lock()
+			.insert(installation_id, (expiry, token.clone()));
 
 		Ok(token)
 	}
 
 	async fn execute(&self, builder: RequestBuilder) -> Result<Response> {
-		let request = builder
-			.bearer_auth(&self.auth_key().await?)
+		let mut request = builder
 			.header(
 				header::ACCEPT,
 				"application/vnd.github.starfox-preview+json",
@@ -206,12 +529,136 @@ impl Client {
 			.timeout(std::time::Duration::from_secs(10))
 			.build()
 			.context(error::Http)?;
+		let account = installation_account(&self.github_api_url, request.url())
+			.unwrap_or(&self.installation_login)
+			.to_lowercase();
+		let token = self.auth_key_for(&account).await?;
+		// Each installation has its own rate limit
+		let installation_id = self.installation_id(&account).await?;
+		let mut authorization: header::HeaderValue =
+			format!("Bearer {}", token).parse().map_err(|_| {
+				Error::Message {
+					msg: format!("Invalid installation token for {}", account),
+				}
+			})?;
+		authorization.set_sensitive(true);
+		request
+			.headers_mut()
+			.insert(header::AUTHORIZATION, authorization);
 
 		log::debug!("request: {:?}", &request);
-		handle_response(
-			self.client.execute(request).await.context(error::Http)?,
-		)
-		.await
+		let method = request.method().clone();
+		let url = request.url().clone();
+		let mut request = Some(request);
+		let mut rate_limited_attempts = 0;
+		let mut transient_attempts = 0;
+		loop {
+			let current_request =
+				request.take().expect("request should be set");
+			// The request can't be retried if its body can't be cloned, which is
+			// never the case for the JSON bodies
+			request = current_request.try_clone();
+
+			// Changes invalidate the memoized responses (see http_cache.rs), also
+			// afterwards since the responses received meanwhile might predate them
+			let is_change = method != Method::GET;
+			if is_change {
+				http_cache::invalidate_memos();
+			}
+			self.wait_for_rate_limit(&account, installation_id).await;
+			let response = self.client.execute(current_request).await;
+			if is_change {
+				http_cache::invalidate_memos();
+			}
+			record_response(&method, response.as_ref().ok());
+			let err = match response {
+				Ok(response) => {
+					self.update_rate_limit(
+						&account,
+						installation_id,
+						response.headers(),
+					);
+					let status = response.status();
+					if !status.is_server_error()
+						&& status != StatusCode::FORBIDDEN
+						&& status != StatusCode::TOO_MANY_REQUESTS
+					{
+						return handle_response(response).await;
+					}
+
+					let headers = response.headers().clone();
+					let text = response.text().await.context(error::Http)?;
+					if status.is_server_error() {
+						response_error(status, text)
+					} else {
+						let retry_at = match rate_limited(
+							status,
+							&headers,
+							&text,
+							rate_limited_attempts,
+						) {
+							Some(RateLimited::Primary { reset }) => {
+								metrics::record_github_rate_limited("primary");
+								reset
+							}
+							Some(RateLimited::Secondary { retry_after }) => {
+								metrics::record_github_rate_limited(
+									"secondary",
+								);
+								Utc::now() + retry_after
+							}
+							None => return Err(response_error(status, text)),
+						};
+						if request.is_none()
+							|| rate_limited_attempts
+								>= GITHUB_RATE_LIMIT_MAX_RETRIES
+						{
+							return Err(response_error(status, text));
+						}
+
+						log::warn!(
+							"{} {} was rate limited (attempt {}); retrying at {}",
+							method,
+							url,
+							rate_limited_attempts + 1,
+							retry_at.to_rfc3339()
+						);
+						self.rate_limits.pause(installation_id, retry_at);
+						rate_limited_attempts += 1;
+						continue;
+					}
+				}
+				Err(err) if is_transient(&err) => Error::Http { source: err },
+				Err(err) => return Err(Error::Http { source: err }),
+			};
+
+			// Only the requests which can be safely repeated are retried
+			transient_attempts += 1;
+			if !method.is_idempotent()
+				|| request.is_none()
+				|| transient_attempts > self.max_retries
+			{
+				return Err(Error::Transient {
+					attempts: transient_attempts,
+					source: Box::new(err),
+				});
+			}
+			let delay = retry_delay(
+				self.retry_base_delay,
+				transient_attempts - 1,
+				jitter(),
+			);
+			log::warn!(
+				"{} {} failed due to {} (attempt {}); retrying in {:?}",
+				method,
+				url,
+				err,
+				transient_attempts,
+				delay
+			);
+			metrics::record_github_api_retry();
+			delay_for(delay).await;
+		}
 	}
 
 	fn create_jwt(&self) -> Result<String> {
@@ -285,19 +732,63 @@ impl Client {
 			.context(error::Http)
 	}
 
-	/// Get a single entry from a resource in GitHub.
+	/// Get a single entry from a resource in GitHub. The response is cached (see
+	/// http_cache.rs).
 	pub async fn get<'b, I, T>(&self, url: I) -> Result<T>
 	where
 		I: Into<Cow<'b, str>> + Clone,
 		T: serde::de::DeserializeOwned + core::fmt::Debug,
 	{
-		let res = self
-			.get_response(url, serde_json::json!({}))
-			.await?
-			.json::<T>()
-			.await
-			.context(error::Http);
-		res
+		let url = url.into();
+		let body = self.get_cached(&url).await?;
+		serde_json::from_slice(&body).context(error::Json)
+	}
+
+	async fn get_cached(&self, url: &str) -> Result<Vec<u8>> {
+		if let Some(body) = http_cache::memoized(url) {
+			metrics::record_github_api_cache("memoized");
+			return Ok(body);
+		}
+
+		let generation = http_cache::generation();
+		let etag = http_cache::etag(url);
+		let mut builder = self.client.get(url).json(&serde_json::json!({}));
+		if let Some(etag) = &etag {
+			builder = builder.header(header::IF_NONE_MATCH, etag);
+		}
+		let response = self.execute(builder).await?;
+
+		let cached = etag
+			.filter(|_| response.status() == StatusCode::NOT_MODIFIED)
+			.and_then(|etag| http_cache::cached(url, &etag));
+		let body = if let Some(body) = cached {
+			metrics::record_github_api_cache("not_modified");
+			body
+		} else if response.status() == StatusCode::NOT_MODIFIED {
+			// The cached response was evicted meanwhile
+			return self
+				.get_response(url, serde_json::json!({}))
+				.await?
+				.bytes()
+				.await
+				.map(|body| body.to_vec())
+				.context(error::Http);
+		} else {
+			metrics::record_github_api_cache("miss");
+			let etag = response
+				.headers()
+				.get(header::ETAG)
+				.and_then(|value| value.to_str().ok())
+				.map(str::to_owned);
+			let body = response.bytes().await.context(error::Http)?.to_vec();
+			if let Some(etag) = etag {
+				http_cache::cache(url, etag, &body);
+			}
+			body
+		};
+
+		http_cache::memoize(url, &body, generation);
+		Ok(body)
 	}
 
 	/// Get a disembodied entry from a resource in GitHub.
@@ -323,24 +814,8 @@ impl Client {
 		P: Serialize + Clone,
 	{
 		log::debug!("get_response");
-		// retry up to 5 times if request times out
-		let mut retries = 0;
-		'retry: loop {
-			let res = self
-				.execute(
-					self.client.get(&*url.clone().into()).json(&params.clone()),
-				)
-				.await;
-			// retry if timeout
-			if let Err(error::Error::Http { source: e, .. }) = res.as_ref() {
-				if e.is_timeout() && retries < 5 {
-					log::debug!("Request timed out; retrying");
-					retries += 1;
-					continue 'retry;
-				}
-			}
-			return res;
-		}
+		self.execute(self.client.get(&*url.into()).json(&params))
+			.await
 	}
 
 	// Originally adapted from:
@@ -367,7 +842,7 @@ impl Client {
 				.iter()
 				.flat_map(|v| v.values())
 				.find(|link| {
-					link.rel().map_or(false, |rel| {
+					link.rel().is_some_and(|rel| {
 						rel.contains(&hyperx::header::RelationType::Next)
 					})
 				})
@@ -383,3 +858,184 @@ impl Client {
 		Ok(entities)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use reqwest::header::HeaderValue;
+
+	fn rate_limit_headers(remaining: i64, reset: DateTime<Utc>) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		headers.insert("x-ratelimit-limit", HeaderValue::from_static("5000"));
+		headers.insert(
+			"x-ratelimit-remaining",
+			HeaderValue::from_str(&remaining.to_string()).unwrap(),
+		);
+		headers.insert(
+			"x-ratelimit-reset",
+			HeaderValue::from_str(&reset.timestamp().to_string()).unwrap(),
+		);
+		headers
+	}
+
+	#[test]
+	fn test_throttle_delay() {
+		let now = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+		let reset = now + Duration::minutes(10);
+		let state = |remaining| RateLimitState {
+			rate_limit: RateLimit::from_headers(&rate_limit_headers(
+				remaining, reset,
+			)),
+			paused_until: None,
+		};
+
+		assert_eq!(throttle_delay(&RateLimitState::default(), now), None);
+		assert_eq!(
+			throttle_delay(&state(GITHUB_RATE_LIMIT_RESERVE), now),
+			None
+		);
+		// The remaining requests are spread out until the reset
+		assert_eq!(throttle_delay(&state(99), now), Some(Duration::seconds(6)));
+		assert_eq!(throttle_delay(&state(0), now), Some(Duration::minutes(10)));
+		// The rate limit has been reset meanwhile
+		assert_eq!(throttle_delay(&state(0), reset), None);
+
+		let paused = RateLimitState {
+			paused_until: Some(now + Duration::seconds(30)),
+			..state(GITHUB_RATE_LIMIT_RESERVE)
+		};
+		assert_eq!(throttle_delay(&paused, now), Some(Duration::seconds(30)));
+	}
+
+	#[test]
+	fn test_rate_limits_are_kept_per_installation() {
+		let now = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+		let reset = now + Duration::minutes(10);
+		let rate_limit = |remaining| {
+			RateLimit::from_headers(&rate_limit_headers(remaining, reset))
+				.unwrap()
+		};
+
+		let rate_limits = RateLimits::default();
+		rate_limits.update(1, rate_limit(0));
+		rate_limits.update(2, rate_limit(4000));
+		// The exhausted quota of one installation doesn't hold back the other
+		assert_eq!(rate_limits.delay(1, now), Some(Duration::minutes(10)));
+		assert_eq!(rate_limits.delay(2, now), None);
+		assert_eq!(rate_limits.delay(3, now), None);
+
+		rate_limits.consume(2);
+		assert_eq!(rate_limits.rate_limit(2).unwrap().remaining, 3999);
+		assert_eq!(rate_limits.rate_limit(1).unwrap().remaining, 0);
+
+		rate_limits.pause(2, now + Duration::seconds(30));
+		assert_eq!(rate_limits.delay(2, now), Some(Duration::seconds(30)));
+		assert_eq!(rate_limits.delay(3, now), None);
+	}
+
+	#[test]
+	fn test_retry_delay() {
+		let base_delay = std::time::Duration::from_secs(1);
+		assert_eq!(retry_delay(base_delay, 0, 0.0), base_delay);
+		assert_eq!(
+			retry_delay(base_delay, 3, 0.0),
+			std::time::Duration::from_secs(8)
+		);
+		assert_eq!(
+			retry_delay(base_delay, 3, 1.0),
+			std::time::Duration::from_secs(4)
+		);
+		let jittered = retry_delay(base_delay, 1, jitter());
+		assert!(jittered >= base_delay && jittered <= base_delay * 2);
+	}
+
+	#[test]
+	fn test_transient_errors_do_not_stop_the_merge() {
+		let err = Error::Transient {
+			attempts: 4,
+			source: Box::new(Error::Response {
+				status: StatusCode::BAD_GATEWAY,
+				body: serde_json::json!({}),
+			}),
+		}
+		.map_issue(("paritytech".into(), "polkadot".into(), 2));
+		assert!(err.is_transient());
+		assert!(!err.stops_merge_attempt());
+	}
+
+	#[test]
+	fn test_installation_account() {
+		let account = |url: &str| {
+			installation_account(
+				"https://api.github.com",
+				&reqwest::Url::parse(url).unwrap(),
+			)
+			.map(str::to_owned)
+		};
+		assert_eq!(
+			account("https://api.github.com/repos/paritytech/polkadot/pulls/2")
+				.as_deref(),
+			Some("paritytech")
+		);
+		assert_eq!(
+			account("https://api.github.com/orgs/fork-org/members/foo?page=2")
+				.as_deref(),
+			Some("fork-org")
+		);
+		assert_eq!(account("https://api.github.com/users/foo"), None);
+		assert_eq!(account("https://api.github.com/repos"), None);
+	}
+
+	#[test]
+	fn test_rate_limited() {
+		let reset = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+
+		assert_eq!(
+			rate_limited(
+				StatusCode::FORBIDDEN,
+				&rate_limit_headers(0, reset),
+				"{\"message\":\"API rate limit exceeded\"}",
+				0
+			),
+			Some(RateLimited::Primary { reset })
+		);
+
+		let mut headers = rate_limit_headers(100, reset);
+		headers.insert(header::RETRY_AFTER, HeaderValue::from_static("30"));
+		assert_eq!(
+			rate_limited(StatusCode::FORBIDDEN, &headers, "", 0),
+			Some(RateLimited::Secondary {
+				retry_after: Duration::seconds(30)
+			})
+		);
+
+		let message =
+			"{\"message\":\"You have exceeded a secondary rate limit.\"}";
+		assert_eq!(
+			rate_limited(
+				StatusCode::FORBIDDEN,
+				&rate_limit_headers(100, reset),
+				message,
+				2
+			),
+			Some(RateLimited::Secondary {
+				retry_after: Duration::minutes(4)
+			})
+		);
+
+		// Lacking permissions is not retried
+		assert_eq!(
+			rate_limited(
+				StatusCode::FORBIDDEN,
+				&rate_limit_headers(100, reset),
+				"{\"message\":\"Resource not accessible by integration\"}",
+				0
+			),
+			None
+		);
+		assert_eq!(
+			rate_limited(StatusCode::NOT_FOUND, &HeaderMap::new(), message, 0),
+			None
+		);
+	}
+}
@@ -0,0 +1,184 @@
+use std::{
+	cell::RefCell,
+	collections::HashMap,
+	future::Future,
+	sync::atomic::{AtomicU64, Ordering},
+	time::Instant,
+};
+
+/*
+	The responses of the GETs made through http::Client::get are cached at two
+	levels:
+
+	- By ETag: the latest response of each URL is kept along with its ETag, which
+		is sent back as If-None-Match. GitHub responds with 304 Not Modified if the
+		resource didn't change, which does not count against the rate limit, and
+		the kept response is used.
+	- By event: while an event is processed (see with_event_memo), the responses
+		are memoized so that fetching the same pull request, statuses or checks
+		several times while handling it (e.g. in checks_and_status and then in
+		update_then_merge) only makes one request.
+
+	The memoized responses are discarded as soon as processbot changes anything
+	on GitHub (a request other than a GET, or a git push; see invalidate_memos
+	and changing),
+	including while processing another event, so that an event never acts on the
+	state from before its own or another event's changes.
+*/
+
+// How many URLs are kept in the ETag cache; the least recently used is evicted
+// when it's full
+const ETAG_CACHE_CAPACITY: usize = 1000;
+
+struct CachedResponse {
+	etag: String,
+	body: Vec<u8>,
+	last_used: Instant,
+}
+
+lazy_static::lazy_static! {
+	static ref ETAG_CACHE: parking_lot::Mutex<HashMap<String, CachedResponse>> = {
+		parking_lot::Mutex::new(HashMap::new())
+	};
+}
+
+// Incremented on every change, which invalidates all the memoized responses
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Default)]
+struct Memo {
+	generation: u64,
+	responses: HashMap<String, Vec<u8>>,
+}
+
+tokio::task_local! {
+	static EVENT_MEMO: RefCell<Memo>;
+}
+
+/// Run `f` with a memo of the GET responses, which should be an event's
+/// processing.
+pub async fn with_event_memo<F: Future>(f: F) -> F::Output {
+	let memo = Memo {
+		generation: GENERATION.load(Ordering::SeqCst),
+		..Memo::default()
+	};
+	EVENT_MEMO.scope(RefCell::new(memo), f).await
+}
+
+/// Should be called whenever processbot is about to change something on GitHub.
+pub fn invalidate_memos() {
+	GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Run `change`, which changes something on GitHub other than through the API,
+/// e.g. a git push. The memos are invalidated before it and also afterwards,
+/// since the responses received meanwhile might predate it.
+pub async fn changing<F: Future>(change: F) -> F::Output {
+	invalidate_memos();
+	let output = change.await;
+	invalidate_memos();
+	output
+}
+
+pub fn memoized(url: &str) -> Option<Vec<u8>> {
+	EVENT_MEMO
+		.try_with(|memo| {
+			let mut memo = memo.borrow_mut();
+			let generation = GENERATION.load(Ordering::SeqCst);
+			if memo.generation != generation {
+				memo.generation = generation;
+				memo.responses.clear();
+				return None;
+			}
+			memo.responses.get(url).cloned()
+		})
+		.ok()
+		.flatten()
+}
+
+/// `generation` should be the value of generation() from before the request
+/// was made, so that a response which might predate a change is not memoized.
+pub fn memoize(url: &str, body: &[u8], generation: u64) {
+	let _ = EVENT_MEMO.try_with(|memo| {
+		let mut memo = memo.borrow_mut();
+		if memo.generation == generation {
+			memo.responses.insert(url.to_owned(), body.to_vec());
+		}
+	});
+}
+
+pub fn generation() -> u64 {
+	GENERATION.load(Ordering::SeqCst)
+}
+
+/// The ETag of the cached response of `url`, if any.
+pub fn etag(url: &str) -> Option<String> {
+	ETAG_CACHE.lock().get(url).map(|cached| cached.etag.clone())
+}
+
+/// The cached response of `url` if its ETag still matches, i.e. GitHub
+/// responded with 304 Not Modified to the request made with it.
+pub fn cached(url: &str, etag: &str) -> Option<Vec<u8>> {
+	let mut cache = ETAG_CACHE.lock();
+	let cached = cache.get_mut(url).filter(|cached| cached.etag == etag)?;
+	cached.last_used = Instant::now();
+	Some(cached.body.clone())
+}
+
+pub fn cache(url: &str, etag: String, body: &[u8]) {
+	let mut cache = ETAG_CACHE.lock();
+	if cache.len() >= ETAG_CACHE_CAPACITY && !cache.contains_key(url) {
+		let least_recently_used = cache
+			.iter()
+			.min_by_key(|(_, cached)| cached.last_used)
+			.map(|(url, _)| url.clone());
+		if let Some(url) = least_recently_used {
+			cache.remove(&url);
+		}
+	}
+	cache.insert(
+		url.to_owned(),
+		CachedResponse {
+			etag,
+			body: body.to_vec(),
+			last_used: Instant::now(),
+		},
+	);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_event_memo() {
+		let url = "https://api.github.com/repos/paritytech/polkadot/pulls/2";
+
+		// Nothing is memoized outside of an event
+		memoize(url, b"{}", generation());
+		assert_eq!(memoized(url), None);
+
+		with_event_memo(async {
+			memoize(url, b"{}", generation());
+			assert_eq!(memoized(url), Some(b"{}".to_vec()));
+
+			// A response which was requested before a change is not memoized
+			let before_change = generation();
+			invalidate_memos();
+			assert_eq!(memoized(url), None);
+			memoize(url, b"{}", before_change);
+			assert_eq!(memoized(url), None);
+		})
+		.await;
+	}
+
+	#[test]
+	fn test_etag_cache() {
+		let url = "https://api.github.com/repos/paritytech/substrate/pulls/1";
+		cache(url, "\"abc\"".to_owned(), b"[]");
+
+		assert_eq!(etag(url).as_deref(), Some("\"abc\""));
+		assert_eq!(cached(url, "\"abc\""), Some(b"[]".to_vec()));
+		assert_eq!(cached(url, "\"def\""), None);
+	}
+}
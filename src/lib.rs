@@ -1,22 +1,36 @@
 #![forbid(unsafe_code)]
-#![allow(clippy::blocks_in_if_conditions)]
 #![allow(clippy::too_many_arguments)]
 
 use serde::{Deserialize, Serialize};
 
+pub mod admin;
 pub mod cmd;
 mod macros;
 #[macro_use]
 pub mod companion;
 pub mod config;
 pub mod constants;
+pub mod dashboard;
+pub mod db;
 pub mod error;
+pub mod event_queue;
 #[macro_use]
 pub mod github;
 pub mod github_bot;
+pub mod health;
 pub mod http;
+pub mod http_cache;
+pub mod lock;
+pub mod merge_batch;
+pub mod merge_queue;
+pub mod merge_state;
+pub mod metrics;
 pub mod rebase;
+pub mod repository_config;
 pub mod server;
+pub mod shutdown;
+#[cfg(test)]
+mod test_utils;
 pub mod utils;
 pub mod vanity_service;
 pub mod webhook;
@@ -30,16 +44,35 @@ pub enum Status {
 	Failure,
 }
 
+// The merge method, if any, overrides the one configured for the repository
 #[derive(Debug)]
 pub enum MergeCommentCommand {
-	Normal,
-	Force,
+	Normal(Option<github::MergeMethod>),
+	Priority(Option<github::MergeMethod>),
+	Force(Option<github::MergeMethod>),
+}
+
+impl MergeCommentCommand {
+	pub fn merge_method(&self) -> Option<github::MergeMethod> {
+		match self {
+			Self::Normal(merge_method)
+			| Self::Priority(merge_method)
+			| Self::Force(merge_method) => *merge_method,
+		}
+	}
+}
+#[derive(Debug)]
+pub enum QueueCommentCommand {
+	Show,
+	Position,
 }
 #[derive(Debug)]
 pub enum CommentCommand {
 	Merge(MergeCommentCommand),
 	CancelMerge,
 	Rebase,
+	Update,
+	Queue(QueueCommentCommand),
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
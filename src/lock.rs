@@ -0,0 +1,218 @@
+use std::{
+	collections::{HashMap, HashSet},
+	future::Future,
+	sync::{Arc, Weak},
+};
+use tokio::sync::Mutex;
+
+/*
+	Events are processed concurrently, thus everything which is done on behalf of
+	a pull request happens while holding its lock (see pull_request_key). This
+	serializes e.g. duplicate status hooks for the same pull request while
+	letting unrelated pull requests be processed in parallel.
+
+	Locks are held for the whole duration of the future given to with_locks and
+	are reentrant within a task, so a pull request's processing can go on to
+	process its dependents or the next entries of its merge queue without
+	deadlocking on itself. To avoid deadlocks between tasks, locks are only
+	acquired in the direction in which merges flow: a pull request before its
+	dependents, a merged pull request before the next head of its queue, and
+	a merge queue's lock before nothing else but the repository's clone.
+
+	A batch (see merge_batch.rs) might merge or cancel any of the pull requests
+	of its queue, so their locks are acquired together with the queue's. A task
+	which already holds a pull request's lock can't wait for the others since
+	another task might be doing the same for another pull request of the queue,
+	therefore it only takes them if they're free (see try_with_locks) and
+	otherwise leaves the queue to be processed later. Only tasks which hold no
+	lock wait for a queue's locks (e.g. checks_and_status after releasing the
+	pull request's lock, or merge_batch::check_batch), and since locks acquired
+	together are taken in the same order, none of them can wait on another.
+
+	The repository's clone (see clone_repository) is shared by all of its pull
+	requests, so it has its own lock (see clone_key) which is held while git
+	commands are run on it.
+*/
+
+lazy_static::lazy_static! {
+	static ref LOCKS: parking_lot::Mutex<HashMap<String, Weak<Mutex<()>>>> = {
+		parking_lot::Mutex::new(HashMap::new())
+	};
+}
+
+tokio::task_local! {
+	static HELD_LOCKS: HashSet<String>;
+}
+
+pub fn pull_request_key(owner: &str, repo: &str, number: i64) -> String {
+	format!("pr/{}/{}/{}", owner, repo, number)
+}
+
+pub fn merge_queue_key(owner: &str, repo: &str, base_branch: &str) -> String {
+	format!("queue/{}/{}/{}", owner, repo, base_branch)
+}
+
+pub fn clone_key(owner: &str, repo: &str) -> String {
+	format!("clone/{}/{}", owner, repo)
+}
+
+fn get_lock(key: &str) -> Arc<Mutex<()>> {
+	let mut locks = LOCKS.lock();
+	if let Some(lock) = locks.get(key).and_then(Weak::upgrade) {
+		return lock;
+	}
+	// Locks which are not held or waited on anymore are dropped along the way
+	locks.retain(|_, lock| lock.strong_count() > 0);
+	let lock = Arc::new(Mutex::new(()));
+	locks.insert(key.to_owned(), Arc::downgrade(&lock));
+	lock
+}
+
+/// Whether the current task holds any lock.
+pub fn holds_locks() -> bool {
+	HELD_LOCKS
+		.try_with(|held| !held.is_empty())
+		.unwrap_or(false)
+}
+
+/// Run `f` while holding the locks of `keys`, except for the ones which are
+/// already held by the current task.
+pub async fn with_locks<F: Future>(keys: Vec<String>, f: F) -> F::Output {
+	let mut held = HELD_LOCKS.try_with(|held| held.clone()).unwrap_or_default();
+	let keys = keys_to_acquire(keys, &held);
+
+	let mut guards = Vec::with_capacity(keys.len());
+	for key in &keys {
+		let lock = get_lock(key);
+		let guard = match lock.clone().try_lock_owned() {
+			Ok(guard) => guard,
+			Err(_) => {
+				log::info!("Waiting for lock {}", key);
+				lock.lock_owned().await
+			}
+		};
+		guards.push(guard);
+	}
+
+	held.extend(keys);
+	HELD_LOCKS.scope(held, f).await
+}
+
+/// Same as with_locks, except that `f` is not run if any of the locks is held by
+/// another task, in which case None is returned without waiting for it.
+pub async fn try_with_locks<F: Future>(
+	keys: Vec<String>,
+	f: F,
+) -> Option<F::Output> {
+	let mut held = HELD_LOCKS.try_with(|held| held.clone()).unwrap_or_default();
+	let keys = keys_to_acquire(keys, &held);
+
+	let mut guards = Vec::with_capacity(keys.len());
+	for key in &keys {
+		match get_lock(key).try_lock_owned() {
+			Ok(guard) => guards.push(guard),
+			Err(_) => {
+				log::info!("Lock {} is held by another task", key);
+				return None;
+			}
+		}
+	}
+
+	held.extend(keys);
+	Some(HELD_LOCKS.scope(held, f).await)
+}
+
+fn keys_to_acquire(keys: Vec<String>, held: &HashSet<String>) -> Vec<String> {
+	let mut keys = keys
+		.into_iter()
+		.filter(|key| !held.contains(key))
+		.collect::<Vec<_>>();
+	// Locks acquired together are always taken in the same order
+	keys.sort();
+	keys.dedup();
+	keys
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Duration;
+	use tokio::time::delay_for;
+
+	#[tokio::test]
+	async fn test_locks_are_reentrant() {
+		let key = pull_request_key("paritytech", "substrate", 1);
+		let output = with_locks(vec![key.clone()], async {
+			with_locks(vec![key.clone()], async { 1 }).await
+		})
+		.await;
+		assert_eq!(output, 1);
+	}
+
+	#[tokio::test]
+	async fn test_same_key_is_serialized() {
+		let key = pull_request_key("paritytech", "substrate", 2);
+		let events = Arc::new(parking_lot::Mutex::new(vec![]));
+
+		let first = {
+			let events = events.clone();
+			let key = key.clone();
+			tokio::spawn(with_locks(vec![key], async move {
+				events.lock().push("first start");
+				delay_for(Duration::from_millis(50)).await;
+				events.lock().push("first end");
+			}))
+		};
+		delay_for(Duration::from_millis(10)).await;
+		let second = {
+			let events = events.clone();
+			tokio::spawn(with_locks(vec![key], async move {
+				events.lock().push("second");
+			}))
+		};
+		// Other keys are not blocked
+		with_locks(
+			vec![pull_request_key("paritytech", "substrate", 3)],
+			async {
+				events.lock().push("other");
+			},
+		)
+		.await;
+
+		first.await.unwrap();
+		second.await.unwrap();
+		assert_eq!(
+			*events.lock(),
+			vec!["first start", "other", "first end", "second"]
+		);
+	}
+
+	#[tokio::test]
+	async fn test_try_with_locks_does_not_wait() {
+		let key = pull_request_key("paritytech", "substrate", 4);
+		let other_key = pull_request_key("paritytech", "substrate", 5);
+
+		let (started, start) = tokio::sync::oneshot::channel();
+		let (finish, finished) = tokio::sync::oneshot::channel::<()>();
+		let holder = tokio::spawn(with_locks(vec![key.clone()], async move {
+			started.send(()).unwrap();
+			finished.await.unwrap();
+		}));
+		start.await.unwrap();
+
+		assert_eq!(
+			try_with_locks(vec![other_key.clone(), key.clone()], async { 1 })
+				.await,
+			None
+		);
+		// Neither is the lock which was free left held
+		assert_eq!(
+			try_with_locks(vec![other_key.clone()], async { 2 }).await,
+			Some(2)
+		);
+
+		finish.send(()).unwrap();
+		holder.await.unwrap();
+		assert_eq!(try_with_locks(vec![key], async { 3 }).await, Some(3));
+	}
+}
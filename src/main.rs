@@ -1,19 +1,13 @@
-use rocksdb::DB;
-use std::fs;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::path::Path;
 use std::sync::Arc;
-use tokio::sync::Mutex;
 mod logging;
-use parity_processbot::{
-	error::Bincode, webhook::checks_and_status, MergeCancelOutcome,
-};
-use snafu::ResultExt;
+use parity_processbot::{webhook::checks_and_status, MergeCancelOutcome};
 use std::{thread, time::Duration};
 
 use parity_processbot::{
-	config::MainConfig, constants::*, github::Payload, github_bot, server,
-	webhook::*,
+	config::MainConfig, constants::MERGE_REQUESTS_POLL_INTERVAL, db,
+	event_queue, github_bot, health, merge_batch, merge_queue, merge_state,
+	server, shutdown, webhook::*,
 };
 
 fn main() -> anyhow::Result<()> {
@@ -21,6 +15,8 @@ fn main() -> anyhow::Result<()> {
 		.format(logging::gke::format)
 		.init();
 
+	health::init();
+
 	let config = MainConfig::from_env();
 
 	let socket = SocketAddr::new(
@@ -28,145 +24,241 @@ fn main() -> anyhow::Result<()> {
 		config.webhook_port.parse::<u16>().expect("webhook port"),
 	);
 
-	let db_version_path =
-		Path::new(&config.db_path).join("__PROCESSBOT_VERSION__");
-	let is_at_current_db_version = match db_version_path.exists() {
-		true => {
-			let str = fs::read_to_string(&db_version_path)?;
-			str == DATABASE_VERSION
-		}
-		false => false,
-	};
-	if !is_at_current_db_version {
-		log::info!(
-			"Clearing database to start from version {}",
-			DATABASE_VERSION
-		);
-		for entry in fs::read_dir(&config.db_path)? {
-			let entry = entry?;
-			if entry.path() == db_version_path {
-				continue;
-			}
-			if entry.metadata()?.is_dir() {
-				fs::remove_dir_all(entry.path())?;
-			} else {
-				fs::remove_file(entry.path())?;
-			}
-		}
-		fs::write(db_version_path, DATABASE_VERSION)?;
-	}
-
-	let db = DB::open_default(&config.db_path)?;
+	let db = db::open(&config.db_path)?;
 
 	let github_bot = github_bot::GithubBot::new(&config);
 
 	let webhook_proxy_url = config.webhook_proxy_url.clone();
 
-	let app_state = Arc::new(Mutex::new(AppState {
+	let app_state = Arc::new(AppState {
 		db,
 		github_bot,
 		config,
-	}));
+	});
+
+	let mut rt = tokio::runtime::Builder::new()
+		.threaded_scheduler()
+		.enable_all()
+		.build()?;
+
+	// Before anything else touches the pending merges
+	rt.block_on(merge_state::resume_interrupted(&app_state));
 
 	// Poll for pending merge requests
 	{
-		const DELAY: Duration = Duration::from_secs(30 * 60);
 		let state = app_state.clone();
 		let mut rt = tokio::runtime::Builder::new()
 			.threaded_scheduler()
 			.enable_all()
 			.build()?;
 		thread::spawn(move || loop {
-			log::info!("Acquiring poll lock");
-
 			rt.block_on(async {
-				let state = &*state.lock().await;
+				// Not started if a shutdown is under way, in which case this thread
+				// is blocked until the process exits
+				let _work = shutdown::start_work().await;
+				if shutdown::is_requested() {
+					return;
+				}
+				log::info!("Polling pending merge requests");
+				health::record_poll_heartbeat();
+
+				let state = &*state;
 
 				/*
-					Set up a loop for reinitializing the DB's iterator since the operations
-					performed in this loop might modify or delete multiple items from the
-					database, thus potentially making the iteration not work according to
-					expectations.
+					Collect the keys upfront and look each record up right before
+					processing it, since the operations performed in this loop might
+					modify or delete multiple items from the database.
+
+					Of the queued merge requests only the heads of the queues are
+					processed, since the others are waiting for their turn (see
+					merge_queue.rs), and the head of a batched queue processes the
+					whole queue. The merge requests which are not queued (e.g.
+					companions whose dependencies were merged) are only found by
+					going through all of the pending merge requests.
 				*/
-				let mut processed_mrs = vec![];
-				'db_iteration_loop: loop {
-					let db_iter =
-						state.db.iterator(rocksdb::IteratorMode::Start);
-					for (key, value) in db_iter {
-						match bincode::deserialize::<MergeRequest>(&value)
-							.context(Bincode)
-						{
-							Ok(mr) => {
-								if processed_mrs.iter().any(
-									|prev_mr: &MergeRequest| {
-										mr.owner == prev_mr.owner
-											&& mr.repo == prev_mr.repo && mr.number
-											== prev_mr.number
-									},
-								) {
-									continue;
-								}
-
-								// It's only worthwhile to try merging this MR if it has no pending
-								// dependencies
-								if mr
-									.dependencies
-									.as_ref()
-									.map(|vec| vec.is_empty())
-									.unwrap_or(true)
-								{
-									log::info!(
-										"Attempting to resume merge request processing during poll: {:?}",
-										mr
-									);
-
-									if let Err(err) =
-										checks_and_status(state, &mr.sha).await
-									{
-										let _ = cleanup_pr(
-											state,
-											&mr.sha,
-											&mr.owner,
-											&mr.repo,
-											mr.number,
-											&PullRequestCleanupReason::Error,
-										)
-										.await;
-										handle_error(
-											MergeCancelOutcome::WasCancelled,
-											err,
-											state,
-										)
-										.await;
-									}
-
-									processed_mrs.push(mr);
-									continue 'db_iteration_loop;
-								}
+				let queues = match db::get_merge_queues(&state.db) {
+					Ok(queues) => queues,
+					Err(err) => {
+						log::error!(
+							"Failed to fetch the merge queues from the database due to {:?}",
+							err
+						);
+						vec![]
+					}
+				};
+				let is_queued = |owner: &str, repo: &str, number| {
+					queues.iter().any(|queue| {
+						queue.owner == owner
+							&& queue.repo == repo && queue
+							.position(number)
+							.is_some()
+					})
+				};
+				let mut pending_mrs = queues
+					.iter()
+					.filter_map(|queue| {
+						queue.head().map(|head| {
+							(
+								queue.owner.clone(),
+								queue.repo.clone(),
+								head.number,
+							)
+						})
+					})
+					.collect::<Vec<_>>();
+				for (key, mr) in db::iter_merge_requests(&state.db) {
+					match mr {
+						Ok(mr) => {
+							if !is_queued(&mr.owner, &mr.repo, mr.number) {
+								pending_mrs.push((mr.owner, mr.repo, mr.number))
+							}
+						}
+						Err(err) => {
+							log::error!(
+								"Failed to deserialize key {} from the database due to {:?}",
+								String::from_utf8_lossy(&key),
+								err
+							);
+							let _ = state.db.delete(&key);
+						}
+					}
+				}
+
+				for (owner, repo, number) in pending_mrs {
+					// Processing a merge request might take a while, so progress is
+					// recorded for each of them (see health.rs)
+					health::record_poll_heartbeat();
+					let mr = match db::get_merge_request(
+						&state.db, &owner, &repo, number,
+					) {
+						Ok(Some(mr)) => mr,
+						Ok(None) => continue,
+						Err(err) => {
+							log::error!(
+								"Failed to fetch {}/{}/pull/{} from the database due to {:?}",
+								owner,
+								repo,
+								number,
+								err
+							);
+							continue;
+						}
+					};
+
+					// It's only worthwhile to try merging this MR if it has no pending
+					// dependencies
+					if !mr
+						.dependencies
+						.as_ref()
+						.map(|vec| vec.is_empty())
+						.unwrap_or(true)
+					{
+						continue;
+					}
+
+					log::info!(
+						"Attempting to resume merge request processing during poll: {:?}",
+						mr
+					);
+
+					if let Err(err) = checks_and_status(state, &mr.sha).await {
+						// Transient failures are attempted again on the next poll
+						if err.stops_merge_attempt() {
+							let _ = cleanup_pr(
+								state,
+								&mr.sha,
+								&mr.owner,
+								&mr.repo,
+								mr.number,
+								&PullRequestCleanupReason::Error,
+							)
+							.await;
+						}
+						handle_error(
+							MergeCancelOutcome::WasCancelled,
+							err,
+							state,
+						)
+						.await;
+					}
+				}
+
+				// Batches whose statuses were delivered while processbot was down
+				// would otherwise be stuck
+				match db::get_merge_batches(&state.db) {
+					Ok(batches) => {
+						for batch in batches {
+							health::record_poll_heartbeat();
+							if let Err(err) =
+								merge_batch::check_batch(state, &batch).await
+							{
+								log::error!(
+									"Failed to check batch {:?} due to {:?}",
+									batch,
+									err
+								);
+							}
+						}
+					}
+					Err(err) => {
+						log::error!(
+							"Failed to fetch the merge batches from the database due to {:?}",
+							err
+						);
+					}
+				}
+
+				// Queues whose head is no longer pending merge (e.g. processbot was
+				// stopped while advancing the queue) would otherwise be stuck
+				match db::get_merge_queues(&state.db) {
+					Ok(queues) => {
+						for queue in queues {
+							health::record_poll_heartbeat();
+							let is_stuck = match queue.head() {
+								Some(head) => matches!(
+									db::get_merge_request(
+										&state.db,
+										&queue.owner,
+										&queue.repo,
+										head.number
+									),
+									Ok(None)
+								),
+								None => false,
+							};
+							if !is_stuck {
+								continue;
 							}
-							Err(err) => {
+							if let Err(err) =
+								merge_queue::process_head(state, &queue).await
+							{
 								log::error!(
-									"Failed to deserialize key {} from the database due to {:?}",
-									String::from_utf8_lossy(&key),
+									"Failed to resume the merge queue of {}/{} ({}) due to {:?}",
+									queue.owner,
+									queue.repo,
+									queue.base_branch,
 									err
 								);
-								let _ = state.db.delete(&key);
 							}
 						}
 					}
-					break;
+					Err(err) => {
+						log::error!(
+							"Failed to fetch the merge queues from the database due to {:?}",
+							err
+						);
+					}
 				}
+
+				log::info!("Finished polling pending merge requests");
+				health::record_poll_success();
 			});
 
-			log::info!("Releasing poll lock");
-			thread::sleep(DELAY);
+			thread::sleep(MERGE_REQUESTS_POLL_INTERVAL);
 		});
 	}
 
-	let mut rt = tokio::runtime::Builder::new()
-		.threaded_scheduler()
-		.enable_all()
-		.build()?;
+	rt.spawn(event_queue::run_worker(app_state.clone()));
 
 	if let Some(webhook_proxy_url) = webhook_proxy_url {
 		use eventsource::reqwest::Client;
@@ -174,26 +266,41 @@ fn main() -> anyhow::Result<()> {
 
 		let client = Client::new(Url::parse(&webhook_proxy_url).unwrap());
 
+		// Smee forwards the delivery's headers alongside its body
 		#[derive(serde::Deserialize)]
 		struct SmeePayload {
-			body: Payload,
+			body: serde_json::Value,
+			#[serde(rename = "x-github-delivery")]
+			delivery_id: Option<String>,
+			#[serde(rename = "x-github-event")]
+			event_type: Option<String>,
 		}
-		for event in client {
-			let state = app_state.clone();
-			rt.block_on(async move {
+		let state = app_state.clone();
+		thread::spawn(move || {
+			for event in client {
 				let event = event.unwrap();
 
 				if let Ok(payload) =
 					serde_json::from_str::<SmeePayload>(event.data.as_str())
 				{
-					log::info!("Acquiring lock");
-					let state = &*state.lock().await;
-					let (merge_cancel_outcome, result) =
-						handle_payload(payload.body, state).await;
-					if let Err(err) = result {
-						handle_error(merge_cancel_outcome, err, state).await;
+					let body = payload.body.to_string().into_bytes();
+					let delivery_id = event_queue::delivery_id(
+						payload.delivery_id.as_deref(),
+						&body,
+					);
+					if let Err(err) = event_queue::enqueue(
+						&state.db,
+						event_queue::delivery_ttl(&state.config),
+						&delivery_id,
+						payload.event_type.as_deref(),
+						body,
+					) {
+						log::error!(
+							"Failed to store delivery {} due to {:?}",
+							delivery_id,
+							err
+						);
 					}
-					log::info!("Releasing lock");
 				} else {
 					match event.event_type.as_deref() {
 						Some("ping") => (),
@@ -201,11 +308,29 @@ fn main() -> anyhow::Result<()> {
 						_ => log::info!("Not parsed: {:?}", event),
 					}
 				}
-			});
-		}
+			}
+		});
+		rt.block_on(shutdown::signal_received());
 	} else {
-		rt.block_on(server::init(socket, app_state))?;
+		rt.block_on(server::init(
+			socket,
+			app_state.clone(),
+			shutdown::signal_received(),
+		))?;
+	}
+
+	let shutdown_timeout =
+		Duration::from_secs(app_state.config.shutdown_timeout_secs);
+	if rt.block_on(shutdown::finish_work(shutdown_timeout)) {
+		log::info!("The work in progress has finished");
+	} else {
+		log::warn!(
+			"Shutting down with work still in progress after {:?}",
+			shutdown_timeout
+		);
 	}
+	app_state.db.flush()?;
+	log::info!("Shut down");
 
 	Ok(())
 }
@@ -0,0 +1,820 @@
+use async_recursion::async_recursion;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	cmd::*,
+	companion::{clone_repository, set_owner_remote},
+	db,
+	error::*,
+	github::{MergeMethod, PullRequest},
+	http_cache, lock, merge_queue, metrics,
+	repository_config::{
+		fetch_repository_config, RepositoryConfig, REPOSITORY_CONFIG_PATH,
+	},
+	webhook::{
+		check_merge_is_allowed, cleanup_pr, get_latest_checks_state,
+		get_latest_statuses_state, handle_dependents_after_merge, handle_error,
+		ready_to_merge, AppState, MergeRequest, PullRequestCleanupReason,
+	},
+	MergeCancelOutcome, Result, Status,
+};
+
+/*
+	In repositories where batch merging is enabled the pull requests of a merge
+	queue are not merged one by one. Instead, up to a configured amount of ready
+	pull requests are merged together on top of the base branch into a candidate
+	commit which is pushed to the "processbot/batch/{base_branch}" branch. Once the
+	candidate's statuses and checks pass, the base branch is fast-forwarded to it,
+	which merges all of the batch's pull requests at once.
+
+	If the candidate fails, the batch is bisected and each half is tested
+	separately (the second half is kept in MergeBatch::pending) until the
+	culprit is isolated in a batch of its own, at which point its merge is
+	cancelled.
+
+	Only one batch is tested at a time for each queue. Its record prevents other
+	batches from being started for the queue while it's being tested, and the
+	queue's lock (see lock::merge_queue_key) serializes the processing of the
+	queue and of its batch. Processing them merges or cancels the queue's pull
+	requests, so they're locked as well; their locks are acquired along with the
+	queue's, thus before it (see lock.rs). Pull requests which are queued after
+	the locks were acquired are left for the next time the queue is processed.
+*/
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MergeBatch {
+	pub owner: String,
+	pub repo: String,
+	pub base_branch: String,
+	// The commit of the base branch which the candidate was built on top of
+	pub base_sha: String,
+	pub candidate_sha: String,
+	pub numbers: Vec<i64>,
+	// The groups of pull requests left to be tested after a failed batch was
+	// bisected
+	pub pending: Vec<Vec<i64>>,
+}
+
+/// The maximum size of the batches for a repository's base branch, if batch
+/// merging is enabled for it. The repository's configuration takes precedence
+/// over BATCH_MERGE_REPOSITORIES.
+pub async fn batch_size(
+	state: &AppState,
+	owner: &str,
+	repo: &str,
+	base_branch: &str,
+) -> Result<Option<usize>> {
+	let AppState {
+		github_bot, config, ..
+	} = state;
+
+	let repository_config =
+		fetch_repository_config(github_bot, owner, repo, base_branch).await?;
+	let batch_size = repository_config
+		.batch_size
+		.or_else(|| {
+			config
+				.batch_merge_repositories
+				.get(&format!("{}/{}", owner, repo))
+				.copied()
+		})
+		.filter(|batch_size| *batch_size > 0);
+
+	if batch_size.is_some() {
+		check_batchable(&repository_config).map_err(|msg| Error::Message {
+			msg: format!(
+				"Batch merging is enabled for {}/{}, but {}",
+				owner, repo, msg
+			),
+		})?;
+	}
+
+	Ok(batch_size)
+}
+
+/// Batches are merged by pushing merge commits to the base branch (see
+/// build_candidate and fast_forward), thus they can't follow a configuration
+/// which asks for something else. Returns why it can't.
+fn check_batchable(
+	repository_config: &RepositoryConfig,
+) -> std::result::Result<(), String> {
+	if let Some(merge_method) = repository_config
+		.merge_method
+		.filter(|merge_method| *merge_method != MergeMethod::Merge)
+	{
+		return Err(format!(
+			"{} sets merge_method = {:?} while batches are always merged as merge commits; set it to \"merge\" or disable batch merging.",
+			REPOSITORY_CONFIG_PATH,
+			format!("{:?}", merge_method).to_lowercase()
+		));
+	}
+	if repository_config.merge_commit_title.is_some()
+		|| repository_config.merge_commit_message.is_some()
+	{
+		return Err(format!(
+			"{} sets merge commit templates which don't apply to batches; remove them or disable batch merging.",
+			REPOSITORY_CONFIG_PATH
+		));
+	}
+	Ok(())
+}
+
+fn candidate_branch(base_branch: &str) -> String {
+	format!("processbot/batch/{}", base_branch)
+}
+
+/// Split the pull requests of a failed batch in two halves which will be tested
+/// separately, ahead of the groups which were already pending.
+fn bisect(numbers: &[i64], pending: &[Vec<i64>]) -> Vec<Vec<i64>> {
+	let (first_half, second_half) =
+		numbers.split_at(numbers.len() - numbers.len() / 2);
+	let mut groups = vec![first_half.to_vec(), second_half.to_vec()];
+	groups.extend_from_slice(pending);
+	groups
+}
+
+struct Candidate {
+	base_sha: String,
+	sha: String,
+	merged: Vec<MergeRequest>,
+	failed: Vec<(MergeRequest, Error)>,
+}
+
+/// Merge the pull requests on top of the base branch and push the result to the
+/// candidate branch. Pull requests which can't be merged cleanly are left out.
+async fn build_candidate(
+	state: &AppState,
+	owner: &str,
+	repo: &str,
+	base_branch: &str,
+	mrs: Vec<MergeRequest>,
+) -> Result<Candidate> {
+	let repo_dir = clone_repository(state, owner, repo).await?;
+	let auth = set_owner_remote(state, &repo_dir, owner, repo).await?;
+	let secrets_to_hide = [auth.as_str()];
+	let secrets_to_hide = Some(&secrets_to_hide[..]);
+
+	git(
+		&repo_dir,
+		&["-c", &auth, "fetch", "origin", base_branch],
+		secrets_to_hide,
+	)
+	.await?;
+	git(
+		&repo_dir,
+		&[
+			"checkout",
+			"--force",
+			"--detach",
+			&format!("origin/{}", base_branch),
+		],
+		secrets_to_hide,
+	)
+	.await?;
+	let base_sha =
+		git(&repo_dir, &["rev-parse", "HEAD"], secrets_to_hide).await?;
+
+	let mut merged = vec![];
+	let mut failed = vec![];
+	for mr in mrs {
+		git(
+			&repo_dir,
+			&[
+				"-c",
+				&auth,
+				"fetch",
+				"origin",
+				&format!("pull/{}/head", mr.number),
+			],
+			secrets_to_hide,
+		)
+		.await?;
+		// Only the commit which was trusted by the command's requester is merged
+		let fetched_sha =
+			git(&repo_dir, &["rev-parse", "FETCH_HEAD"], secrets_to_hide)
+				.await?;
+		if fetched_sha != mr.sha {
+			let err = Error::HeadChanged {
+				expected: mr.sha.to_owned(),
+				actual: fetched_sha,
+			};
+			failed.push((mr, err));
+			continue;
+		}
+
+		if let Err(err) = git(
+			&repo_dir,
+			&[
+				"merge",
+				"--no-ff",
+				"-m",
+				&format!("Merge #{} into {}", mr.number, base_branch),
+				&mr.sha,
+			],
+			secrets_to_hide,
+		)
+		.await
+		{
+			log::info!(
+				"Leaving {} out of the batch due to merge failure: {:?}",
+				mr.html_url,
+				err
+			);
+			git(&repo_dir, &["merge", "--abort"], secrets_to_hide).await?;
+			let err = Error::Message {
+				msg: format!(
+					"This PR could not be merged into `{}` together with the other PRs of its batch: {}",
+					base_branch, err
+				),
+			};
+			failed.push((mr, err));
+			continue;
+		}
+
+		merged.push(mr);
+	}
+
+	let sha = git(&repo_dir, &["rev-parse", "HEAD"], secrets_to_hide).await?;
+	if !merged.is_empty() {
+		http_cache::changing(git(
+			&repo_dir,
+			&[
+				"-c",
+				&auth,
+				"push",
+				"--force",
+				"origin",
+				&format!("HEAD:refs/heads/{}", candidate_branch(base_branch)),
+			],
+			secrets_to_hide,
+		))
+		.await?;
+	}
+
+	Ok(Candidate {
+		base_sha,
+		sha,
+		merged,
+		failed,
+	})
+}
+
+/// Push the candidate to the base branch. Returns false if the base branch has
+/// moved since the candidate was built, in which case nothing is pushed.
+async fn fast_forward(state: &AppState, batch: &MergeBatch) -> Result<bool> {
+	let MergeBatch {
+		owner,
+		repo,
+		base_branch,
+		..
+	} = batch;
+
+	let repo_dir = clone_repository(state, owner, repo).await?;
+	let auth = set_owner_remote(state, &repo_dir, owner, repo).await?;
+	let secrets_to_hide = [auth.as_str()];
+	let secrets_to_hide = Some(&secrets_to_hide[..]);
+
+	git(
+		&repo_dir,
+		&[
+			"-c",
+			&auth,
+			"fetch",
+			"origin",
+			base_branch,
+			&candidate_branch(base_branch),
+		],
+		secrets_to_hide,
+	)
+	.await?;
+	let base_sha = git(
+		&repo_dir,
+		&["rev-parse", &format!("origin/{}", base_branch)],
+		secrets_to_hide,
+	)
+	.await?;
+	if base_sha != batch.base_sha {
+		log::info!(
+			"{} of {}/{} moved from {} to {} while the batch was being tested",
+			base_branch,
+			owner,
+			repo,
+			batch.base_sha,
+			base_sha
+		);
+		return Ok(false);
+	}
+
+	http_cache::changing(git(
+		&repo_dir,
+		&[
+			"-c",
+			&auth,
+			"push",
+			"origin",
+			&format!("{}:refs/heads/{}", batch.candidate_sha, base_branch),
+		],
+		secrets_to_hide,
+	))
+	.await?;
+
+	Ok(true)
+}
+
+/// Cancel the merge of the pull requests which can't be merged. They're removed
+/// from the queue upfront so that their cleanup does not trigger the processing
+/// of the queue, which is left up to the caller.
+async fn fail_pull_requests(
+	state: &AppState,
+	failed: Vec<(MergeRequest, Error)>,
+) -> Result<()> {
+	let AppState { db, .. } = state;
+
+	for (mr, _) in &failed {
+		merge_queue::dequeue(db, &mr.owner, &mr.repo, mr.number)?;
+	}
+
+	for (mr, err) in failed {
+		if let Err(cleanup_err) = cleanup_pr(
+			state,
+			&mr.sha,
+			&mr.owner,
+			&mr.repo,
+			mr.number,
+			&PullRequestCleanupReason::Error,
+		)
+		.await
+		{
+			log::error!(
+				"Failed to cleanup {} after batch merge error: {:?}",
+				mr.html_url,
+				cleanup_err
+			);
+		}
+		handle_error(
+			MergeCancelOutcome::WasCancelled,
+			err.map_issue((mr.owner, mr.repo, mr.number)),
+			state,
+		)
+		.await;
+	}
+
+	Ok(())
+}
+
+/// Start testing the first group of pull requests which can still be merged;
+/// the groups after it are left pending. Returns whether a batch was started.
+async fn next_batch(
+	state: &AppState,
+	owner: &str,
+	repo: &str,
+	base_branch: &str,
+	groups: Vec<Vec<i64>>,
+	mut failed: Vec<(MergeRequest, Error)>,
+) -> Result<bool> {
+	let AppState { db, github_bot, .. } = state;
+
+	let mut groups = groups.into_iter();
+	let mut started_batch = None;
+	while let Some(numbers) = groups.next() {
+		let mut mrs = vec![];
+		for number in numbers {
+			// The pull request might have been cancelled in the meantime
+			if let Some(mr) = db::get_merge_request(db, owner, repo, number)? {
+				mrs.push(mr);
+			}
+		}
+		if mrs.is_empty() {
+			continue;
+		}
+
+		let candidate = lock::with_locks(
+			vec![lock::clone_key(owner, repo)],
+			build_candidate(state, owner, repo, base_branch, mrs),
+		)
+		.await?;
+		failed.extend(candidate.failed);
+		if candidate.merged.is_empty() {
+			continue;
+		}
+
+		let batch = MergeBatch {
+			owner: owner.into(),
+			repo: repo.into(),
+			base_branch: base_branch.into(),
+			base_sha: candidate.base_sha,
+			candidate_sha: candidate.sha,
+			numbers: candidate.merged.iter().map(|mr| mr.number).collect(),
+			pending: groups.collect(),
+		};
+		log::info!("Starting batch {:?}", batch);
+		db::put_merge_batch(db, &batch)?;
+		started_batch = Some((batch, candidate.merged));
+		break;
+	}
+
+	let is_started = if let Some((batch, mrs)) = started_batch {
+		let numbers = batch
+			.numbers
+			.iter()
+			.map(|number| format!("#{}", number))
+			.collect::<Vec<_>>()
+			.join(", ");
+		let msg = format!(
+			"Testing this PR in a batch ({}) on `{}` (commit {}). It will be merged once the batch's checks pass.",
+			numbers,
+			candidate_branch(base_branch),
+			batch.candidate_sha
+		);
+		for mr in mrs {
+			if let Err(err) = github_bot
+				.create_issue_comment(owner, repo, mr.number, &msg)
+				.await
+			{
+				log::error!("Error posting comment: {}", err);
+			}
+		}
+		true
+	} else {
+		false
+	};
+
+	fail_pull_requests(state, failed).await?;
+
+	Ok(is_started)
+}
+
+/// Run `f` while holding the locks of the queue and of its pull requests
+/// `numbers`. If the current task already holds other locks, they're only taken
+/// if they're free (see lock.rs); otherwise `f` is not run and None is returned,
+/// leaving the queue to be processed later.
+async fn with_queue_locks<T, F: std::future::Future<Output = Result<T>>>(
+	owner: &str,
+	repo: &str,
+	base_branch: &str,
+	numbers: &[i64],
+	f: F,
+) -> Result<Option<T>> {
+	let mut keys = numbers
+		.iter()
+		.map(|number| lock::pull_request_key(owner, repo, *number))
+		.collect::<Vec<_>>();
+	keys.push(lock::merge_queue_key(owner, repo, base_branch));
+
+	if !lock::holds_locks() {
+		return lock::with_locks(keys, f).await.map(Some);
+	}
+	match lock::try_with_locks(keys, f).await {
+		Some(result) => result.map(Some),
+		None => {
+			log::info!(
+				"Leaving the merge queue of {}/{} ({}) to be processed later since some of its pull requests are being processed",
+				owner,
+				repo,
+				base_branch
+			);
+			Ok(None)
+		}
+	}
+}
+
+/// Start testing a batch of the queue's ready pull requests unless a batch is
+/// already being tested for it.
+#[async_recursion]
+pub async fn process_queue(
+	state: &AppState,
+	owner: &str,
+	repo: &str,
+	base_branch: &str,
+) -> Result<()> {
+	let numbers = db::get_merge_queue(&state.db, owner, repo, base_branch)?
+		.entries
+		.iter()
+		.map(|entry| entry.number)
+		.collect::<Vec<_>>();
+	with_queue_locks(
+		owner,
+		repo,
+		base_branch,
+		&numbers,
+		process_queue_locked(state, owner, repo, base_branch, &numbers),
+	)
+	.await?;
+	Ok(())
+}
+
+/// Only the pull requests `numbers`, whose locks are held, are considered.
+async fn process_queue_locked(
+	state: &AppState,
+	owner: &str,
+	repo: &str,
+	base_branch: &str,
+	numbers: &[i64],
+) -> Result<()> {
+	let AppState { db, github_bot, .. } = state;
+
+	let max_size = match batch_size(state, owner, repo, base_branch).await? {
+		Some(max_size) => max_size,
+		None => return Ok(()),
+	};
+	if db::get_merge_batch(db, owner, repo, base_branch)?.is_some() {
+		return Ok(());
+	}
+
+	let queue = db::get_merge_queue(db, owner, repo, base_branch)?;
+	let mut ready = vec![];
+	let mut failed = vec![];
+	for entry in queue
+		.entries
+		.iter()
+		.filter(|entry| numbers.contains(&entry.number))
+	{
+		if ready.len() == max_size {
+			break;
+		}
+
+		let mr = match db::get_merge_request(db, owner, repo, entry.number)? {
+			Some(mr) => mr,
+			None => continue,
+		};
+		let pr = github_bot.pull_request(owner, repo, mr.number).await?;
+		if pr.merged {
+			continue;
+		}
+
+		match async {
+			if pr.head.sha != mr.sha {
+				return Err(Error::HeadChanged {
+					expected: mr.sha.to_owned(),
+					actual: pr.head.sha.to_owned(),
+				});
+			}
+			if let Some(merge_method) = mr
+				.merge_method
+				.filter(|merge_method| *merge_method != MergeMethod::Merge)
+			{
+				return Err(Error::Message {
+					msg: format!(
+						"This PR can't be merged with the {:?} method because the PRs of `{}` are merged in batches, which are always merged as merge commits. Request the merge again without a merge method.",
+						format!("{:?}", merge_method).to_lowercase(),
+						base_branch
+					),
+				});
+			}
+			if !ready_to_merge(github_bot, &pr).await? {
+				return Ok(false);
+			}
+			check_merge_is_allowed(state, &pr, &mr.requested_by, &[]).await?;
+			Ok(true)
+		}
+		.await
+		{
+			Ok(true) => ready.push(mr.number),
+			Ok(false) => {
+				log::info!("{} is not ready to be batched", pr.html_url)
+			}
+			Err(err) => failed.push((mr, err)),
+		}
+	}
+
+	let should_retry = !ready.is_empty() || !failed.is_empty();
+	if !next_batch(state, owner, repo, base_branch, vec![ready], failed).await?
+		&& should_retry
+	{
+		// Pull requests were removed from the queue, so others might be ready
+		process_queue(state, owner, repo, base_branch).await?;
+	}
+
+	Ok(())
+}
+
+/// Act on the outcome of a batch's statuses and checks.
+pub async fn check_batch(state: &AppState, batch: &MergeBatch) -> Result<()> {
+	let MergeBatch {
+		owner,
+		repo,
+		base_branch,
+		..
+	} = batch;
+
+	// The pending pull requests are included since they're cancelled if they
+	// can't be merged when their group is built into the next batch, and so are
+	// the queued ones for the next batch to be started from the queue
+	let mut numbers = db::get_merge_queue(&state.db, owner, repo, base_branch)?
+		.entries
+		.iter()
+		.map(|entry| entry.number)
+		.chain(batch.numbers.iter().copied())
+		.chain(batch.pending.iter().flatten().copied())
+		.collect::<Vec<_>>();
+	numbers.sort_unstable();
+	numbers.dedup();
+	let merged_prs = with_queue_locks(
+		owner,
+		repo,
+		base_branch,
+		&numbers,
+		check_batch_locked(state, batch),
+	)
+	.await?
+	.unwrap_or_default();
+
+	// The dependents are handled after the queue's lock is released since their
+	// processing acquires the dependents' locks
+	for (mr, pr) in merged_prs {
+		if let Err(err) =
+			handle_dependents_after_merge(state, &pr, &mr.requested_by).await
+		{
+			log::error!(
+				"Failed to handle the dependents of {} after batch merge: {:?}",
+				pr.html_url,
+				err
+			);
+		}
+	}
+
+	Ok(())
+}
+
+/// Returns the pull requests which were merged.
+async fn check_batch_locked(
+	state: &AppState,
+	batch: &MergeBatch,
+) -> Result<Vec<(MergeRequest, PullRequest)>> {
+	let AppState { db, github_bot, .. } = state;
+	let MergeBatch {
+		owner,
+		repo,
+		base_branch,
+		candidate_sha,
+		..
+	} = batch;
+
+	// The batch might have been processed by the time the lock was acquired,
+	// e.g. due to a duplicate status hook
+	match db::get_merge_batch(db, owner, repo, base_branch)? {
+		Some(current) if &current.candidate_sha == candidate_sha => {}
+		_ => {
+			log::info!(
+				"Skipping batch {:?} since it's no longer being tested",
+				batch
+			);
+			return Ok(vec![]);
+		}
+	}
+
+	let description = format!(
+		"batch {:?} of {}/{} ({})",
+		batch.numbers, owner, repo, base_branch
+	);
+	let status = match get_latest_statuses_state(
+		github_bot,
+		owner,
+		repo,
+		candidate_sha,
+		&description,
+	)
+	.await?
+	.0
+	{
+		Status::Success => {
+			get_latest_checks_state(
+				github_bot,
+				owner,
+				repo,
+				candidate_sha,
+				&description,
+			)
+			.await?
+		}
+		status => status,
+	};
+
+	let mut failed = vec![];
+	let mut merged_prs = vec![];
+	let groups = match status {
+		Status::Pending => return Ok(vec![]),
+		Status::Success => {
+			// The pull requests might have been cancelled or updated while the batch
+			// was being tested, in which case the candidate is not valid anymore
+			let mut is_stale = false;
+			let mut prs = vec![];
+			for number in &batch.numbers {
+				let mr = match db::get_merge_request(db, owner, repo, *number)?
+				{
+					Some(mr) => mr,
+					None => {
+						is_stale = true;
+						continue;
+					}
+				};
+				let pr = github_bot.pull_request(owner, repo, *number).await?;
+				if pr.head.sha != mr.sha {
+					is_stale = true;
+					let err = Error::HeadChanged {
+						expected: mr.sha.to_owned(),
+						actual: pr.head.sha,
+					};
+					failed.push((mr, err));
+				} else {
+					prs.push((mr, pr));
+				}
+			}
+
+			if is_stale
+				|| !lock::with_locks(
+					vec![lock::clone_key(owner, repo)],
+					fast_forward(state, batch),
+				)
+				.await?
+			{
+				log::info!("Rebuilding {}", description);
+				let mut groups =
+					vec![prs.iter().map(|(mr, _)| mr.number).collect()];
+				groups.extend(batch.pending.iter().cloned());
+				groups
+			} else {
+				log::info!("{} was merged", description);
+				// The batch's record is only deleted afterwards so that the cleanup
+				// does not start another batch for the queue
+				for (mr, pr) in prs {
+					metrics::record_merge(true);
+					if let Err(err) = cleanup_pr(
+						state,
+						&mr.sha,
+						owner,
+						repo,
+						mr.number,
+						&PullRequestCleanupReason::AfterMerge,
+					)
+					.await
+					{
+						log::error!(
+							"Failed to cleanup PR on the database after batch merge: {}",
+							err
+						);
+					}
+					merged_prs.push((mr, pr));
+				}
+				batch.pending.clone()
+			}
+		}
+		Status::Failure => {
+			if let [number] = batch.numbers[..] {
+				if let Some(mr) =
+					db::get_merge_request(db, owner, repo, number)?
+				{
+					let err = Error::ChecksFailed {
+						commit_sha: candidate_sha.to_owned(),
+					};
+					failed.push((mr, err));
+				}
+				batch.pending.clone()
+			} else {
+				log::info!("Bisecting {}", description);
+				bisect(&batch.numbers, &batch.pending)
+			}
+		}
+	};
+
+	db::delete_merge_batch(db, owner, repo, base_branch)?;
+	if !next_batch(state, owner, repo, base_branch, groups, failed).await? {
+		process_queue(state, owner, repo, base_branch).await?;
+	}
+
+	Ok(merged_prs)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_bisect() {
+		assert_eq!(
+			bisect(&[1, 2, 3, 4, 5], &[vec![6, 7]]),
+			vec![vec![1, 2, 3], vec![4, 5], vec![6, 7]]
+		);
+		assert_eq!(bisect(&[1, 2], &[]), vec![vec![1], vec![2]]);
+	}
+
+	#[test]
+	fn test_check_batchable() {
+		assert!(check_batchable(&RepositoryConfig::default()).is_ok());
+		assert!(check_batchable(&RepositoryConfig {
+			merge_method: Some(MergeMethod::Merge),
+			..Default::default()
+		})
+		.is_ok());
+		assert!(check_batchable(&RepositoryConfig {
+			merge_method: Some(MergeMethod::Squash),
+			..Default::default()
+		})
+		.is_err());
+		assert!(check_batchable(&RepositoryConfig {
+			merge_commit_title: Some("{title} (#{number})".into()),
+			..Default::default()
+		})
+		.is_err());
+	}
+}
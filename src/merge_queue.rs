@@ -0,0 +1,502 @@
+use async_recursion::async_recursion;
+use rocksdb::DB;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	companion::update_then_merge,
+	config::MainConfig,
+	db,
+	error::*,
+	lock, merge_batch,
+	webhook::{
+		cleanup_pr, handle_error, AppState, MergeRequest,
+		PullRequestCleanupReason, WaitToMergeMessage,
+	},
+	MergeCancelOutcome, Result,
+};
+
+/*
+	Pull requests are merged one at a time into each base branch. `bot merge` on a
+	pull request while another one is queued for the same base branch puts it at
+	the back of that branch's queue, and only the head of the queue is allowed to
+	be merged. A pull request is queued even if it can be merged right away, before
+	it's merged, so that no other one is merged into the same base branch
+	meanwhile. Once the head leaves the queue (it was merged, cancelled or failed)
+	the next entry is updated against the base branch and merged when its checks
+	pass.
+
+	In repositories where batch merging is enabled the queue is processed in
+	batches instead (see merge_batch.rs).
+
+	Priority entries (`bot merge priority` or the priority label) are placed
+	ahead of normal ones, though never ahead of the current head since it might
+	already be in the process of being merged.
+
+	Companions are not queued: they're merged as a consequence of their
+	dependencies being merged (see handle_dependents_after_merge).
+
+	Queues are modified through read-modify-write cycles which might happen
+	concurrently for the same queue, so they're serialized by QUEUES_LOCK.
+*/
+
+lazy_static::lazy_static! {
+	static ref QUEUES_LOCK: parking_lot::Mutex<()> = parking_lot::Mutex::new(());
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MergeQueueEntry {
+	pub number: i64,
+	pub html_url: String,
+	pub requested_by: String,
+	pub is_priority: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MergeQueue {
+	pub owner: String,
+	pub repo: String,
+	pub base_branch: String,
+	pub entries: Vec<MergeQueueEntry>,
+}
+
+impl MergeQueue {
+	pub fn head(&self) -> Option<&MergeQueueEntry> {
+		self.entries.first()
+	}
+
+	/// 1-based position of a pull request in the queue
+	pub fn position(&self, number: i64) -> Option<usize> {
+		self.entries
+			.iter()
+			.position(|entry| entry.number == number)
+			.map(|index| index + 1)
+	}
+
+	/// Whether the pull request is allowed to be merged as far as the queue is
+	/// concerned, i.e. nothing is ahead of it.
+	pub fn is_turn_of(&self, number: i64) -> bool {
+		self.head()
+			.map(|head| head.number == number)
+			.unwrap_or(true)
+	}
+
+	fn insert(&mut self, entry: MergeQueueEntry) -> usize {
+		let index = if entry.is_priority && !self.entries.is_empty() {
+			self.entries
+				.iter()
+				.skip(1)
+				.position(|entry| !entry.is_priority)
+				.map(|index| index + 1)
+				.unwrap_or_else(|| self.entries.len())
+		} else {
+			self.entries.len()
+		};
+		self.entries.insert(index, entry);
+		index + 1
+	}
+}
+
+/// Whether the user is allowed to move pull requests ahead in the queue.
+pub fn can_prioritize(config: &MainConfig, login: &str) -> bool {
+	config
+		.merge_priority_maintainers
+		.iter()
+		.any(|maintainer| maintainer.eq_ignore_ascii_case(login))
+}
+
+/// Add a pull request to the queue of its base branch unless it's already
+/// queued, in which case it might only be promoted to priority. Returns its
+/// position and the length of the queue.
+pub fn enqueue(
+	db: &DB,
+	owner: &str,
+	repo: &str,
+	base_branch: &str,
+	entry: MergeQueueEntry,
+) -> Result<(usize, usize)> {
+	let _queues_lock = QUEUES_LOCK.lock();
+	let mut queue = db::get_merge_queue(db, owner, repo, base_branch)?;
+	let position = match queue.position(entry.number) {
+		Some(position)
+			if !entry.is_priority
+				|| queue.entries[position - 1].is_priority =>
+		{
+			position
+		}
+		Some(1) => {
+			queue.entries[0].is_priority = true;
+			db::put_merge_queue(db, &queue)?;
+			1
+		}
+		Some(position) => {
+			log::info!(
+				"Moving {} ahead in the merge queue of {}/{} ({}) due to priority",
+				entry.html_url,
+				owner,
+				repo,
+				base_branch
+			);
+			queue.entries.remove(position - 1);
+			let position = queue.insert(entry);
+			db::put_merge_queue(db, &queue)?;
+			position
+		}
+		None => {
+			log::info!(
+				"Adding {} to the merge queue of {}/{} ({})",
+				entry.html_url,
+				owner,
+				repo,
+				base_branch
+			);
+			let position = queue.insert(entry);
+			db::put_merge_queue(db, &queue)?;
+			position
+		}
+	};
+	Ok((position, queue.entries.len()))
+}
+
+/// Remove a pull request from the queues of its repository. Returns the queues
+/// whose head changed as a result.
+pub fn dequeue(
+	db: &DB,
+	owner: &str,
+	repo: &str,
+	number: i64,
+) -> Result<Vec<MergeQueue>> {
+	let _queues_lock = QUEUES_LOCK.lock();
+	let mut advanced_queues = vec![];
+
+	for mut queue in db::get_repository_merge_queues(db, owner, repo)? {
+		let position = match queue.position(number) {
+			Some(position) => position,
+			None => continue,
+		};
+		log::info!(
+			"Removing {}/{}/pull/{} from the merge queue of {}",
+			owner,
+			repo,
+			number,
+			queue.base_branch
+		);
+		queue.entries.remove(position - 1);
+		db::put_merge_queue(db, &queue)?;
+		if position == 1 && !queue.entries.is_empty() {
+			advanced_queues.push(queue);
+		}
+	}
+
+	Ok(advanced_queues)
+}
+
+/// Start processing the head of a queue: the pull request is updated against
+/// the base branch and merged once it's ready. Entries which are no longer
+/// pending merge are dropped on the way.
+#[async_recursion]
+pub async fn process_head(state: &AppState, queue: &MergeQueue) -> Result<()> {
+	let AppState { db, .. } = state;
+
+	if merge_batch::batch_size(
+		state,
+		&queue.owner,
+		&queue.repo,
+		&queue.base_branch,
+	)
+	.await?
+	.is_some()
+	{
+		return merge_batch::process_queue(
+			state,
+			&queue.owner,
+			&queue.repo,
+			&queue.base_branch,
+		)
+		.await;
+	}
+
+	let head = {
+		let _queues_lock = QUEUES_LOCK.lock();
+		let mut queue = db::get_merge_queue(
+			db,
+			&queue.owner,
+			&queue.repo,
+			&queue.base_branch,
+		)?;
+		loop {
+			let head = match queue.head() {
+				Some(head) => head.clone(),
+				None => return Ok(()),
+			};
+			if db::get_merge_request(
+				db,
+				&queue.owner,
+				&queue.repo,
+				head.number,
+			)?
+			.is_some()
+			{
+				break head;
+			}
+			log::info!(
+				"Dropping {} from the merge queue of {} because it's no longer pending merge",
+				head.html_url,
+				queue.base_branch
+			);
+			queue.entries.remove(0);
+			db::put_merge_queue(db, &queue)?;
+		}
+	};
+
+	lock::with_locks(
+		vec![lock::pull_request_key(
+			&queue.owner,
+			&queue.repo,
+			head.number,
+		)],
+		async {
+			// The head might have been handled by the time the lock was acquired
+			match db::get_merge_request(
+				db,
+				&queue.owner,
+				&queue.repo,
+				head.number,
+			)? {
+				Some(mr) => {
+					process_head_locked(state, &queue.base_branch, mr).await
+				}
+				None => Ok(()),
+			}
+		},
+	)
+	.await
+}
+
+async fn process_head_locked(
+	state: &AppState,
+	base_branch: &str,
+	mr: MergeRequest,
+) -> Result<()> {
+	let AppState { github_bot, .. } = state;
+
+	log::info!(
+		"{} is now at the head of the merge queue of {}",
+		mr.html_url,
+		base_branch
+	);
+
+	let msg = format!(
+		"This PR is now at the head of the merge queue for `{}`. It has been updated and will be merged once its checks pass.",
+		base_branch
+	);
+	if let Err(err) = async {
+		// The command issuer only trusted the commit they commented on, therefore
+		// the branch is only updated if no other commits were pushed since then
+		let pr = github_bot
+			.pull_request(&mr.owner, &mr.repo, mr.number)
+			.await?;
+		if pr.head.sha != mr.sha {
+			return Err(Error::HeadChanged {
+				expected: mr.sha.to_owned(),
+				actual: pr.head.sha,
+			}
+			.map_issue((mr.owner.to_owned(), mr.repo.to_owned(), mr.number)));
+		}
+
+		update_then_merge(
+			state,
+			&MergeRequest {
+				was_updated: false,
+				..mr.clone()
+			},
+			&WaitToMergeMessage::Custom(&msg),
+			true,
+			true,
+		)
+		.await
+	}
+	.await
+	{
+		// After a transient failure the pull request stays at the head of the
+		// queue and its merge is attempted again by the poll
+		if err.stops_merge_attempt() {
+			if let Err(cleanup_err) = cleanup_pr(
+				state,
+				&mr.sha,
+				&mr.owner,
+				&mr.repo,
+				mr.number,
+				&PullRequestCleanupReason::Error,
+			)
+			.await
+			{
+				log::error!(
+					"Failed to cleanup {} after merge queue error: {:?}",
+					mr.html_url,
+					cleanup_err
+				);
+			}
+		}
+		handle_error(MergeCancelOutcome::WasCancelled, err, state).await;
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entry(number: i64) -> MergeQueueEntry {
+		MergeQueueEntry {
+			number,
+			html_url: format!(
+				"https://github.com/paritytech/substrate/pull/{}",
+				number
+			),
+			requested_by: "foo".into(),
+			is_priority: false,
+		}
+	}
+
+	fn priority_entry(number: i64) -> MergeQueueEntry {
+		MergeQueueEntry {
+			is_priority: true,
+			..entry(number)
+		}
+	}
+
+	fn queued_numbers(db: &DB) -> Vec<i64> {
+		db::get_merge_queue(db, "paritytech", "substrate", "master")
+			.unwrap()
+			.entries
+			.iter()
+			.map(|entry| entry.number)
+			.collect()
+	}
+
+	#[test]
+	fn test_queue_is_fifo_per_base_branch() {
+		let dir = tempfile::tempdir().unwrap();
+		let db = DB::open_default(dir.path()).unwrap();
+
+		assert_eq!(
+			enqueue(&db, "paritytech", "substrate", "master", entry(1))
+				.unwrap(),
+			(1, 1)
+		);
+		assert_eq!(
+			enqueue(&db, "paritytech", "substrate", "master", entry(2))
+				.unwrap(),
+			(2, 2)
+		);
+		assert_eq!(
+			enqueue(&db, "paritytech", "substrate", "release", entry(3))
+				.unwrap(),
+			(1, 1)
+		);
+		// Queueing again does not change the position
+		assert_eq!(
+			enqueue(&db, "paritytech", "substrate", "master", entry(1))
+				.unwrap(),
+			(1, 2)
+		);
+
+		let queue =
+			db::get_merge_queue(&db, "paritytech", "substrate", "master")
+				.unwrap();
+		assert!(queue.is_turn_of(1));
+		assert!(!queue.is_turn_of(2));
+		assert_eq!(queue.position(2), Some(2));
+		assert_eq!(queue.position(3), None);
+	}
+
+	#[test]
+	fn test_dequeue_reports_advanced_queues() {
+		let dir = tempfile::tempdir().unwrap();
+		let db = DB::open_default(dir.path()).unwrap();
+		for number in 1..=3 {
+			enqueue(&db, "paritytech", "substrate", "master", entry(number))
+				.unwrap();
+		}
+		enqueue(&db, "paritytech", "polkadot", "master", entry(1)).unwrap();
+
+		// Removing an entry which is not the head does not advance the queue
+		assert!(dequeue(&db, "paritytech", "substrate", 2)
+			.unwrap()
+			.is_empty());
+
+		let advanced = dequeue(&db, "paritytech", "substrate", 1).unwrap();
+		assert_eq!(advanced.len(), 1);
+		assert_eq!(advanced[0].head(), Some(&entry(3)));
+
+		// Other repositories are not affected
+		let queue =
+			db::get_merge_queue(&db, "paritytech", "polkadot", "master")
+				.unwrap();
+		assert_eq!(queue.head(), Some(&entry(1)));
+
+		// Empty queues are removed
+		assert!(dequeue(&db, "paritytech", "substrate", 3)
+			.unwrap()
+			.is_empty());
+		assert_eq!(db::get_merge_queues(&db).unwrap().len(), 1);
+	}
+
+	#[test]
+	fn test_priority_entries_go_ahead_of_normal_ones() {
+		let dir = tempfile::tempdir().unwrap();
+		let db = DB::open_default(dir.path()).unwrap();
+
+		// The head keeps its place even if normal priority
+		for number in 1..=3 {
+			enqueue(&db, "paritytech", "substrate", "master", entry(number))
+				.unwrap();
+		}
+		assert_eq!(
+			enqueue(
+				&db,
+				"paritytech",
+				"substrate",
+				"master",
+				priority_entry(4)
+			)
+			.unwrap(),
+			(2, 4)
+		);
+		// Priority entries are FIFO among themselves
+		assert_eq!(
+			enqueue(
+				&db,
+				"paritytech",
+				"substrate",
+				"master",
+				priority_entry(5)
+			)
+			.unwrap(),
+			(3, 5)
+		);
+		assert_eq!(queued_numbers(&db), vec![1, 4, 5, 2, 3]);
+
+		// Queued entries are promoted
+		assert_eq!(
+			enqueue(
+				&db,
+				"paritytech",
+				"substrate",
+				"master",
+				priority_entry(3)
+			)
+			.unwrap(),
+			(4, 5)
+		);
+		assert_eq!(queued_numbers(&db), vec![1, 4, 5, 3, 2]);
+
+		// Re-queueing as normal does not demote
+		assert_eq!(
+			enqueue(&db, "paritytech", "substrate", "master", entry(4))
+				.unwrap(),
+			(2, 5)
+		);
+	}
+}
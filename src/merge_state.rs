@@ -0,0 +1,400 @@
+use rocksdb::DB;
+
+use crate::{
+	companion::update_then_merge,
+	db,
+	error::*,
+	lock,
+	webhook::{
+		cleanup_pr, handle_error, handle_merged_pr, AppState, MergeRequest,
+		MergeState, PullRequestCleanupReason, WaitToMergeMessage,
+	},
+	MergeCancelOutcome, Result,
+};
+
+/*
+	Each pending merge request carries the state of its merge (see MergeState),
+	which is persisted before the step it describes is taken:
+
+	Queued -> Updating { pushed_sha: None } -> Updating { pushed_sha: Some(..) }
+		-> AwaitingChecks -> Merging -> Merged
+
+	A step can fail at any point, in which case the merge request goes to Failed
+	before it's cleaned up. Merge requests which don't depend on others start
+	from AwaitingChecks.
+
+	If processbot stops in the middle of a step (e.g. it crashes or the shutdown
+	timeout elapses), the step is resumed or rolled back on startup according to
+	what GitHub says about the pull request, before anything else is processed:
+
+	- Updating: the update is redone if the branch was not pushed; if it was, the
+		merge request continues from the pushed commit
+	- Merging and Merged: the merge is finished (cleanup and dependents) if the
+		pull request was merged, otherwise it goes back to waiting for its checks
+	- Failed: the cleanup is finished and the failure is reported again
+
+	Merge requests waiting for their dependencies are also resumed if one of
+	their dependencies was merged meanwhile, since those are not handled by the
+	poll in main.rs.
+
+	Merge batches (see merge_batch.rs) have their own records and are resumed by
+	the poll.
+*/
+
+/// Persist a new state for the pending merge of a pull request. Does nothing if
+/// its merge is not pending.
+pub fn transition(
+	db: &DB,
+	owner: &str,
+	repo: &str,
+	number: i64,
+	state: MergeState,
+) -> Result<Option<MergeRequest>> {
+	let mr = match db::get_merge_request(db, owner, repo, number)? {
+		Some(mr) => mr,
+		None => return Ok(None),
+	};
+	log::info!("Merge of {}: {:?} -> {:?}", mr.html_url, mr.state, state);
+	let mr = MergeRequest { state, ..mr };
+	db::put_merge_request(db, &mr)?;
+	Ok(Some(mr))
+}
+
+/// Record that the pending merge of a pull request failed due to `err`, unless
+/// the failure does not stop the merge (see Error::stops_merge_attempt).
+pub fn fail(
+	db: &DB,
+	owner: &str,
+	repo: &str,
+	number: i64,
+	err: &Error,
+) -> Result<()> {
+	if !err.stops_merge_attempt() {
+		return Ok(());
+	}
+	transition(
+		db,
+		owner,
+		repo,
+		number,
+		MergeState::Failed {
+			reason: err.to_string(),
+		},
+	)
+	.map(|_| ())
+}
+
+/// Persist a merge request with a new state, registering it if it's not yet.
+pub fn put_with_state(
+	db: &DB,
+	mr: &MergeRequest,
+	state: MergeState,
+) -> Result<()> {
+	log::info!("Merge of {}: {:?} -> {:?}", mr.html_url, mr.state, state);
+	db::put_merge_request(
+		db,
+		&MergeRequest {
+			state,
+			..mr.clone()
+		},
+	)
+}
+
+fn is_interrupted(state: &MergeState) -> bool {
+	match state {
+		MergeState::Queued | MergeState::AwaitingChecks => false,
+		MergeState::Updating { .. }
+		| MergeState::Merging
+		| MergeState::Merged
+		| MergeState::Failed { .. } => true,
+	}
+}
+
+#[derive(Debug, PartialEq)]
+enum Recovery {
+	// The pull request was merged: clean up and handle its dependents
+	FinishMerge,
+	// Go back to waiting for the checks, after which the merge is attempted again
+	RollBack,
+	// The updated branch was pushed: continue from the pushed commit
+	CompleteUpdate(String),
+	// The updated branch was not pushed: update it again
+	RetryUpdate,
+	Fail(String),
+}
+
+/// Decide how to recover an interrupted merge request given the current state
+/// of its pull request.
+fn recovery(mr: &MergeRequest, head_sha: &str, is_merged: bool) -> Recovery {
+	if is_merged {
+		return Recovery::FinishMerge;
+	}
+	match &mr.state {
+		MergeState::Updating { pushed_sha } => {
+			if pushed_sha.as_deref() == Some(head_sha) {
+				Recovery::CompleteUpdate(head_sha.to_owned())
+			} else if mr.sha == head_sha {
+				Recovery::RetryUpdate
+			} else {
+				Recovery::Fail(
+					Error::HeadChanged {
+						expected: pushed_sha
+							.as_ref()
+							.unwrap_or(&mr.sha)
+							.to_owned(),
+						actual: head_sha.to_owned(),
+					}
+					.to_string(),
+				)
+			}
+		}
+		MergeState::Failed { reason } => Recovery::Fail(reason.to_owned()),
+		MergeState::Queued
+		| MergeState::AwaitingChecks
+		| MergeState::Merging
+		| MergeState::Merged => Recovery::RollBack,
+	}
+}
+
+async fn resume(state: &AppState, mr: &MergeRequest) -> Result<()> {
+	let AppState { db, github_bot, .. } = state;
+
+	let pr = github_bot
+		.pull_request(&mr.owner, &mr.repo, mr.number)
+		.await?;
+	let recovery = recovery(mr, &pr.head.sha, pr.merged);
+	log::info!(
+		"Recovering the interrupted merge of {} ({:?}) with {:?}",
+		mr.html_url,
+		mr.state,
+		recovery
+	);
+	match recovery {
+		Recovery::FinishMerge => {
+			handle_merged_pr(state, &pr, &mr.requested_by).await?;
+		}
+		Recovery::RollBack => {
+			transition(
+				db,
+				&mr.owner,
+				&mr.repo,
+				mr.number,
+				MergeState::AwaitingChecks,
+			)?;
+		}
+		Recovery::CompleteUpdate(updated_sha) => {
+			cleanup_pr(
+				state,
+				&mr.sha,
+				&mr.owner,
+				&mr.repo,
+				mr.number,
+				&PullRequestCleanupReason::AfterSHAUpdate(&updated_sha),
+			)
+			.await?;
+			put_with_state(
+				db,
+				&MergeRequest {
+					sha: updated_sha,
+					was_updated: true,
+					dependencies: None,
+					..mr.clone()
+				},
+				MergeState::AwaitingChecks,
+			)?;
+		}
+		Recovery::RetryUpdate => {
+			update_then_merge(
+				state,
+				&MergeRequest {
+					was_updated: false,
+					..mr.clone()
+				},
+				&WaitToMergeMessage::None,
+				true,
+				true,
+			)
+			.await?;
+		}
+		Recovery::Fail(reason) => return Err(Error::Message { msg: reason }),
+	}
+
+	Ok(())
+}
+
+/// Resume the merges of the pull requests which depend on `dependency` if it was
+/// merged while processbot was stopped.
+async fn resume_dependents(
+	state: &AppState,
+	dependency: (&str, &str, i64),
+	requested_by: &str,
+) -> Result<()> {
+	let (owner, repo, number) = dependency;
+	let pr = state.github_bot.pull_request(owner, repo, number).await?;
+	if pr.merged {
+		log::info!(
+			"Resuming the dependents of {} since it was merged",
+			pr.html_url
+		);
+		handle_merged_pr(state, &pr, requested_by).await?;
+	}
+	Ok(())
+}
+
+/// Resume or roll back the merges which were interrupted by processbot stopping.
+/// Should be called on startup before any other merge is processed.
+pub async fn resume_interrupted(state: &AppState) {
+	let mrs = db::get_merge_requests(&state.db);
+
+	for mr in mrs.iter().filter(|mr| is_interrupted(&mr.state)) {
+		let result = lock::with_locks(
+			vec![lock::pull_request_key(&mr.owner, &mr.repo, mr.number)],
+			async {
+				// The merge might have been handled by the time the lock was acquired
+				let mr = match db::get_merge_request(
+					&state.db, &mr.owner, &mr.repo, mr.number,
+				)? {
+					Some(mr) if is_interrupted(&mr.state) => mr,
+					_ => return Ok(()),
+				};
+				let result = resume(state, &mr).await;
+				if let Err(err) = &result {
+					// After a transient failure the merge is left as it is for the poll
+					if err.stops_merge_attempt() {
+						let _ = cleanup_pr(
+							state,
+							&mr.sha,
+							&mr.owner,
+							&mr.repo,
+							mr.number,
+							&PullRequestCleanupReason::Error,
+						)
+						.await;
+					}
+				}
+				result
+			},
+		)
+		.await;
+		if let Err(err) = result {
+			handle_error(
+				MergeCancelOutcome::WasCancelled,
+				err.map_issue((mr.owner.clone(), mr.repo.clone(), mr.number)),
+				state,
+			)
+			.await;
+		}
+	}
+
+	let mut dependencies = vec![];
+	for mr in mrs.iter().filter(|mr| mr.state == MergeState::Queued) {
+		for dependency in mr.dependencies.iter().flatten() {
+			let key = (
+				dependency.owner.as_str(),
+				dependency.repo.as_str(),
+				dependency.number,
+			);
+			if !dependencies.iter().any(|(other, _)| *other == key) {
+				dependencies.push((key, mr.requested_by.as_str()));
+			}
+		}
+	}
+	for (dependency, requested_by) in dependencies {
+		let (owner, repo, number) = dependency;
+		let result = lock::with_locks(
+			vec![lock::pull_request_key(owner, repo, number)],
+			resume_dependents(state, dependency, requested_by),
+		)
+		.await;
+		if let Err(err) = result {
+			log::error!(
+				"Failed to resume the dependents of {}/{}/pull/{} due to {:?}",
+				owner,
+				repo,
+				number,
+				err
+			);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::MergeRequestBuilder;
+
+	fn merge_request(state: MergeState) -> MergeRequest {
+		MergeRequestBuilder::new("polkadot", 2)
+			.sha("old")
+			.state(state)
+			.build()
+	}
+
+	#[test]
+	fn test_transition() {
+		let dir = tempfile::tempdir().unwrap();
+		let db = db::open(dir.path()).unwrap();
+
+		assert!(transition(
+			&db,
+			"paritytech",
+			"polkadot",
+			2,
+			MergeState::Merging
+		)
+		.unwrap()
+		.is_none());
+
+		db::put_merge_request(&db, &merge_request(MergeState::AwaitingChecks))
+			.unwrap();
+		transition(&db, "paritytech", "polkadot", 2, MergeState::Merging)
+			.unwrap();
+		let mr = db::get_merge_request(&db, "paritytech", "polkadot", 2)
+			.unwrap()
+			.unwrap();
+		assert_eq!(mr.state, MergeState::Merging);
+	}
+
+	#[test]
+	fn test_recovery_of_update() {
+		let not_pushed =
+			merge_request(MergeState::Updating { pushed_sha: None });
+		assert_eq!(recovery(&not_pushed, "old", false), Recovery::RetryUpdate);
+		assert!(matches!(
+			recovery(&not_pushed, "other", false),
+			Recovery::Fail(_)
+		));
+
+		let pushed = merge_request(MergeState::Updating {
+			pushed_sha: Some("new".into()),
+		});
+		assert_eq!(
+			recovery(&pushed, "new", false),
+			Recovery::CompleteUpdate("new".into())
+		);
+		// The push did not go through
+		assert_eq!(recovery(&pushed, "old", false), Recovery::RetryUpdate);
+		assert!(matches!(
+			recovery(&pushed, "other", false),
+			Recovery::Fail(_)
+		));
+		assert_eq!(recovery(&pushed, "new", true), Recovery::FinishMerge);
+	}
+
+	#[test]
+	fn test_recovery_of_merge() {
+		for state in [MergeState::Merging, MergeState::Merged].iter() {
+			let mr = merge_request(state.clone());
+			assert_eq!(recovery(&mr, "old", true), Recovery::FinishMerge);
+			assert_eq!(recovery(&mr, "old", false), Recovery::RollBack);
+		}
+
+		let failed = merge_request(MergeState::Failed {
+			reason: "Conflicts".into(),
+		});
+		assert_eq!(
+			recovery(&failed, "old", false),
+			Recovery::Fail("Conflicts".into())
+		);
+	}
+}
@@ -0,0 +1,207 @@
+use prometheus::{
+	register_histogram_vec, register_int_counter, register_int_counter_vec,
+	register_int_gauge, register_int_gauge_vec, Encoder, HistogramVec,
+	IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use rocksdb::DB;
+use snafu::OptionExt;
+use std::time::Duration;
+
+use crate::{db, error::*, Result};
+
+/*
+	Metrics are served in the Prometheus text format at /metrics, which is only
+	authenticated if METRICS_TOKEN is set (see
+	admin::reject_unauthorized_metrics). They're registered in the default
+	registry and updated where the measured thing happens, except for the gauges
+	which reflect the database's contents; those are computed when the metrics
+	are rendered.
+*/
+
+// The content type of the Prometheus text format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+lazy_static::lazy_static! {
+	static ref WEBHOOK_EVENTS: IntCounterVec = register_int_counter_vec!(
+		"processbot_webhook_events_total",
+		"Webhook events by event type and outcome",
+		&["event", "outcome"]
+	)
+	.unwrap();
+	static ref PENDING_MERGE_REQUESTS: IntGauge = register_int_gauge!(
+		"processbot_pending_merge_requests",
+		"Merge requests stored in the database"
+	)
+	.unwrap();
+	static ref MERGES: IntCounterVec = register_int_counter_vec!(
+		"processbot_merges_total",
+		"Pull requests merged by processbot or which failed to be merged",
+		&["outcome"]
+	)
+	.unwrap();
+	static ref COMPANION_UPDATES: IntCounterVec = register_int_counter_vec!(
+		"processbot_companion_updates_total",
+		"Companion branches updated with their dependencies",
+		&["outcome"]
+	)
+	.unwrap();
+	static ref GITHUB_API_REQUESTS: IntCounterVec = register_int_counter_vec!(
+		"processbot_github_api_requests_total",
+		"Requests made to the GitHub API by method and response status",
+		&["method", "status"]
+	)
+	.unwrap();
+	static ref GITHUB_API_RETRIES: IntCounter = register_int_counter!(
+		"processbot_github_api_retries_total",
+		"Requests to the GitHub API which were retried after a transient failure"
+	)
+	.unwrap();
+	static ref GITHUB_API_CACHE: IntCounterVec = register_int_counter_vec!(
+		"processbot_github_api_cache_total",
+		"GETs to the GitHub API by how they were served",
+		&["result"]
+	)
+	.unwrap();
+	static ref GITHUB_RATE_LIMIT: IntGaugeVec = register_int_gauge_vec!(
+		"processbot_github_rate_limit",
+		"Requests allowed per GitHub API rate limit window, as of the latest response",
+		&["account"]
+	)
+	.unwrap();
+	static ref GITHUB_RATE_LIMIT_REMAINING: IntGaugeVec = register_int_gauge_vec!(
+		"processbot_github_rate_limit_remaining",
+		"Requests remaining in the current GitHub API rate limit window, as of the latest response",
+		&["account"]
+	)
+	.unwrap();
+	static ref GITHUB_RATE_LIMIT_RESET: IntGaugeVec = register_int_gauge_vec!(
+		"processbot_github_rate_limit_reset_timestamp_seconds",
+		"When the current GitHub API rate limit window resets, as of the latest response",
+		&["account"]
+	)
+	.unwrap();
+	static ref GITHUB_RATE_LIMITED: IntCounterVec = register_int_counter_vec!(
+		"processbot_github_rate_limited_total",
+		"Requests to the GitHub API which were delayed or retried due to the rate limits",
+		&["reason"]
+	)
+	.unwrap();
+	static ref COMMAND_DURATION: HistogramVec = register_histogram_vec!(
+		"processbot_command_duration_seconds",
+		"Duration of the commands (e.g. git or cargo) run by processbot",
+		&["command", "subcommand"],
+		vec![0.1, 0.5, 1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0]
+	)
+	.unwrap();
+}
+
+/// `outcome` is one of "duplicate", "processed", "failed" (an error was
+/// reported for it), "interrupted" or "dead_letter".
+pub fn record_webhook_event(event_type: Option<&str>, outcome: &str) {
+	WEBHOOK_EVENTS
+		.with_label_values(&[event_type.unwrap_or("unknown"), outcome])
+		.inc();
+}
+
+pub fn record_merge(was_merged: bool) {
+	MERGES
+		.with_label_values(&[if was_merged { "merged" } else { "failed" }])
+		.inc();
+}
+
+pub fn record_companion_update(was_updated: bool) {
+	COMPANION_UPDATES
+		.with_label_values(&[if was_updated { "updated" } else { "failed" }])
+		.inc();
+}
+
+/// `status` is None if no response was received.
+pub fn record_github_api_request(method: &str, status: Option<u16>) {
+	let status = status.map(|status| status.to_string());
+	GITHUB_API_REQUESTS
+		.with_label_values(&[method, status.as_deref().unwrap_or("error")])
+		.inc();
+}
+
+pub fn record_github_api_retry() {
+	GITHUB_API_RETRIES.inc();
+}
+
+/// `result` is one of "memoized" (no request was made), "not_modified" (served
+/// from the ETag cache) or "miss".
+pub fn record_github_api_cache(result: &str) {
+	GITHUB_API_CACHE.with_label_values(&[result]).inc();
+}
+
+/// `account` is the one whose installation made the request, since each
+/// installation has its own rate limit.
+pub fn record_github_rate_limit(
+	account: &str,
+	limit: i64,
+	remaining: i64,
+	reset: i64,
+) {
+	GITHUB_RATE_LIMIT.with_label_values(&[account]).set(limit);
+	GITHUB_RATE_LIMIT_REMAINING
+		.with_label_values(&[account])
+		.set(remaining);
+	GITHUB_RATE_LIMIT_RESET
+		.with_label_values(&[account])
+		.set(reset);
+}
+
+/// `reason` is one of "throttled" (delayed since the rate limit is nearly
+/// exhausted), "primary" or "secondary" (retried after hitting that rate
+/// limit).
+pub fn record_github_rate_limited(reason: &str) {
+	GITHUB_RATE_LIMITED.with_label_values(&[reason]).inc();
+}
+
+pub fn record_command_duration(
+	command: &str,
+	subcommand: Option<&str>,
+	duration: Duration,
+) {
+	COMMAND_DURATION
+		.with_label_values(&[command, subcommand.unwrap_or("")])
+		.observe(duration.as_secs_f64());
+}
+
+/// Render all metrics in the Prometheus text format.
+pub fn render(db: &DB) -> Result<String> {
+	PENDING_MERGE_REQUESTS.set(db::iter_merge_requests(db).count() as i64);
+
+	let mut buffer = vec![];
+	TextEncoder::new()
+		.encode(&prometheus::gather(), &mut buffer)
+		.ok()
+		.context(Message {
+			msg: "Failed to encode the metrics".to_owned(),
+		})?;
+	String::from_utf8(buffer).ok().context(Message {
+		msg: "Metrics are not valid UTF-8".to_owned(),
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_render() {
+		let dir = tempfile::tempdir().unwrap();
+		let db = db::open(dir.path()).unwrap();
+
+		record_webhook_event(Some("issue_comment"), "processed");
+		record_command_duration("git", Some("fetch"), Duration::from_secs(2));
+
+		let metrics = render(&db).unwrap();
+		assert!(metrics.contains("processbot_pending_merge_requests 0"));
+		assert!(metrics.contains(
+			"processbot_webhook_events_total{event=\"issue_comment\",outcome=\"processed\"}"
+		));
+		assert!(metrics.contains(
+			"processbot_command_duration_seconds_count{command=\"git\",subcommand=\"fetch\"}"
+		));
+	}
+}
@@ -0,0 +1,222 @@
+use regex::Regex;
+use reqwest::StatusCode;
+use serde::{de::Error as _, Deserialize};
+use std::collections::HashMap;
+
+use crate::{
+	error::*,
+	github::{MergeMethod, PullRequest},
+	github_bot::GithubBot,
+	rebase::RebaseStrategy,
+	Result,
+};
+
+/*
+	Repositories can override parts of processbot's behavior by having a
+	configuration file at REPOSITORY_CONFIG_PATH, e.g.
+
+	```toml
+	batch_size = 4
+	required_review_status = "Check reviews"
+	```
+
+	The file is read from the head of the pull request's base branch rather than
+	from the pull request itself, otherwise contributors would be able to change
+	the requirements of their own pull requests.
+*/
+
+pub const REPOSITORY_CONFIG_PATH: &str = ".github/processbot.toml";
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RepositoryConfig {
+	// Enables batch merging with batches of up to this size (see merge_batch.rs);
+	// 0 disables it even if the repository is in BATCH_MERGE_REPOSITORIES
+	pub batch_size: Option<usize>,
+	// The status which signals that the pull request has been properly reviewed,
+	// required for companions (see check_all_companions_are_mergeable)
+	pub required_review_status: Option<String>,
+	// Overrides for the delays of MainConfig (in milliseconds)
+	pub merge_command_delay: Option<u64>,
+	pub companion_status_settle_delay: Option<u64>,
+	// Defaults to MergeMethod::default(); can be overridden per command, e.g.
+	// `bot merge rebase`
+	pub merge_method: Option<MergeMethod>,
+	// Templates for the merge commit (see MERGE_TEMPLATE_PLACEHOLDERS); GitHub's
+	// default is used for the ones which are not provided. They're not
+	// applicable to the rebase method since it doesn't create a merge commit.
+	pub merge_commit_title: Option<String>,
+	pub merge_commit_message: Option<String>,
+	// What `bot rebase` does (see rebase.rs)
+	pub rebase_strategy: Option<RebaseStrategy>,
+}
+
+pub const MERGE_TEMPLATE_PLACEHOLDERS: &[&str] =
+	&["title", "number", "requested_by"];
+
+lazy_static::lazy_static! {
+	static ref PLACEHOLDER_REGEX: Regex = Regex::new(r"\{([a-z_]+)\}").unwrap();
+}
+
+impl RepositoryConfig {
+	pub fn parse(content: &str) -> Result<Self, toml::de::Error> {
+		let config: Self = toml::from_str(content)?;
+
+		for template in config
+			.merge_commit_title
+			.iter()
+			.chain(config.merge_commit_message.iter())
+		{
+			for captures in PLACEHOLDER_REGEX.captures_iter(template) {
+				let placeholder = &captures[1];
+				if !MERGE_TEMPLATE_PLACEHOLDERS.contains(&placeholder) {
+					return Err(toml::de::Error::custom(format!(
+						"unknown placeholder {{{}}} in merge commit template; the available ones are: {}",
+						placeholder,
+						MERGE_TEMPLATE_PLACEHOLDERS.join(", ")
+					)));
+				}
+			}
+		}
+
+		Ok(config)
+	}
+}
+
+/// Fill in the placeholders of a merge commit template, e.g.
+/// "{title} (#{number})".
+pub fn render_merge_template(
+	template: &str,
+	pr: &PullRequest,
+	requested_by: &str,
+) -> String {
+	PLACEHOLDER_REGEX
+		.replace_all(template, |captures: &regex::Captures| {
+			match &captures[1] {
+				"title" => pr.title.to_owned(),
+				"number" => pr.number.to_string(),
+				"requested_by" => requested_by.to_owned(),
+				// Unknown placeholders are rejected when parsing the configuration
+				_ => captures[0].to_owned(),
+			}
+		})
+		.into_owned()
+}
+
+// The configuration is cached per commit since it can't change for a given
+// commit
+const CACHE_CAPACITY: usize = 1024;
+lazy_static::lazy_static! {
+	static ref CACHE: parking_lot::Mutex<HashMap<(String, String, String), RepositoryConfig>> = {
+		parking_lot::Mutex::new(HashMap::new())
+	};
+}
+
+/// Fetch the configuration of a repository as of the head of `branch`.
+/// Repositories without a configuration file get the default configuration.
+pub async fn fetch_repository_config(
+	github_bot: &GithubBot,
+	owner: &str,
+	repo: &str,
+	branch: &str,
+) -> Result<RepositoryConfig> {
+	let sha = github_bot.branch_ref(owner, repo, branch).await?.object.sha;
+	let cache_key = (owner.to_owned(), repo.to_owned(), sha.to_owned());
+	if let Some(config) = CACHE.lock().get(&cache_key) {
+		return Ok(config.clone());
+	}
+
+	let config = match github_bot
+		.contents(owner, repo, REPOSITORY_CONFIG_PATH, &sha)
+		.await
+	{
+		Ok(contents) => {
+			let content = base64::decode(&contents.content.replace('\n', ""))
+				.map_err(|err| Error::Message {
+				msg: format!(
+						"Failed to decode the API content for {} of {}/{} (commit {}): {:?}",
+						REPOSITORY_CONFIG_PATH, owner, repo, sha, err
+					),
+			})?;
+			RepositoryConfig::parse(&String::from_utf8_lossy(&content))
+				.map_err(|err| Error::Message {
+					msg: format!(
+						"Invalid {} in {}/{} (commit {}): {}",
+						REPOSITORY_CONFIG_PATH, owner, repo, sha, err
+					),
+				})?
+		}
+		Err(Error::Response { status, .. })
+			if status == StatusCode::NOT_FOUND =>
+		{
+			RepositoryConfig::default()
+		}
+		Err(err) => return Err(err),
+	};
+
+	let mut cache = CACHE.lock();
+	if cache.len() >= CACHE_CAPACITY {
+		cache.clear();
+	}
+	cache.insert(cache_key, config.clone());
+
+	Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_repository_config() {
+		assert_eq!(RepositoryConfig::parse("").unwrap(), Default::default());
+		assert_eq!(
+			RepositoryConfig::parse(
+				"batch_size = 4\nrequired_review_status = \"reviews\""
+			)
+			.unwrap(),
+			RepositoryConfig {
+				batch_size: Some(4),
+				required_review_status: Some("reviews".into()),
+				..Default::default()
+			}
+		);
+		// Typos should not be silently ignored
+		assert!(RepositoryConfig::parse("batch_sise = 4").is_err());
+		assert!(RepositoryConfig::parse("batch_size = \"4\"").is_err());
+		assert!(RepositoryConfig::parse("merge_method = \"octopus\"").is_err());
+		assert!(RepositoryConfig::parse("merge_commit_title = \"{titel}\"")
+			.is_err());
+	}
+
+	#[test]
+	fn test_render_merge_template() {
+		let config = RepositoryConfig::parse(
+			"merge_method = \"merge\"\nmerge_commit_title = \"{title} (#{number})\"\nmerge_commit_message = \"Merged by @{requested_by} {}\"",
+		)
+		.unwrap();
+		assert_eq!(config.merge_method, Some(MergeMethod::Merge));
+
+		let pr = PullRequest {
+			number: 42,
+			title: "Fix the thing".into(),
+			..Default::default()
+		};
+		assert_eq!(
+			render_merge_template(
+				config.merge_commit_title.as_ref().unwrap(),
+				&pr,
+				"foo"
+			),
+			"Fix the thing (#42)"
+		);
+		assert_eq!(
+			render_merge_template(
+				config.merge_commit_message.as_ref().unwrap(),
+				&pr,
+				"foo"
+			),
+			"Merged by @foo {}"
+		);
+	}
+}
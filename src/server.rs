@@ -10,10 +10,10 @@ use hyper::{
 	service::{make_service_fn, service_fn},
 	Body, Request, Server,
 };
+use std::future::Future;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::task::Poll;
-use tokio::sync::Mutex;
 
 struct Incoming<'a>(pub async_std::net::Incoming<'a>);
 
@@ -67,9 +67,12 @@ impl tokio::io::AsyncWrite for TcpStream {
 	}
 }
 
+/// Serve until `shutdown` resolves, after which no further connections are
+/// accepted and the requests being handled are waited for.
 pub async fn init(
 	addr: SocketAddr,
-	state: Arc<Mutex<AppState>>,
+	state: Arc<AppState>,
+	shutdown: impl Future<Output = ()> + Send + 'static,
 ) -> anyhow::Result<()> {
 	let listener = async_std::net::TcpListener::bind(&addr).await.unwrap();
 
@@ -88,6 +91,7 @@ pub async fn init(
 	Server::builder(Incoming(listener.incoming()))
 		.http1_half_close(true)
 		.serve(service)
+		.with_graceful_shutdown(shutdown)
 		.boxed()
 		.await
 		.context("Server error".to_owned())
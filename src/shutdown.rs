@@ -0,0 +1,95 @@
+use std::{
+	sync::atomic::{AtomicBool, Ordering},
+	time::Duration,
+};
+use tokio::{
+	signal::unix::{signal, SignalKind},
+	sync::{RwLock, RwLockReadGuard},
+	time::timeout,
+};
+
+/*
+	When processbot is asked to shut down (SIGTERM, e.g. on deployments, or
+	SIGINT):
+	1. The server stops accepting connections, letting the requests which are
+		being handled finish (see server::init)
+	2. No further events are taken from the event queue (see event_queue.rs) and
+		no further poll iterations are started (see main.rs)
+	3. The work in progress, i.e. anything done while holding a guard from
+		start_work, is waited for up to config.shutdown_timeout_secs
+	4. The database is flushed
+
+	Events whose processing is still ongoing when the timeout elapses are
+	attempted again after the restart, like those of a crash.
+
+	Guards from start_work must not be acquired while holding another one: once a
+	shutdown is requested, no further guards are handed out.
+*/
+
+static IS_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+	static ref WORK: RwLock<()> = RwLock::new(());
+}
+
+pub fn is_requested() -> bool {
+	IS_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Returns a guard which should be held while doing work which should not be
+/// interrupted by a shutdown. Never returns once a shutdown is under way.
+pub async fn start_work() -> RwLockReadGuard<'static, ()> {
+	WORK.read().await
+}
+
+/// Resolves once a shutdown is requested through SIGTERM or SIGINT.
+pub async fn signal_received() {
+	let mut sigterm =
+		signal(SignalKind::terminate()).expect("SIGTERM handler should be set");
+	tokio::select! {
+		_ = sigterm.recv() => log::info!("Received SIGTERM"),
+		_ = tokio::signal::ctrl_c() => log::info!("Received SIGINT"),
+	}
+	IS_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Wait for the work in progress to finish, up to `max_duration`. Returns
+/// whether it finished in time.
+pub async fn finish_work(max_duration: Duration) -> bool {
+	IS_REQUESTED.store(true, Ordering::SeqCst);
+	log::info!(
+		"Waiting up to {:?} for the work in progress to finish",
+		max_duration
+	);
+	match timeout(max_duration, WORK.write()).await {
+		Ok(guard) => {
+			// Held until the process exits so that no further work is started
+			std::mem::forget(guard);
+			true
+		}
+		Err(_) => false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tokio::time::delay_for;
+
+	#[tokio::test]
+	async fn test_finish_work() {
+		let guard = start_work().await;
+		let work = tokio::spawn(async move {
+			delay_for(Duration::from_millis(50)).await;
+			drop(guard);
+		});
+
+		assert!(finish_work(Duration::from_secs(5)).await);
+		work.await.unwrap();
+		assert!(is_requested());
+		// No further work is started
+		assert!(timeout(Duration::from_millis(50), start_work())
+			.await
+			.is_err());
+	}
+}
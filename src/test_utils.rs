@@ -0,0 +1,78 @@
+use chrono::{DateTime, Utc};
+
+use crate::webhook::{Dependency, MergeRequest, MergeState};
+
+/// Builds the merge requests used in the unit tests. Unless set otherwise, it's
+/// a queued merge of paritytech/{repo}#{number} without dependencies, whose head
+/// SHA is "{repo}{number}".
+pub(crate) struct MergeRequestBuilder(MergeRequest);
+
+impl MergeRequestBuilder {
+	pub fn new(repo: &str, number: i64) -> Self {
+		Self(MergeRequest {
+			sha: format!("{}{}", repo, number),
+			was_updated: false,
+			owner: "paritytech".to_owned(),
+			repo: repo.to_owned(),
+			number,
+			html_url: format!(
+				"https://github.com/paritytech/{}/pull/{}",
+				repo, number
+			),
+			requested_by: "foo".to_owned(),
+			dependencies: None,
+			merge_method: None,
+			requested_at: None,
+			state: MergeState::Queued,
+		})
+	}
+
+	pub fn sha(mut self, sha: &str) -> Self {
+		self.0.sha = sha.to_owned();
+		self
+	}
+
+	pub fn requested_by(mut self, requested_by: &str) -> Self {
+		self.0.requested_by = requested_by.to_owned();
+		self
+	}
+
+	pub fn requested_at(mut self, requested_at: DateTime<Utc>) -> Self {
+		self.0.requested_at = Some(requested_at);
+		self
+	}
+
+	pub fn dependencies(mut self, dependencies: Vec<Dependency>) -> Self {
+		self.0.dependencies = Some(dependencies);
+		self
+	}
+
+	pub fn state(mut self, state: MergeState) -> Self {
+		self.0.state = state;
+		self
+	}
+
+	pub fn build(self) -> MergeRequest {
+		self.0
+	}
+
+	/// The merge request's pull request as a direct dependency of another one.
+	pub fn dependency(self) -> Dependency {
+		let MergeRequest {
+			sha,
+			owner,
+			repo,
+			number,
+			html_url,
+			..
+		} = self.0;
+		Dependency {
+			sha,
+			owner,
+			repo,
+			number,
+			html_url,
+			is_directly_referenced: true,
+		}
+	}
+}
@@ -1,16 +1,98 @@
-use crate::{CommentCommand, MergeCommentCommand};
+use crate::{
+	github::MergeMethod, CommentCommand, MergeCommentCommand,
+	QueueCommentCommand,
+};
 
 pub fn parse_bot_comment_from_text(text: &str) -> Option<CommentCommand> {
 	let text = text.to_lowercase();
 	let text = text.trim();
 
 	let cmd = match text {
-		"bot merge" => CommentCommand::Merge(MergeCommentCommand::Normal),
-		"bot merge force" => CommentCommand::Merge(MergeCommentCommand::Force),
 		"bot merge cancel" => CommentCommand::CancelMerge,
 		"bot rebase" => CommentCommand::Rebase,
-		_ => return None,
+		"bot update" | "bot merge-master" => CommentCommand::Update,
+		"bot queue" => CommentCommand::Queue(QueueCommentCommand::Show),
+		"bot queue position" => {
+			CommentCommand::Queue(QueueCommentCommand::Position)
+		}
+		_ => CommentCommand::Merge(parse_merge_command(text)?),
 	};
 
 	Some(cmd)
 }
+
+// Parses "bot merge [priority|force] [merge|squash|rebase]"
+fn parse_merge_command(text: &str) -> Option<MergeCommentCommand> {
+	let mut words = text.split_whitespace();
+	if words.next()? != "bot" || words.next()? != "merge" {
+		return None;
+	}
+
+	let mut words = words.peekable();
+	let variant = match words.peek() {
+		Some(&"priority") => {
+			words.next();
+			MergeCommentCommand::Priority
+		}
+		Some(&"force") => {
+			words.next();
+			MergeCommentCommand::Force
+		}
+		_ => MergeCommentCommand::Normal,
+	};
+	let merge_method = match words.next() {
+		Some("merge") => Some(MergeMethod::Merge),
+		Some("squash") => Some(MergeMethod::Squash),
+		Some("rebase") => Some(MergeMethod::Rebase),
+		Some(_) => return None,
+		None => None,
+	};
+	if words.next().is_some() {
+		return None;
+	}
+
+	Some(variant(merge_method))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse_merge(text: &str) -> Option<MergeCommentCommand> {
+		match parse_bot_comment_from_text(text)? {
+			CommentCommand::Merge(cmd) => Some(cmd),
+			_ => None,
+		}
+	}
+
+	#[test]
+	fn test_parse_merge_command() {
+		assert!(matches!(
+			parse_merge("bot merge"),
+			Some(MergeCommentCommand::Normal(None))
+		));
+		assert!(matches!(
+			parse_merge(" Bot Merge Rebase "),
+			Some(MergeCommentCommand::Normal(Some(MergeMethod::Rebase)))
+		));
+		assert!(matches!(
+			parse_merge("bot merge priority"),
+			Some(MergeCommentCommand::Priority(None))
+		));
+		assert!(matches!(
+			parse_merge("bot merge force merge"),
+			Some(MergeCommentCommand::Force(Some(MergeMethod::Merge)))
+		));
+		assert!(matches!(
+			parse_bot_comment_from_text("bot merge cancel"),
+			Some(CommentCommand::CancelMerge)
+		));
+		assert!(parse_merge("bot merge fast-forward").is_none());
+		assert!(parse_merge("bot merge squash rebase").is_none());
+		assert!(parse_merge("bot merged").is_none());
+		assert!(matches!(
+			parse_bot_comment_from_text("bot merge-master"),
+			Some(CommentCommand::Update)
+		));
+	}
+}
@@ -1,4 +1,5 @@
 use async_recursion::async_recursion;
+use chrono::{DateTime, Utc};
 use futures::StreamExt;
 use html_escape;
 use hyper::{Body, Request, Response, StatusCode};
@@ -7,16 +8,28 @@ use regex::RegexBuilder;
 use ring::hmac;
 use rocksdb::DB;
 use serde::{Deserialize, Serialize};
-use snafu::{OptionExt, ResultExt};
+use snafu::OptionExt;
 use std::collections::HashSet;
 use std::{collections::HashMap, sync::Arc, time::Duration};
-use tokio::{sync::Mutex, time::delay_for};
+use tokio::time::delay_for;
 
 use crate::{
-	companion::*, config::MainConfig, error::*, github::*,
-	github_bot::GithubBot, rebase::*, utils::parse_bot_comment_from_text,
+	admin,
+	companion::*,
+	config::MainConfig,
+	dashboard, db,
+	error::*,
+	event_queue,
+	github::*,
+	github_bot::GithubBot,
+	health, lock, merge_batch,
+	merge_queue::{self, MergeQueueEntry},
+	merge_state, metrics,
+	rebase::*,
+	repository_config::{fetch_repository_config, render_merge_template},
+	utils::parse_bot_comment_from_text,
 	vanity_service, CommentCommand, MergeCancelOutcome, MergeCommentCommand,
-	Result, Status, WEBHOOK_PARSING_ERROR_TEMPLATE,
+	QueueCommentCommand, Result, Status, WEBHOOK_PARSING_ERROR_TEMPLATE,
 };
 
 pub struct AppState {
@@ -35,6 +48,26 @@ pub struct Dependency {
 	pub html_url: String,
 	pub is_directly_referenced: bool,
 }
+
+/// Where a merge request is at. Each transition is persisted before the step it
+/// describes is taken so that an interrupted step can be resumed or rolled back
+/// on startup (see merge_state.rs).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum MergeState {
+	// Waiting for its dependencies to be merged
+	Queued,
+	// Its branch is being updated with the merged dependencies. `pushed_sha` is
+	// set right before the updated branch is pushed.
+	Updating { pushed_sha: Option<String> },
+	AwaitingChecks,
+	// The merge was requested to GitHub
+	Merging,
+	// GitHub merged it; what's left is cleaning up and handling its dependents
+	Merged,
+	// It failed and is being cleaned up
+	Failed { reason: String },
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[repr(C)]
 pub struct MergeRequest {
@@ -46,63 +79,146 @@ pub struct MergeRequest {
 	pub html_url: String,
 	pub requested_by: String,
 	pub dependencies: Option<Vec<Dependency>>,
+	// Overrides the repository's merge method (e.g. `bot merge rebase`)
+	pub merge_method: Option<MergeMethod>,
+	// Not known for the merges requested before v7.0 of the database
+	pub requested_at: Option<DateTime<Utc>>,
+	pub state: MergeState,
 }
 
+/// Verify a delivery's signature against each of the secrets, so that deliveries
+/// signed with either the old or the new secret are accepted while the secret
+/// is being rotated. The SHA-256 signature (x-hub-signature-256) is verified
+/// when it's present; the SHA-1 one (x-hub-signature) is only used as a
+/// fallback if `allow_sha1` is set.
 fn verify(
-	secret: &[u8],
+	secrets: &[String],
+	allow_sha1: bool,
+	sha256_signature: Option<&str>,
+	sha1_signature: Option<&str>,
 	msg: &[u8],
-	signature: &[u8],
-) -> Result<(), ring::error::Unspecified> {
-	let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
-	hmac::verify(&key, msg, signature)
+) -> Result<()> {
+	let (algorithm, signature) = match (sha256_signature, sha1_signature) {
+		(Some(signature), _) => {
+			(hmac::HMAC_SHA256, signature.trim_start_matches("sha256="))
+		}
+		(None, Some(signature)) if allow_sha1 => (
+			hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+			signature.trim_start_matches("sha1="),
+		),
+		(None, Some(_)) => return Err(Error::Message {
+			msg:
+				"Missing x-hub-signature-256 (SHA-1 signatures are not allowed)"
+					.to_owned(),
+		}),
+		(None, None) => {
+			return Err(Error::Message {
+				msg: "Missing x-hub-signature-256".to_owned(),
+			})
+		}
+	};
+	let signature =
+		base16::decode(signature.as_bytes()).ok().context(Message {
+			msg: "Error decoding the webhook signature".to_owned(),
+		})?;
+
+	if secrets.iter().any(|secret| {
+		let key = hmac::Key::new(algorithm, secret.trim().as_bytes());
+		hmac::verify(&key, msg, &signature).is_ok()
+	}) {
+		Ok(())
+	} else {
+		Err(Error::Message {
+			msg: "Validation signature does not match".to_owned(),
+		})
+	}
 }
 
-/// Receive a webhook and state object, acquire lock on state object.
+/// Receive a webhook and state object. Webhooks are stored and acknowledged
+/// right away, then processed by event_queue::run_worker.
 pub async fn webhook(
 	req: Request<Body>,
-	state: Arc<Mutex<AppState>>,
+	state: Arc<AppState>,
 ) -> Result<Response<Body>> {
 	if req.uri().path() == "/webhook" {
-		// lock here to prevent double merge requests being sent (which often happens when checks
-		// complete because we receive redundant status hooks).
-		let state = &*state.lock().await;
-		let sig = req
-			.headers()
-			.get("x-hub-signature")
-			.context(Message {
-				msg: "Missing x-hub-signature".to_owned(),
-			})?
-			.to_str()
-			.ok()
-			.context(Message {
-				msg: "Error parsing x-hub-signature".to_owned(),
-			})?
-			.to_string();
-
-		log::info!("Lock acquired for {:?}", sig);
-		if let Some((merge_cancel_outcome, err)) =
-			match webhook_inner(req, state).await {
-				Ok((merge_cancel_outcome, result)) => match result {
-					Ok(_) => None,
-					Err(err) => Some((merge_cancel_outcome, err)),
-				},
-				Err(err) => Some((MergeCancelOutcome::WasNotCancelled, err)),
-			} {
-			handle_error(merge_cancel_outcome, err, state).await
+		let state = &*state;
+		log::info!(
+			"Received webhook {:?}",
+			req.headers().get("x-github-delivery")
+		);
+		let status = match webhook_inner(req, state).await {
+			Ok(()) => StatusCode::OK,
+			Err(err) => {
+				// The delivery is only acknowledged once it's stored
+				let status = match err {
+					Error::Db { .. } | Error::Bincode { .. } => {
+						StatusCode::INTERNAL_SERVER_ERROR
+					}
+					_ => StatusCode::OK,
+				};
+				handle_error(MergeCancelOutcome::WasNotCancelled, err, state)
+					.await;
+				status
+			}
 		};
-		log::info!("Will release lock for {:?}", sig);
 
 		Response::builder()
-			.status(StatusCode::OK)
+			.status(status)
 			.body(Body::from(""))
 			.ok()
 			.context(Message {
 				msg: "Error building response".to_owned(),
 			})
-	} else if req.uri().path() == "/health" {
+	} else if req.uri().path().starts_with("/admin/") {
+		admin::handle_request(req, &state).await
+	} else if req.uri().path() == "/dashboard" {
+		if let Some(response) = admin::reject_unauthorized(&req, &state.config)
+		{
+			return response;
+		}
 		Response::builder()
 			.status(StatusCode::OK)
-			.body(Body::from("OK"))
+			.header("Content-Type", "text/html; charset=utf-8")
+			.body(Body::from(dashboard::render(&state).await))
+			.ok()
+			.context(Message {
+				msg: "Error building response".to_owned(),
+			})
+	} else if req.uri().path() == "/metrics" {
+		if let Some(response) =
+			admin::reject_unauthorized_metrics(&req, &state.config)
+		{
+			return response;
+		}
+		let (status, body) = match metrics::render(&state.db) {
+			Ok(metrics) => (StatusCode::OK, metrics),
+			Err(err) => {
+				log::error!("Failed to render the metrics due to {:?}", err);
+				(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+			}
+		};
+		Response::builder()
+			.status(status)
+			.header("Content-Type", metrics::CONTENT_TYPE)
+			.body(Body::from(body))
+			.ok()
+			.context(Message {
+				msg: "Error building response".to_owned(),
+			})
+	} else if req.uri().path() == "/health" || req.uri().path() == "/ready" {
+		let (is_healthy, report) = if req.uri().path() == "/health" {
+			health::liveness(&state)
+		} else {
+			health::readiness(&state).await
+		};
+		Response::builder()
+			.status(if is_healthy {
+				StatusCode::OK
+			} else {
+				StatusCode::SERVICE_UNAVAILABLE
+			})
+			.header("Content-Type", "application/json")
+			.body(Body::from(report.to_string()))
 			.ok()
 			.context(Message {
 				msg: "Healthcheck".to_owned(),
@@ -118,10 +234,11 @@ pub async fn webhook(
 	}
 }
 
+/// Verify the delivery's signature and store it for processing.
 pub async fn webhook_inner(
 	mut req: Request<Body>,
 	state: &AppState,
-) -> Result<(MergeCancelOutcome, Result<()>)> {
+) -> Result<()> {
 	let mut msg_bytes = vec![];
 	while let Some(item) = req.body_mut().next().await {
 		msg_bytes.extend_from_slice(&item.ok().context(Message {
@@ -129,37 +246,56 @@ pub async fn webhook_inner(
 		})?);
 	}
 
-	let sig = req
-		.headers()
-		.get("x-hub-signature")
-		.context(Message {
-			msg: "Missing x-hub-signature".to_string(),
-		})?
-		.to_str()
-		.ok()
-		.context(Message {
-			msg: "Error parsing x-hub-signature".to_owned(),
-		})?
-		.replace("sha1=", "");
-	let sig_bytes = base16::decode(sig.as_bytes()).ok().context(Message {
-		msg: "Error decoding x-hub-signature".to_owned(),
-	})?;
-
-	let AppState { config, .. } = state;
+	let AppState { config, db, .. } = state;
+
+	let header = |name: &str| {
+		req.headers()
+			.get(name)
+			.and_then(|value| value.to_str().ok())
+	};
 
 	verify(
-		config.webhook_secret.trim().as_bytes(),
+		&config.webhook_secrets,
+		config.webhook_allow_sha1,
+		header("x-hub-signature-256"),
+		header("x-hub-signature"),
 		&msg_bytes,
-		&sig_bytes,
-	)
-	.ok()
-	.context(Message {
-		msg: "Validation signature does not match".to_owned(),
-	})?;
-
-	log::info!("Parsing payload {}", String::from_utf8_lossy(&msg_bytes));
-	match serde_json::from_slice::<Payload>(&msg_bytes) {
-		Ok(payload) => Ok(handle_payload(payload, state).await),
+	)?;
+	let delivery_id =
+		event_queue::delivery_id(header("x-github-delivery"), &msg_bytes);
+	event_queue::enqueue(
+		db,
+		event_queue::delivery_ttl(config),
+		&delivery_id,
+		header("x-github-event"),
+		msg_bytes,
+	)?;
+
+	Ok(())
+}
+
+/// Parse a delivery's payload and act on it.
+pub async fn handle_event(
+	state: &AppState,
+	msg_bytes: &[u8],
+) -> (MergeCancelOutcome, Result<()>) {
+	log::info!("Parsing payload {}", String::from_utf8_lossy(msg_bytes));
+
+	// The requests made on behalf of the payload's repository should use the
+	// installation the payload was delivered for (see http.rs)
+	if let Ok(WebhookInstallation {
+		installation: Some(installation),
+		repository: Some(repository),
+	}) = serde_json::from_slice(msg_bytes)
+	{
+		state
+			.github_bot
+			.client
+			.register_installation(&repository.owner.login, installation.id);
+	}
+
+	match serde_json::from_slice::<Payload>(msg_bytes) {
+		Ok(payload) => handle_payload(payload, state).await,
 		Err(err) => {
 			// If this comment was originated from a Bot, then acting on it might make the bot
 			// to respond to itself recursively, as happened on
@@ -167,22 +303,25 @@ pub async fn webhook_inner(
 			// this error if it's known for sure it has been initiated only by a User comment.
 			let pr_details = serde_json::from_slice::<
 				DetectUserCommentPullRequest,
-			>(&msg_bytes)
+			>(msg_bytes)
 			.ok()
 			.and_then(|detected| detected.get_issue_details());
 
 			if let Some(pr_details) = pr_details {
-				Err(Error::Message {
-					msg: format!(
-						WEBHOOK_PARSING_ERROR_TEMPLATE!(),
-						err,
-						String::from_utf8_lossy(&msg_bytes)
-					),
-				}
-				.map_issue(pr_details))
+				(
+					MergeCancelOutcome::WasNotCancelled,
+					Err(Error::Message {
+						msg: format!(
+							WEBHOOK_PARSING_ERROR_TEMPLATE!(),
+							err,
+							String::from_utf8_lossy(msg_bytes)
+						),
+					}
+					.map_issue(pr_details)),
+				)
 			} else {
 				log::info!("Ignoring payload parsing error",);
-				Ok((MergeCancelOutcome::ShaNotFound, Ok(())))
+				(MergeCancelOutcome::ShaNotFound, Ok(()))
 			}
 		}
 	}
@@ -248,6 +387,31 @@ pub async fn handle_payload(
 			},
 			_ => (Ok(()), None),
 		},
+		Payload::PullRequest {
+			action: PullRequestAction::Labeled,
+			pull_request,
+			label: Some(label),
+			sender,
+		} => (
+			lock::with_locks(
+				vec![lock::pull_request_key(
+					&pull_request.base.repo.owner.login,
+					&pull_request.base.repo.name,
+					pull_request.number,
+				)],
+				handle_label(state, &pull_request, &label, &sender.login),
+			)
+			.await
+			.map_err(|err| {
+				err.map_issue((
+					pull_request.base.repo.owner.login.to_owned(),
+					pull_request.base.repo.name.to_owned(),
+					pull_request.number,
+				))
+			}),
+			None,
+		),
+		Payload::PullRequest { .. } => (Ok(()), None),
 		Payload::CommitStatus { sha, state: status } => (
 			match status {
 				StatusState::Unknown => Ok(()),
@@ -318,66 +482,75 @@ pub async fn handle_payload(
 		err
 	);
 
-	match state.db.get(sha.as_bytes()) {
-		Ok(Some(bytes)) => {
-			match bincode::deserialize::<MergeRequest>(&bytes).context(Bincode)
-			{
-				Ok(mr) => {
-					let merge_cancel_outcome = match cleanup_pr(
-						state,
-						&sha,
-						&mr.owner,
-						&mr.repo,
-						mr.number,
-						&PullRequestCleanupReason::Cancelled,
-					)
-					.await
-					{
-						Ok(_) => {
-							log::info!(
-								"Merge of {} (sha {}) was cancelled due to {:?}",
-								&mr.html_url,
-								sha,
-								err
-							);
-							MergeCancelOutcome::WasCancelled
-						}
-						Err(err) => {
-							log::error!(
-									"Failed to cancel merge of {} (sha {}) in handle_payload due to {:?}",
-									&mr.html_url,
-									sha,
-									err
-								);
-							MergeCancelOutcome::WasNotCancelled
-						}
-					};
-
-					(
-						merge_cancel_outcome,
-						Err(err.map_issue((mr.owner, mr.repo, mr.number))),
-					)
-				}
-				Err(db_err) => {
-					log::error!(
-						"Failed to parse {} from the database due to {:?}",
-						&sha,
-						db_err
-					);
-					(MergeCancelOutcome::WasNotCancelled, Err(err))
-				}
-			}
+	let mr = match db::get_merge_requests_by_sha(&state.db, &sha) {
+		Ok(mrs) => {
+			// Pull requests from different repositories might share the same head SHA,
+			// in which case the error's issue tells which one of them should be stopped
+			let failed_issue = match &err {
+				Error::WithIssue { issue, .. } => Some(issue),
+				_ => None,
+			};
+			mrs.into_iter().find(|mr| {
+				failed_issue
+					.map(|(owner, repo, number)| {
+						&mr.owner == owner
+							&& &mr.repo == repo && mr.number == *number
+					})
+					.unwrap_or(true)
+			})
 		}
-		Ok(None) => (MergeCancelOutcome::ShaNotFound, Err(err)),
 		Err(db_err) => {
 			log::info!(
 				"Failed to fetch {} from the database due to {:?}",
 				sha,
 				db_err
 			);
-			(MergeCancelOutcome::WasNotCancelled, Err(err))
+			return (MergeCancelOutcome::WasNotCancelled, Err(err));
 		}
-	}
+	};
+
+	let mr = match mr {
+		Some(mr) => mr,
+		None => return (MergeCancelOutcome::ShaNotFound, Err(err)),
+	};
+
+	let merge_cancel_outcome = match lock::with_locks(
+		vec![lock::pull_request_key(&mr.owner, &mr.repo, mr.number)],
+		cleanup_pr(
+			state,
+			&sha,
+			&mr.owner,
+			&mr.repo,
+			mr.number,
+			&PullRequestCleanupReason::Cancelled,
+		),
+	)
+	.await
+	{
+		Ok(_) => {
+			log::info!(
+				"Merge of {} (sha {}) was cancelled due to {:?}",
+				&mr.html_url,
+				sha,
+				err
+			);
+			MergeCancelOutcome::WasCancelled
+		}
+		Err(err) => {
+			log::error!(
+				"Failed to cancel merge of {} (sha {}) in handle_payload due to {:?}",
+				&mr.html_url,
+				sha,
+				err
+			);
+			MergeCancelOutcome::WasNotCancelled
+		}
+	};
+
+	(
+		merge_cancel_outcome,
+		Err(err.map_issue((mr.owner, mr.repo, mr.number))),
+	)
 }
 
 pub async fn get_latest_statuses_state(
@@ -500,17 +673,71 @@ pub async fn get_latest_checks_state(
 	)
 }
 
-/// Act on a status' outcome to decide on whether a PR relating to this SHA is ready to be merged
+/// Act on a status' outcome to decide on whether the PRs relating to this SHA are ready to be
+/// merged
 #[async_recursion]
 pub async fn checks_and_status(state: &AppState, sha: &str) -> Result<()> {
-	let AppState { db, github_bot, .. } = state;
+	let AppState { db, .. } = state;
 
 	log::info!("Checking for statuses of {}", sha);
 
-	let mr: MergeRequest = match db.get(sha.as_bytes()).context(Db)? {
-		Some(bytes) => bincode::deserialize(&bytes).context(Bincode)?,
-		None => return Ok(()),
-	};
+	let mut batched_queues = vec![];
+	for mr in db::get_merge_requests_by_sha(db, sha)? {
+		let batched_queue = lock::with_locks(
+			vec![lock::pull_request_key(&mr.owner, &mr.repo, mr.number)],
+			async {
+				// The merge request might have been handled by the time the lock was
+				// acquired, e.g. due to a duplicate status hook
+				match db::get_merge_request(db, &mr.owner, &mr.repo, mr.number)?
+				{
+					Some(mr) if mr.sha == sha => {
+						merge_request_checks_and_status(state, &mr).await
+					}
+					_ => {
+						log::info!(
+							"Skipping {} since its merge request for {} is no longer pending",
+							mr.html_url,
+							sha
+						);
+						Ok(None)
+					}
+				}
+			},
+		)
+		.await?;
+		if let Some(base_branch) = batched_queue {
+			batched_queues.push((mr.owner, mr.repo, base_branch));
+		}
+	}
+
+	// A batched queue's pull requests are processed together, so the queue is
+	// processed once the pull request's lock is released; otherwise another pull
+	// request of the queue whose status arrived concurrently could prevent it
+	// from being processed (see merge_batch::with_queue_locks)
+	batched_queues.dedup();
+	for (owner, repo, base_branch) in batched_queues {
+		merge_batch::process_queue(state, &owner, &repo, &base_branch).await?;
+	}
+
+	for batch in db::get_merge_batches(db)? {
+		if batch.candidate_sha == sha {
+			merge_batch::check_batch(state, &batch).await?;
+		}
+	}
+
+	Ok(())
+}
+
+/// Returns the base branch of the pull request's merge queue if its pull
+/// requests are merged in batches, in which case the queue should be processed
+/// once the pull request's lock is released (see lock.rs).
+async fn merge_request_checks_and_status(
+	state: &AppState,
+	mr: &MergeRequest,
+) -> Result<Option<String>> {
+	let AppState { db, github_bot, .. } = state;
+	let sha = &mr.sha;
+
 	let pr = github_bot
 		.pull_request(&mr.owner, &mr.repo, mr.number)
 		.await?;
@@ -523,7 +750,7 @@ pub async fn checks_and_status(state: &AppState, sha: &str) -> Result<()> {
 
 	match async {
 		if handle_merged_pr(state, &pr, &mr.requested_by).await? {
-			return Ok(());
+			return Ok(None);
 		}
 
 		if mr.sha != pr.head.sha {
@@ -533,9 +760,36 @@ pub async fn checks_and_status(state: &AppState, sha: &str) -> Result<()> {
 			});
 		}
 
+		let queue = db::get_merge_queue(
+			db,
+			&mr.owner,
+			&mr.repo,
+			&pr.base.ref_field,
+		)?;
+		if queue.position(mr.number).is_some()
+			&& merge_batch::batch_size(
+				state,
+				&mr.owner,
+				&mr.repo,
+				&queue.base_branch,
+			)
+			.await?
+			.is_some()
+		{
+			return Ok(Some(queue.base_branch));
+		}
+		if !queue.is_turn_of(mr.number) {
+			log::info!(
+				"{} is waiting for its turn in the merge queue of {}",
+				pr.html_url,
+				queue.base_branch
+			);
+			return Ok(None);
+		}
+
 		if !ready_to_merge(github_bot, &pr).await? {
 			log::info!("{} is not ready", pr.html_url);
-			return Ok(());
+			return Ok(None);
 		}
 
 		check_merge_is_allowed(state, &pr, &mr.requested_by, &[]).await?;
@@ -581,7 +835,7 @@ pub async fn checks_and_status(state: &AppState, sha: &str) -> Result<()> {
 						pr.html_url,
 						dependency.html_url
 					);
-					return Ok(());
+					return Ok(None);
 				};
 			}
 		}
@@ -589,7 +843,7 @@ pub async fn checks_and_status(state: &AppState, sha: &str) -> Result<()> {
 		log::info!("Updating companion {} before merge", pr.html_url);
 		update_then_merge(
 			state,
-			&mr,
+			mr,
 			&WaitToMergeMessage::None,
 			// No need to register the MR again: we already know it is registered because
 			// it was fetched from the database at the start
@@ -599,11 +853,12 @@ pub async fn checks_and_status(state: &AppState, sha: &str) -> Result<()> {
 		)
 		.await?;
 
-		Ok(())
+		Ok(None)
 	}
 	.await
 	{
-		Ok(_) | Err(Error::MergeFailureWillBeSolvedLater { .. }) => Ok(()),
+		Ok(base_branch) => Ok(base_branch),
+		Err(Error::MergeFailureWillBeSolvedLater { .. }) => Ok(None),
 		Err(err) => Err(err.map_issue((
 			pr.base.repo.owner.login,
 			pr.base.repo.name,
@@ -672,10 +927,8 @@ pub async fn handle_dependents_after_merge(
 	*/
 	let mut processed_mrs = vec![];
 	'db_iteration_loop: loop {
-		let db_iter = db.iterator(rocksdb::IteratorMode::Start);
-		'to_next_item: for (key, value) in db_iter {
-			match bincode::deserialize::<MergeRequest>(&value).context(Bincode)
-			{
+		'to_next_item: for (key, mr) in db::iter_merge_requests(db) {
+			match mr {
 				Ok(mut mr) => {
 					if processed_mrs.iter().any(|prev_mr: &MergeRequest| {
 						mr.owner == prev_mr.owner
@@ -751,13 +1004,7 @@ pub async fn handle_dependents_after_merge(
 							}
 							LivenessOutcome::Updated
 							| LivenessOutcome::AliveNeedsUpdate => {
-								if let Err(err) = db
-									.put(
-										&key,
-										bincode::serialize(&mr)
-											.context(Bincode)?,
-									)
-									.context(Db)
+								if let Err(err) = db::put_merge_request(db, &mr)
 								{
 									log::error!(
 										"Failed to update database references after merge of {} in dependent {} due to {:?}",
@@ -798,7 +1045,9 @@ pub async fn handle_dependents_after_merge(
 								}
 							}
 							LivenessOutcome::Dangling => {
-								let _ = db.delete(&key);
+								let _ = db::delete_merge_request(
+									db, &mr.owner, &mr.repo, mr.number,
+								);
 							}
 						};
 
@@ -843,45 +1092,58 @@ pub async fn handle_dependents_after_merge(
 					.any(|dependency| dependency.repo != pr.base.repo.name)
 			})
 			.unwrap_or(false);
-		match update_then_merge(
-			state,
-			dependent,
-			&WaitToMergeMessage::Default,
-			// The dependent should always be registered to the database as a pending
-			// item since one of its dependencies just got merged, therefore it becomes
-			// eligible for merge in the future
-			true,
-			!depends_on_another_pr,
-		)
-		.await
-		{
-			Ok(updated_sha) => {
-				if let Some(updated_sha) = updated_sha {
-					updated_dependents.push((updated_sha, dependent))
-				}
-			}
-			Err(err) => {
-				let _ = cleanup_pr(
-					state,
-					&dependent.sha,
-					&dependent.owner,
-					&dependent.repo,
-					dependent.number,
-					&PullRequestCleanupReason::Error,
-				)
-				.await;
-				handle_error(
-					MergeCancelOutcome::WasCancelled,
-					err.map_issue((
-						(&dependent.owner).into(),
-						(&dependent.repo).into(),
-						dependent.number,
-					)),
+		lock::with_locks(
+			vec![lock::pull_request_key(
+				&dependent.owner,
+				&dependent.repo,
+				dependent.number,
+			)],
+			async {
+				match update_then_merge(
 					state,
+					dependent,
+					&WaitToMergeMessage::Default,
+					// The dependent should always be registered to the database as a pending
+					// item since one of its dependencies just got merged, therefore it becomes
+					// eligible for merge in the future
+					true,
+					!depends_on_another_pr,
 				)
-				.await;
-			}
-		}
+				.await
+				{
+					Ok(updated_sha) => {
+						if let Some(updated_sha) = updated_sha {
+							updated_dependents.push((updated_sha, dependent))
+						}
+					}
+					Err(err) => {
+						// Transient failures are attempted again by the poll
+						if err.stops_merge_attempt() {
+							let _ = cleanup_pr(
+								state,
+								&dependent.sha,
+								&dependent.owner,
+								&dependent.repo,
+								dependent.number,
+								&PullRequestCleanupReason::Error,
+							)
+							.await;
+						}
+						handle_error(
+							MergeCancelOutcome::WasCancelled,
+							err.map_issue((
+								(&dependent.owner).into(),
+								(&dependent.repo).into(),
+								dependent.number,
+							)),
+							state,
+						)
+						.await;
+					}
+				}
+			},
+		)
+		.await;
 	}
 
 	/*
@@ -902,9 +1164,8 @@ pub async fn handle_dependents_after_merge(
 		the HEAD of the PR ourselves through the update, which is safe).
 	*/
 	let mut dependents_to_check = HashMap::new();
-	let db_iter = db.iterator(rocksdb::IteratorMode::Start);
-	for (key, value) in db_iter {
-		match bincode::deserialize::<MergeRequest>(&value).context(Bincode) {
+	for (key, mr) in db::iter_merge_requests(db) {
+		match mr {
 			Ok(mut dependent_of_dependent) => {
 				let mut should_be_included_in_check = false;
 				let mut record_needs_update = false;
@@ -948,13 +1209,8 @@ pub async fn handle_dependents_after_merge(
 					};
 
 				if record_needs_update {
-					if let Err(err) = db
-						.put(
-							&key,
-							bincode::serialize(&dependent_of_dependent)
-								.context(Bincode)?,
-						)
-						.context(Db)
+					if let Err(err) =
+						db::put_merge_request(db, &dependent_of_dependent)
 					{
 						log::error!(
 							"Failed to update a dependent to {:?} due to {:?}",
@@ -1016,15 +1272,17 @@ pub async fn handle_dependents_after_merge(
 	*/
 	for dependent in dependents_to_check.into_values() {
 		if let Err(err) = checks_and_status(state, &dependent.sha).await {
-			let _ = cleanup_pr(
-				state,
-				&dependent.sha,
-				&dependent.owner,
-				&dependent.repo,
-				dependent.number,
-				&PullRequestCleanupReason::Error,
-			)
-			.await;
+			if err.stops_merge_attempt() {
+				let _ = cleanup_pr(
+					state,
+					&dependent.sha,
+					&dependent.owner,
+					&dependent.repo,
+					dependent.number,
+					&PullRequestCleanupReason::Error,
+				)
+				.await;
+			}
 			handle_error(MergeCancelOutcome::WasCancelled, err, state).await;
 		}
 	}
@@ -1038,7 +1296,11 @@ async fn handle_command(
 	pr: &PullRequest,
 	requested_by: &str,
 ) -> Result<()> {
-	let AppState { github_bot, .. } = state;
+	let AppState {
+		db,
+		github_bot,
+		config,
+	} = state;
 
 	match cmd {
 		// This command marks the start of the chain of merges. The PR where the
@@ -1060,16 +1322,93 @@ async fn handle_command(
 				// This is the starting point of the merge chain, hence why always no
 				// dependencies are registered for it upfront
 				dependencies: None,
+				merge_method: cmd.merge_method(),
+				requested_at: Some(Utc::now()),
+				state: MergeState::AwaitingChecks,
 			};
 
 			check_merge_is_allowed(state, pr, requested_by, &[]).await?;
 
 			match cmd {
-				MergeCommentCommand::Normal => {
+				MergeCommentCommand::Normal(_)
+				| MergeCommentCommand::Priority(_) => {
+					let is_priority =
+						matches!(cmd, MergeCommentCommand::Priority(_));
+					if is_priority
+						&& !merge_queue::can_prioritize(config, requested_by)
+					{
+						return Err(Error::Message {
+							msg: format!(
+								"@{} is not allowed to prioritize merges. Only the following users can: {}",
+								requested_by,
+								config.merge_priority_maintainers.join(", ")
+							),
+						});
+					}
+
+					let queue_entry = MergeQueueEntry {
+						number: pr.number,
+						html_url: (&pr.html_url).into(),
+						requested_by: requested_by.into(),
+						is_priority,
+					};
+
+					let is_batched = merge_batch::batch_size(
+						state,
+						&pr.base.repo.owner.login,
+						&pr.base.repo.name,
+						&pr.base.ref_field,
+					)
+					.await?
+					.is_some();
+					// The pull request is registered and queued before it's merged
+					// so that a `bot merge` on another pull request with the same
+					// base branch, which might be handled concurrently, finds it
+					// ahead in the queue (see merge_queue.rs)
+					register_merge_request(state, &mr).await?;
+					let (position, queue_len) = merge_queue::enqueue(
+						db,
+						&pr.base.repo.owner.login,
+						&pr.base.repo.name,
+						&pr.base.ref_field,
+						queue_entry,
+					)?;
+					if is_batched || position > 1 {
+						let msg = format!(
+							"Queued for merge at position {} of {} in the merge queue for `{}`. {}",
+							position,
+							queue_len,
+							pr.base.ref_field,
+							if is_batched {
+								"This PR will be tested in a batch together with other queued PRs once its checks pass."
+							} else {
+								"This PR will be merged once the ones ahead of it are done."
+							}
+						);
+						wait_to_merge(
+							state,
+							&mr,
+							&WaitToMergeMessage::Custom(&msg),
+						)
+						.await?;
+						if is_batched {
+							merge_batch::process_queue(
+								state,
+								&pr.base.repo.owner.login,
+								&pr.base.repo.name,
+								&pr.base.ref_field,
+							)
+							.await?;
+						}
+						return Ok(());
+					}
+
 					if ready_to_merge(github_bot, pr).await? {
-						match merge(state, pr, requested_by).await? {
-							// If the merge failure will be solved later, then register the PR in the database so that
-							// it'll eventually resume processing when later statuses arrive
+						match merge(state, pr, requested_by, mr.merge_method)
+							.await?
+						{
+							// If the merge failure will be solved later, then the PR stays registered and queued so
+							// that it'll eventually resume processing when later statuses arrive
 							Err(Error::MergeFailureWillBeSolvedLater {
 								msg,
 							}) => {
@@ -1098,8 +1437,10 @@ async fn handle_command(
 						return Ok(());
 					}
 				}
-				MergeCommentCommand::Force => {
-					match merge(state, pr, requested_by).await? {
+				MergeCommentCommand::Force(_) => {
+					match merge(state, pr, requested_by, mr.merge_method)
+						.await?
+					{
 						// Even if the merge failure can be solved later, it does not matter because `merge force` is
 						// supposed to be immediate. We should give up here and yield the error message.
 						Err(Error::MergeFailureWillBeSolvedLater { msg }) => {
@@ -1144,13 +1485,30 @@ async fn handle_command(
 
 			Ok(())
 		}
-		CommentCommand::Rebase => {
+		CommentCommand::Rebase | CommentCommand::Update => {
+			let strategy = if let CommentCommand::Update = cmd {
+				RebaseStrategy::Merge
+			} else {
+				fetch_repository_config(
+					github_bot,
+					&pr.base.repo.owner.login,
+					&pr.base.repo.name,
+					&pr.base.ref_field,
+				)
+				.await?
+				.rebase_strategy
+				.unwrap_or_default()
+			};
+
 			if let Err(err) = github_bot
 				.create_issue_comment(
 					&pr.base.repo.owner.login,
 					&pr.base.repo.name,
 					pr.number,
-					"Rebasing",
+					match strategy {
+						RebaseStrategy::Rebase => "Rebasing",
+						RebaseStrategy::Merge => "Updating",
+					},
 				)
 				.await
 			{
@@ -1161,15 +1519,92 @@ async fn handle_command(
 				);
 			}
 
-			rebase(
-				github_bot,
+			let msg = lock::with_locks(
+				vec![lock::clone_key(
+					&pr.base.repo.owner.login,
+					&pr.base.repo.name,
+				)],
+				rebase(state, pr, strategy),
+			)
+			.await?;
+			if let Err(err) = github_bot
+				.create_issue_comment(
+					&pr.base.repo.owner.login,
+					&pr.base.repo.name,
+					pr.number,
+					&msg,
+				)
+				.await
+			{
+				log::error!(
+					"Failed to post comment on {} due to {}",
+					pr.html_url,
+					err
+				);
+			}
+
+			Ok(())
+		}
+		CommentCommand::Queue(cmd) => {
+			let queue = db::get_merge_queue(
+				db,
 				&pr.base.repo.owner.login,
 				&pr.base.repo.name,
-				&pr.head.repo.owner.login,
-				&pr.head.repo.name,
-				&pr.head.ref_field,
-			)
-			.await
+				&pr.base.ref_field,
+			)?;
+
+			let msg = match cmd {
+				QueueCommentCommand::Show => {
+					if queue.entries.is_empty() {
+						format!(
+							"The merge queue for `{}` is empty.",
+							queue.base_branch
+						)
+					} else {
+						let entries = queue
+							.entries
+							.iter()
+							.enumerate()
+							.map(|(index, entry)| {
+								format!(
+									"{}. {} (requested by {})",
+									index + 1,
+									entry.html_url,
+									entry.requested_by
+								)
+							})
+							.collect::<Vec<_>>()
+							.join("\n");
+						format!(
+							"Merge queue for `{}`:\n\n{}",
+							queue.base_branch, entries
+						)
+					}
+				}
+				QueueCommentCommand::Position => {
+					match queue.position(pr.number) {
+						Some(position) => format!(
+							"This PR is at position {} of {} in the merge queue for `{}`.",
+							position,
+							queue.entries.len(),
+							queue.base_branch
+						),
+						None => format!(
+							"This PR is not in the merge queue for `{}`.",
+							queue.base_branch
+						),
+					}
+				}
+			};
+
+			github_bot
+				.create_issue_comment(
+					&pr.base.repo.owner.login,
+					&pr.base.repo.name,
+					pr.number,
+					&msg,
+				)
+				.await
 		}
 	}
 }
@@ -1192,24 +1627,55 @@ async fn handle_comment(
 	};
 	log::info!("{:?} requested by {} in {}", cmd, requested_by, html_url);
 
+	let owner = match owner_from_html_url(html_url).context(Message {
+		msg: format!("Failed parsing owner in url: {}", html_url),
+	}) {
+		Ok(owner) => owner,
+		Err(err) => return (None, Err(err)),
+	};
+	let repo = match repo_url.rsplit('/').next().context(Message {
+		msg: format!("Failed parsing repo name in url: {}", repo_url),
+	}) {
+		Ok(repo) => repo,
+		Err(err) => return (None, Err(err)),
+	};
+
+	lock::with_locks(vec![lock::pull_request_key(owner, repo, number)], async {
+		handle_comment_locked(cmd, requested_by, owner, repo, number, state)
+			.await
+	})
+	.await
+}
+
+/// Handle the comment's command while holding the pull request's lock.
+async fn handle_comment_locked(
+	cmd: CommentCommand,
+	requested_by: &str,
+	owner: &str,
+	repo: &str,
+	number: i64,
+	state: &AppState,
+) -> (Option<String>, Result<()>) {
 	let AppState {
 		github_bot, config, ..
 	} = state;
 
-	let (owner, repo, pr) = match async {
-		let owner = owner_from_html_url(html_url).context(Message {
-			msg: format!("Failed parsing owner in url: {}", html_url),
-		})?;
-
-		let repo = repo_url.rsplit('/').next().context(Message {
-			msg: format!("Failed parsing repo name in url: {}", repo_url),
-		})?;
-
+	let pr = match async {
 		if !config.disable_org_check {
 			github_bot.org_member(owner, requested_by).await?;
 		}
 
-		if let CommentCommand::Merge(_) = cmd {
+		let pr = github_bot.pull_request(owner, repo, number).await?;
+
+		let pr = if let CommentCommand::Merge(_) = cmd {
+			let repository_config = fetch_repository_config(
+				github_bot,
+				owner,
+				repo,
+				&pr.base.ref_field,
+			)
+			.await?;
+
 			// We've noticed the bot failing for no human-discernable reason when, for instance, it
 			// complained that the pull request was not mergeable when, in fact, it seemed to be, if one
 			// were to guess what the state of the Github API was at the time the response was received with
@@ -1220,12 +1686,19 @@ async fn handle_comment(
 			// As a workaround we'll wait for long enough so that Github hopefully has time to update the
 			// API and make our merges succeed. A proper workaround would also entail retrying every X
 			// seconds for recoverable errors such as "required statuses are missing or pending".
-			delay_for(Duration::from_millis(config.merge_command_delay)).await;
-		};
+			delay_for(Duration::from_millis(
+				repository_config
+					.merge_command_delay
+					.unwrap_or(config.merge_command_delay),
+			))
+			.await;
 
-		let pr = github_bot.pull_request(owner, repo, number).await?;
+			github_bot.pull_request(owner, repo, number).await?
+		} else {
+			pr
+		};
 
-		Ok((owner, repo, pr))
+		Ok(pr)
 	}
 	.await
 	{
@@ -1247,6 +1720,79 @@ async fn handle_comment(
 	(sha, result)
 }
 
+/// Act on labels added to pull requests. The merge priority label is the
+/// equivalent of `bot merge priority` for pull requests which are already in the
+/// merge queue.
+async fn handle_label(
+	state: &AppState,
+	pr: &PullRequest,
+	label: &Label,
+	sender: &str,
+) -> Result<()> {
+	let AppState {
+		db,
+		github_bot,
+		config,
+	} = state;
+
+	if label.name != config.merge_priority_label {
+		return Ok(());
+	}
+	log::info!("{} labeled {} as {}", sender, pr.html_url, label.name);
+
+	if !merge_queue::can_prioritize(config, sender) {
+		return Err(Error::Message {
+			msg: format!(
+				"@{} is not allowed to prioritize merges, therefore the \"{}\" label has no effect. Only the following users can: {}",
+				sender,
+				label.name,
+				config.merge_priority_maintainers.join(", ")
+			),
+		});
+	}
+
+	let queue = db::get_merge_queue(
+		db,
+		&pr.base.repo.owner.login,
+		&pr.base.repo.name,
+		&pr.base.ref_field,
+	)?;
+	let entry = match queue.position(pr.number) {
+		Some(position) => queue.entries[position - 1].clone(),
+		None => {
+			log::info!(
+				"Ignoring the \"{}\" label on {} because it's not in the merge queue",
+				label.name,
+				pr.html_url
+			);
+			return Ok(());
+		}
+	};
+
+	let (position, queue_len) = merge_queue::enqueue(
+		db,
+		&pr.base.repo.owner.login,
+		&pr.base.repo.name,
+		&pr.base.ref_field,
+		MergeQueueEntry {
+			is_priority: true,
+			..entry
+		},
+	)?;
+
+	github_bot
+		.create_issue_comment(
+			&pr.base.repo.owner.login,
+			&pr.base.repo.name,
+			pr.number,
+			&format!(
+				"Prioritized by @{}: this PR is now at position {} of {} in the merge queue for `{}`.",
+				sender, position, queue_len, pr.base.ref_field
+			),
+		)
+		.await
+}
+
 pub async fn check_merge_is_allowed(
 	state: &AppState,
 	pr: &PullRequest,
@@ -1315,8 +1861,7 @@ async fn register_merge_request(
 	let AppState { db, .. } = state;
 	let MergeRequest { sha, .. } = mr;
 	log::info!("Registering merge request (sha: {}): {:?}", sha, mr);
-	db.put(sha.as_bytes(), bincode::serialize(mr).context(Bincode)?)
-		.context(Db)
+	db::put_merge_request(db, mr)
 }
 
 pub enum WaitToMergeMessage<'a> {
@@ -1405,6 +1950,7 @@ pub enum PullRequestCleanupReason<'a> {
 
 // Removes a pull request from the database (e.g. when it has been merged) and
 // executes side-effects related to the kind of trigger for this function
+#[async_recursion]
 pub async fn cleanup_pr(
 	state: &AppState,
 	key_to_guarantee_deleted: &str,
@@ -1415,38 +1961,48 @@ pub async fn cleanup_pr(
 ) -> Result<()> {
 	let AppState { db, .. } = state;
 
-	let mut related_dependents = HashMap::new();
+	match db::delete_merge_request(db, owner, repo, number) {
+		Ok(Some(mr)) => {
+			log::info!(
+				"Cleaning up {:?} due to key {} of {}/{}/pull/{}",
+				mr,
+				key_to_guarantee_deleted,
+				owner,
+				repo,
+				number
+			);
+		}
+		Ok(None) => {}
+		Err(err) => {
+			log::error!(
+				"Failed to delete {}/{}/pull/{} during cleanup_pr due to {:?}",
+				owner,
+				repo,
+				number,
+				err
+			);
+		}
+	}
 
-	let db_iter = db.iterator(rocksdb::IteratorMode::Start);
-	'to_next_db_item: for (key, value) in db_iter {
-		match bincode::deserialize::<MergeRequest>(&value).context(Bincode) {
-			Ok(mr) => {
-				if mr.owner == owner && mr.repo == repo && mr.number == number {
-					log::info!(
-						"Cleaning up {:?} due to key {} of {}/{}/pull/{}",
-						mr,
-						key_to_guarantee_deleted,
-						owner,
-						repo,
-						number
-					);
+	// The merge queue only has to move on when the pull request is done with,
+	// not when it's merely updated
+	let advanced_queues = match reason {
+		PullRequestCleanupReason::AfterSHAUpdate(_) => vec![],
+		_ => merge_queue::dequeue(db, owner, repo, number)?,
+	};
 
-					if let Err(err) = db.delete(&key) {
-						log::error!(
-							"Failed to delete {} during cleanup_pr due to {:?}",
-							String::from_utf8_lossy(&key),
-							err
-						);
-					}
-				}
+	let mut related_dependents = HashMap::new();
 
+	'to_next_db_item: for (key, mr) in db::iter_merge_requests(db) {
+		match mr {
+			Ok(mr) => {
 				if let Some(dependencies) = &mr.dependencies {
 					for dependency in dependencies.iter() {
 						if dependency.owner == owner
 							&& dependency.repo == repo && dependency.number
 							== number
 						{
-							related_dependents.insert((&mr.sha).clone(), mr);
+							related_dependents.insert(mr.sha.clone(), mr);
 							continue 'to_next_db_item;
 						}
 					}
@@ -1462,12 +2018,12 @@ pub async fn cleanup_pr(
 		}
 	}
 
-	// Sanity check: the key should have actually been deleted
-	if db.get(key_to_guarantee_deleted).context(Db)?.is_some() {
+	// Sanity check: the record should have actually been deleted
+	if db::get_merge_request(db, owner, repo, number)?.is_some() {
 		return Err(Error::Message {
 			msg: format!(
-				"Key {} was not deleted from the database",
-				key_to_guarantee_deleted
+				"Merge request of {}/{}/pull/{} (key {}) was not deleted from the database",
+				owner, repo, number, key_to_guarantee_deleted
 			),
 		});
 	}
@@ -1534,7 +2090,8 @@ pub async fn cleanup_pr(
 					&dependent.repo,
 					dependent.number,
 					reason,
-				);
+				)
+				.await;
 			}
 		}
 		PullRequestCleanupReason::AfterSHAUpdate(updated_sha) => {
@@ -1565,11 +2122,7 @@ pub async fn cleanup_pr(
 					};
 
 				if was_updated {
-					db.put(
-						dependent.sha.as_bytes(),
-						bincode::serialize(&dependent).context(Bincode)?,
-					)
-					.context(Db)?;
+					db::put_merge_request(db, &dependent)?;
 				}
 			}
 		}
@@ -1579,6 +2132,10 @@ pub async fn cleanup_pr(
 	log::info!("Cleaning up cleanup_pr recursion prevention lock's entries");
 	CLEANUP_PR_RECURSION_PREVENTION.lock().clear();
 
+	for queue in advanced_queues {
+		merge_queue::process_head(state, &queue).await?;
+	}
+
 	Ok(())
 }
 
@@ -1586,12 +2143,36 @@ pub async fn merge(
 	state: &AppState,
 	pr: &PullRequest,
 	requested_by: &str,
+	merge_method: Option<MergeMethod>,
 ) -> Result<Result<()>> {
 	if handle_merged_pr(state, pr, requested_by).await? {
 		return Ok(Ok(()));
 	}
 
-	let AppState { github_bot, .. } = state;
+	let AppState { db, github_bot, .. } = state;
+
+	let repository_config = fetch_repository_config(
+		github_bot,
+		&pr.base.repo.owner.login,
+		&pr.base.repo.name,
+		&pr.base.ref_field,
+	)
+	.await?;
+	let merge_method = merge_method
+		.or(repository_config.merge_method)
+		.unwrap_or_default();
+	let render = |template: &Option<String>| {
+		template
+			.as_ref()
+			.filter(|_| merge_method != MergeMethod::Rebase)
+			.map(|template| render_merge_template(template, pr, requested_by))
+	};
+	let commit_title = render(&repository_config.merge_commit_title);
+	let commit_message = render(&repository_config.merge_commit_message);
+
+	let owner = &pr.base.repo.owner.login;
+	let repo = &pr.base.repo.name;
+	merge_state::transition(db, owner, repo, pr.number, MergeState::Merging)?;
 
 	let err = match github_bot
 		.merge_pull_request(
@@ -1599,11 +2180,29 @@ pub async fn merge(
 			&pr.base.repo.name,
 			pr.number,
 			&pr.head.sha,
+			merge_method,
+			commit_title.as_deref(),
+			commit_message.as_deref(),
 		)
 		.await
 	{
 		Ok(_) => {
 			log::info!("{} merged successfully.", pr.html_url);
+			metrics::record_merge(true);
+			// Not worth failing over since the cleanup follows right away
+			if let Err(err) = merge_state::transition(
+				db,
+				owner,
+				repo,
+				pr.number,
+				MergeState::Merged,
+			) {
+				log::error!(
+					"Failed to record the merge of {}: {}",
+					pr.html_url,
+					err
+				);
+			}
 			// Merge succeeded! Now clean it from the database
 			if let Err(err) = cleanup_pr(
 				state,
@@ -1622,9 +2221,30 @@ pub async fn merge(
 			};
 			return Ok(Ok(()));
 		}
-		Err(err) => err,
+		Err(err) => {
+			metrics::record_merge(false);
+			err
+		}
 	};
 
+	let result = merge_failure(err);
+	if let Err(ref err) = result {
+		merge_state::fail(db, owner, repo, pr.number, err)?;
+	} else {
+		merge_state::transition(
+			db,
+			owner,
+			repo,
+			pr.number,
+			MergeState::AwaitingChecks,
+		)?;
+	}
+	result
+}
+
+/// Tell whether a failure to merge will be solved later (i.e. once the pending
+/// statuses are delivered), in which case the inner result is the error.
+fn merge_failure(err: Error) -> Result<Result<()>> {
 	let msg = match err {
 		Error::Response {
 			ref status,
@@ -1669,6 +2289,14 @@ pub async fn merge(
 	Err(Error::Message { msg: msg.into() })
 }
 
+fn format_file_list(files: &[String]) -> String {
+	files
+		.iter()
+		.map(|file| format!("- `{}`", file))
+		.collect::<Vec<_>>()
+		.join("\n")
+}
+
 fn format_error(_state: &AppState, err: Error) -> String {
 	match err {
 		Error::Response {
@@ -1679,6 +2307,27 @@ fn format_error(_state: &AppState, err: Error) -> String {
 			status,
 			html_escape::encode_safe(&body.to_string())
 		),
+		Error::MergeConflict {
+			ref base_branch,
+			ref files,
+		} => format!(
+			"Merging `{}` into this PR's branch failed due to conflicts in the following files:\n\n{}\n\nPlease resolve them manually.",
+			base_branch,
+			format_file_list(files)
+		),
+		Error::RebaseConflict {
+			ref base_branch,
+			ref commit_sha,
+			ref commit_subject,
+			ref files,
+		} => format!(
+			"Rebasing this PR's branch onto `{}` failed because commit {} ({}) conflicts in the following files:\n\n{}\n\nThe commits after it were not attempted. Please rebase manually or use `bot update` to merge `{}` instead.",
+			base_branch,
+			commit_sha,
+			html_escape::encode_text(commit_subject),
+			format_file_list(files),
+			base_branch
+		),
 		_ => format!("{}", err),
 	}
 }
@@ -1690,7 +2339,9 @@ pub async fn handle_error(
 ) {
 	log::info!("handle_error: {}", err);
 	match err {
-		Error::MergeFailureWillBeSolvedLater { .. } => (),
+		// Neither of these stop the merge, which is attempted again later on
+		Error::MergeFailureWillBeSolvedLater { .. }
+		| Error::Transient { .. } => (),
 		err => {
 			if let Error::WithIssue {
 				source,
@@ -1699,7 +2350,8 @@ pub async fn handle_error(
 			} = err
 			{
 				match *source {
-					Error::MergeFailureWillBeSolvedLater { .. } => (),
+					Error::MergeFailureWillBeSolvedLater { .. }
+					| Error::Transient { .. } => (),
 					err => {
 						let msg = {
 							let description = format_error(state, err);
@@ -1726,3 +2378,77 @@ pub async fn handle_error(
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const BODY: &[u8] = br#"{"action":"created"}"#;
+
+	fn sign(algorithm: hmac::Algorithm, secret: &str, msg: &[u8]) -> String {
+		let key = hmac::Key::new(algorithm, secret.as_bytes());
+		base16::encode_lower(hmac::sign(&key, msg).as_ref())
+	}
+
+	fn sha256_signature(secret: &str, msg: &[u8]) -> String {
+		format!("sha256={}", sign(hmac::HMAC_SHA256, secret, msg))
+	}
+
+	fn sha1_signature(secret: &str, msg: &[u8]) -> String {
+		format!(
+			"sha1={}",
+			sign(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret, msg)
+		)
+	}
+
+	#[test]
+	fn test_verify_sha256() {
+		let secrets = vec!["secret".to_owned()];
+		let signature = sha256_signature("secret", BODY);
+
+		assert!(verify(&secrets, false, Some(&signature), None, BODY).is_ok());
+
+		let tampered = br#"{"action":"deleted"}"#;
+		assert!(
+			verify(&secrets, false, Some(&signature), None, tampered).is_err()
+		);
+
+		let wrong_secret = sha256_signature("other", BODY);
+		assert!(
+			verify(&secrets, false, Some(&wrong_secret), None, BODY).is_err()
+		);
+	}
+
+	#[test]
+	fn test_verify_with_rotated_secrets() {
+		let secrets = vec!["new".to_owned(), "old".to_owned()];
+		for secret in &["new", "old"] {
+			let signature = sha256_signature(secret, BODY);
+			assert!(
+				verify(&secrets, false, Some(&signature), None, BODY).is_ok()
+			);
+		}
+		let signature = sha256_signature("older", BODY);
+		assert!(verify(&secrets, false, Some(&signature), None, BODY).is_err());
+	}
+
+	#[test]
+	fn test_verify_sha1_fallback() {
+		let secrets = vec!["secret".to_owned()];
+		let sha1 = sha1_signature("secret", BODY);
+
+		assert!(verify(&secrets, false, None, Some(&sha1), BODY).is_err());
+		assert!(verify(&secrets, true, None, Some(&sha1), BODY).is_ok());
+		assert!(verify(&secrets, true, None, None, BODY).is_err());
+
+		let tampered = br#"{"action":"deleted"}"#;
+		assert!(verify(&secrets, true, None, Some(&sha1), tampered).is_err());
+
+		// The SHA-256 signature takes precedence when it's present
+		let wrong_sha256 = sha256_signature("other", BODY);
+		assert!(
+			verify(&secrets, true, Some(&wrong_sha256), Some(&sha1), BODY)
+				.is_err()
+		);
+	}
+}
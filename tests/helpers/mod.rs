@@ -1,7 +1,8 @@
 use std::fs::{self, remove_dir_all, remove_file, File};
-use std::io::Read;
+use std::io::{Read, Seek, SeekFrom};
 use std::net::TcpListener;
 use std::path::{Path, PathBuf};
+use tempfile::TempDir;
 
 pub mod cmd;
 pub mod constants;
@@ -10,24 +11,59 @@ pub mod setup;
 use cmd::exec;
 
 pub fn get_available_port() -> Option<u16> {
-	for port in 1025..65535 {
-		if TcpListener::bind(("127.0.0.1", port)).is_ok() {
-			return Some(port);
-		}
-	}
+	(1025..65535).find(|port| TcpListener::bind(("127.0.0.1", *port)).is_ok())
+}
+
+lazy_static::lazy_static! {
+	// The logger can only be started once per process, therefore all the tests
+	// share the same log file. The tests are run sequentially (see
+	// run_integration_tests.sh) and each one only reads what was logged since it
+	// started (see log_file_len).
+	static ref LOG_DIR: TempDir = {
+		let log_dir = tempfile::tempdir().unwrap();
+		flexi_logger::Logger::with_env_or_str("info")
+			.log_to_file()
+			.directory(log_dir.path().to_path_buf())
+			.duplicate_to_stdout(flexi_logger::Duplicate::All)
+			.start()
+			.unwrap();
+		log_dir
+	};
+}
+
+// The file is only created once something is logged
+fn log_file() -> Option<PathBuf> {
+	LOG_DIR
+		.path()
+		.read_dir()
+		.unwrap()
+		.next()
+		.map(|entry| entry.unwrap().path())
+}
 
-	None
+/// Start the logger if needed and return the current length of the log file,
+/// which marks where the logs of the calling test start.
+pub fn log_file_len() -> u64 {
+	log_file()
+		.map(|log_file| fs::metadata(log_file).unwrap().len())
+		.unwrap_or(0)
 }
 
-pub fn read_snapshot(log_dir: PathBuf, texts_to_hide: &[&str]) -> String {
-	let entry = log_dir.read_dir().unwrap().next().unwrap().unwrap();
-	let mut file = File::open(entry.path()).unwrap();
+// Not every test compares its logs to a snapshot
+#[allow(dead_code)]
+pub fn read_snapshot(log_start: u64, texts_to_hide: &[&str]) -> String {
+	let mut file = File::open(log_file().unwrap()).unwrap();
+	file.seek(SeekFrom::Start(log_start)).unwrap();
 	let mut buf = String::new();
 	file.read_to_string(&mut buf).unwrap();
 	for text_to_hide in texts_to_hide.iter() {
 		buf = buf.replace(text_to_hide, "{REDACTED}");
 	}
-	buf
+	// Timestamps, e.g. when the merge was requested, differ between runs
+	let timestamp =
+		regex::Regex::new(r"\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}(\.\d+)?Z")
+			.unwrap();
+	timestamp.replace_all(&buf, "{TIMESTAMP}").into_owned()
 }
 
 pub fn clean_directory(dir: PathBuf) {
@@ -73,7 +109,7 @@ pub fn initialize_repository(repo_dir: &Path, initial_branch: &str) {
 		Some(repo_dir),
 		None,
 	);
-	fs::write(&repo_dir.join("README"), "").unwrap();
+	fs::write(repo_dir.join("README"), "").unwrap();
 	exec("git", &["add", "."], Some(&repo_dir), None);
 	exec(
 		"git",
@@ -1,10 +1,14 @@
 use httptest::{matchers::*, responders::*, Expectation, Server};
-use parity_processbot::{self, github};
+use parity_processbot::{
+	self, config::MainConfig, db, github, github_bot::GithubBot,
+	webhook::AppState, PlaceholderDeserializationItem,
+};
 use serde_json::json;
 use std::{
+	collections::HashMap,
 	env, fs,
 	io::Write,
-	path::PathBuf,
+	path::{Path, PathBuf},
 	process::{self, Command, Stdio},
 };
 use tempfile::TempDir;
@@ -12,10 +16,20 @@ use tempfile::TempDir;
 use super::{cmd::*, constants::*, *};
 
 pub struct CommonSetupOutput {
-	pub log_dir: TempDir,
+	// Not every test reads its logs (see read_snapshot)
+	#[allow(dead_code)]
+	pub log_start: u64,
 	pub db_dir: TempDir,
+	// Only held so that the daemon and its directory live as long as the test
+	#[allow(dead_code)]
 	pub git_daemon_handle: process::Child,
+	#[allow(dead_code)]
 	pub git_daemon_dir: TempDir,
+	// Used as the GitHub instance's URL so that the repositories are cloned from
+	// the git daemon
+	pub git_daemon_url: String,
+	// Where the bot clones the repositories to
+	pub repos_dir: TempDir,
 	pub private_key: Vec<u8>,
 	pub github_api: Server,
 	pub github_api_url: String,
@@ -26,24 +40,17 @@ pub struct CommonSetupOutput {
 	pub github_app_id: usize,
 	pub initial_branch: String,
 }
-pub fn common_setup() -> CommonSetupOutput {
+pub fn common_setup(initial_branch: &str) -> CommonSetupOutput {
 	let git_daemon_base_path_tracker =
 		env::var("GIT_DAEMON_BASE_PATH_TRACKER").unwrap();
 
-	let log_dir = tempfile::tempdir().unwrap();
-	flexi_logger::Logger::with_env_or_str("info")
-		.log_to_file()
-		.directory((&log_dir).path().to_path_buf())
-		.duplicate_to_stdout(flexi_logger::Duplicate::All)
-		.start()
-		.unwrap();
+	let log_start = log_file_len();
 
 	// The git daemon will be used for fetching and pushing branches during tests
 	let git_daemon_dir = tempfile::tempdir().unwrap();
 	let git_daemon_dir_path_str = git_daemon_dir.path().display().to_string();
 	{
 		let mut file = std::fs::OpenOptions::new()
-			.write(true)
 			.append(true)
 			.open(git_daemon_base_path_tracker)
 			.unwrap();
@@ -58,7 +65,7 @@ pub fn common_setup() -> CommonSetupOutput {
 		.arg("--export-all")
 		.arg("--enable=receive-pack")
 		.stdout(Stdio::null())
-		.current_dir((&git_daemon_dir).path())
+		.current_dir(git_daemon_dir.path())
 		.spawn()
 		.unwrap();
 
@@ -103,8 +110,12 @@ GcZ0izY/30012ajdHY+/QK5lsMoxTnn0skdS+spLxaS5ZEO4qvPVb8RAoCkWMMal
 	// Set up the Git repository with an initial commit
 	let repo = "repo";
 	let repo_full_name = format!("{}/{}", &owner.login, repo);
-	let repo_dir = git_daemon_dir.path().join(&owner.login).join(repo);
-	let initial_branch = "master";
+	// Named like the URLs which the bot clones from (see
+	// companion::git_remote_url)
+	let repo_dir = git_daemon_dir
+		.path()
+		.join(&owner.login)
+		.join(format!("{}.git", repo));
 	fs::create_dir_all(&repo_dir).unwrap();
 	initialize_repository(&repo_dir, initial_branch);
 
@@ -161,12 +172,53 @@ GcZ0izY/30012ajdHY+/QK5lsMoxTnn0skdS+spLxaS5ZEO4qvPVb8RAoCkWMMal
 		),
 	);
 
+	// Repositories don't have a configuration file (.github/processbot.toml)
+	let initial_branch_sha =
+		get_cmd_output("git", &["rev-parse", "HEAD"], Some(&repo_dir));
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/git/ref/heads/{}",
+				&repo_full_name, initial_branch
+			),
+		))
+		.times(0..)
+		.respond_with(json_encoded(github::Ref {
+			object: github::RefObject {
+				sha: initial_branch_sha.clone(),
+			},
+		})),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/contents/{}",
+				&repo_full_name,
+				parity_processbot::repository_config::REPOSITORY_CONFIG_PATH
+			),
+		))
+		.times(0..)
+		.respond_with(
+			status_code(404)
+				.append_header("Content-Type", "application/json")
+				.body(
+					serde_json::to_string(&json!({ "message": "Not Found" }))
+						.unwrap(),
+				),
+		),
+	);
+
 	let db_dir = tempfile::tempdir().unwrap();
+	let repos_dir = tempfile::tempdir().unwrap();
 
 	CommonSetupOutput {
-		log_dir,
+		log_start,
 		git_daemon_handle,
 		git_daemon_dir,
+		git_daemon_url: format!("git://127.0.0.1:{}", git_daemon_port),
+		repos_dir,
 		github_api,
 		github_api_url,
 		db_dir,
@@ -180,6 +232,8 @@ GcZ0izY/30012ajdHY+/QK5lsMoxTnn0skdS+spLxaS5ZEO4qvPVb8RAoCkWMMal
 	}
 }
 
+// Not every test needs the commit's statuses and checks
+#[allow(dead_code)]
 pub fn setup_commit(setup: &CommonSetupOutput, sha: &str) {
 	let CommonSetupOutput {
 		owner,
@@ -229,7 +283,6 @@ pub fn setup_commit(setup: &CommonSetupOutput, sha: &str) {
 }
 
 pub struct SetupPullRequestOutput {
-	pub url: String,
 	pub html_url: String,
 	pub number: i64,
 }
@@ -344,9 +397,10 @@ pub fn setup_pull_request(
 		.respond_with(json_encoded(github::PullRequest {
 			body: None,
 			number,
+			title: "change file".into(),
 			mergeable: Some(true),
 			html_url: html_url.clone(),
-			url: url.clone(),
+			url,
 			user: Some(owner.clone()),
 			base: github::Base {
 				ref_field: base_branch.to_string(),
@@ -381,9 +435,174 @@ pub fn setup_pull_request(
 		),
 	);
 
-	SetupPullRequestOutput {
-		url,
-		html_url,
-		number,
+	SetupPullRequestOutput { html_url, number }
+}
+
+// The payload of `body` being commented on the pull request by `owner`
+#[allow(dead_code)]
+pub fn bot_comment(
+	owner: &github::User,
+	repo: &github::Repository,
+	pr: &SetupPullRequestOutput,
+	body: &str,
+) -> github::Payload {
+	github::Payload::IssueComment {
+		action: github::IssueCommentAction::Created,
+		comment: github::Comment {
+			body: body.to_string(),
+			user: Some(owner.clone()),
+		},
+		issue: github::WebhookIssueComment {
+			number: pr.number,
+			html_url: pr.html_url.clone(),
+			repository_url: repo.html_url.clone(),
+			pull_request: Some(PlaceholderDeserializationItem {}),
+		},
+	}
+}
+
+// Create a branch off the base branch with a commit which changes `file` and
+// return the commit's SHA
+pub fn create_pr_branch(
+	repo_dir: &Path,
+	base_branch: &str,
+	pr_branch: &str,
+	file: &str,
+) -> String {
+	exec(
+		"git",
+		&["checkout", base_branch],
+		Some(repo_dir),
+		Some(CmdConfiguration::IgnoreStderrStartingWith(&[
+			"Switched to branch",
+			"Already on",
+		])),
+	);
+	exec(
+		"git",
+		&["checkout", "-b", pr_branch],
+		Some(repo_dir),
+		Some(CmdConfiguration::IgnoreStderrStartingWith(&[
+			"Switched to a new branch",
+		])),
+	);
+
+	fs::write(repo_dir.join(file), "this file has changed").unwrap();
+	exec("git", &["add", "."], Some(repo_dir), None);
+	exec(
+		"git",
+		&["commit", "-m", "change file"],
+		Some(repo_dir),
+		None,
+	);
+	get_cmd_output("git", &["rev-parse", "HEAD"], Some(repo_dir))
+}
+
+pub fn repository(common_setup: &CommonSetupOutput) -> github::Repository {
+	let CommonSetupOutput {
+		owner,
+		repo_name,
+		repo_full_name,
+		..
+	} = common_setup;
+	github::Repository {
+		name: repo_name.to_string(),
+		full_name: repo_full_name.clone(),
+		owner: owner.clone(),
+		html_url: format!(
+			"{}/{}",
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER, repo_full_name
+		),
+	}
+}
+
+pub fn main_config(common_setup: &CommonSetupOutput) -> MainConfig {
+	let CommonSetupOutput {
+		github_api_url,
+		db_dir,
+		owner,
+		private_key,
+		github_app_id,
+		git_daemon_url,
+		repos_dir,
+		..
+	} = common_setup;
+
+	MainConfig {
+		installation_login: owner.login.clone(),
+		webhook_secrets: vec!["does not matter".to_owned()],
+		webhook_allow_sha1: false,
+		webhook_port: "does not matter".to_string(),
+		db_path: db_dir.path().to_path_buf(),
+		repos_path: repos_dir.path().to_path_buf(),
+		private_key: private_key.clone(),
+		webhook_proxy_url: None,
+		disable_org_check: false,
+		github_api_url: github_api_url.clone(),
+		github_url: git_daemon_url.clone(),
+		github_app_id: *github_app_id,
+		merge_command_delay: 0,
+		companion_status_settle_delay: 0,
+		github_source_prefix: git_daemon_url.clone(),
+		github_source_suffix: "".into(),
+		merge_priority_maintainers: vec![],
+		merge_priority_label: "A-merge-priority".into(),
+		batch_merge_repositories: HashMap::new(),
+		admin_token: None,
+		metrics_token: None,
+		delivery_ttl_hours: 24,
+		shutdown_timeout_secs: 10,
+		github_api_max_retries: 0,
+		github_api_retry_base_delay_ms: 0,
 	}
 }
+
+pub fn app_state(config: MainConfig) -> AppState {
+	let github_bot = GithubBot::new(&config);
+	let db = db::open(&config.db_path).unwrap();
+	AppState {
+		db,
+		github_bot,
+		config,
+	}
+}
+
+// Clone the repository to where the bot would clone it (see
+// companion::clone_repository) so that its commits, e.g. when updating a pull
+// request's branch, have an author; the bot's deployment configures it globally
+#[allow(dead_code)]
+pub fn setup_bot_clone(common_setup: &CommonSetupOutput) {
+	let CommonSetupOutput {
+		owner,
+		repo_name,
+		repo_dir,
+		repos_dir,
+		..
+	} = common_setup;
+
+	let clone_dir = repos_dir.path().join(&owner.login).join(repo_name);
+	fs::create_dir_all(&clone_dir).unwrap();
+	exec(
+		"git",
+		&[
+			"clone",
+			"--quiet",
+			&repo_dir.display().to_string(),
+			&clone_dir.display().to_string(),
+		],
+		None::<&Path>,
+		None,
+	);
+	exec(
+		"git",
+		&["config", "--local", "user.name", "processbot"],
+		Some(&clone_dir),
+		None,
+	);
+	exec(
+		"git",
+		&["config", "--local", "user.email", "foo@bar.com"],
+		Some(&clone_dir),
+		None,
+	);
+}
@@ -1,125 +1,153 @@
+use httptest::{matchers::*, responders::*, Expectation};
 use insta::assert_snapshot;
-use parity_processbot::{
-	config::MainConfig,
-	github,
-	github_bot::GithubBot,
-	webhook::{handle_payload, AppState},
-	PlaceholderDeserializationItem,
-};
-use rocksdb::DB;
-use std::fs;
+use parity_processbot::{db, webhook::handle_payload};
+use serde_json::json;
+use std::time::Duration;
+use tokio::time::delay_for;
 
 mod helpers;
 
-use helpers::{cmd::*, constants::*, read_snapshot, setup::*};
+use helpers::{cmd::*, read_snapshot, setup::*};
 
 #[tokio::test]
 async fn simple_merge_succeeds() {
-	let common_setup = common_setup();
+	assert_snapshot!(simple_merge("master").await);
+}
+
+#[tokio::test]
+async fn simple_merge_into_non_master_base_succeeds() {
+	assert_snapshot!(simple_merge("main").await);
+}
+
+// Comments `bot merge` on a pull request whose checks have passed and returns
+// the logs of the merge
+async fn simple_merge(base_branch: &str) -> String {
+	let common_setup = common_setup(base_branch);
 	let CommonSetupOutput {
-		log_dir,
-		github_api_url,
-		db_dir,
+		log_start,
 		owner,
 		repo_dir,
-		private_key,
-		github_app_id,
-		repo_name,
-		repo_full_name,
-		git_daemon_dir,
 		..
 	} = &common_setup;
 
-	// Create PR branch
 	let pr_branch = "contributor_patches";
+	let pr_head_sha = create_pr_branch(repo_dir, base_branch, pr_branch, "foo");
+
+	// Setup the commit in the API so that the status checks criterion will pass
+	setup_commit(&common_setup, &pr_head_sha);
+
+	let repo = repository(&common_setup);
+	let pr =
+		&setup_pull_request(&common_setup, &repo, &pr_head_sha, pr_branch, 1);
+
+	let state = app_state(main_config(&common_setup));
+	let _ = handle_payload(bot_comment(owner, &repo, pr, "bot merge"), &state).await;
+
+	read_snapshot(*log_start, &[&pr_head_sha])
+}
+
+// Comments `bot merge` on two pull requests with the same base branch while the
+// first one is being merged: the second one has to wait for its turn instead of
+// being merged at the same time
+#[tokio::test]
+async fn concurrent_merges_into_the_same_base_branch_are_queued() {
+	let base_branch = "master";
+	let common_setup = common_setup(base_branch);
+	let CommonSetupOutput {
+		owner,
+		repo_dir,
+		repo_full_name,
+		github_api,
+		..
+	} = &common_setup;
+
+	let repo = repository(&common_setup);
+	let mut prs = vec![];
+	for (number, file) in [(1, "foo"), (2, "bar")].iter() {
+		let pr_branch = format!("contributor_patches_{}", number);
+		let pr_head_sha =
+			create_pr_branch(repo_dir, base_branch, &pr_branch, file);
+		setup_commit(&common_setup, &pr_head_sha);
+		prs.push(setup_pull_request(
+			&common_setup,
+			&repo,
+			&pr_head_sha,
+			&pr_branch,
+			*number,
+		));
+	}
 	exec(
 		"git",
-		&["checkout", "-b", pr_branch],
+		&["checkout", base_branch],
 		Some(repo_dir),
 		Some(CmdConfiguration::IgnoreStderrStartingWith(&[
-			"Switched to a new branch",
+			"Switched to branch",
 		])),
 	);
 
-	// Add a commit to the PR's branch
-	fs::write(repo_dir.join("foo"), "this file has changed").unwrap();
-	exec("git", &["add", "."], Some(repo_dir), None);
-	exec(
-		"git",
-		&["commit", "-m", "change file"],
-		Some(repo_dir),
-		None,
+	// The first merge takes a while and GitHub responds that it should be
+	// attempted again later, which leaves the first pull request at the head of
+	// the queue
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"PUT",
+			format!("/repos/{}/pulls/1/merge", repo_full_name),
+		))
+		.times(1)
+		.respond_with(delay_and_then(
+			Duration::from_millis(500),
+			status_code(405)
+				.append_header("Content-Type", "application/json")
+				.body(
+					serde_json::to_string(&json!({
+						"message": "Required status check \"ci\" is expected."
+					}))
+					.unwrap(),
+				),
+		)),
 	);
-	let pr_head_sha =
-		get_cmd_output("git", &["rev-parse", "HEAD"], Some(&repo_dir));
-
-	// Setup the commit in the API so that the status checks criterion will pass
-	setup_commit(&common_setup, &pr_head_sha);
-
-	let repo = github::Repository {
-		name: repo_name.to_string(),
-		full_name: repo_full_name.clone(),
-		owner: owner.clone(),
-		html_url: format!(
-			"{}/{}",
-			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER, repo_full_name
-		),
-	};
-
-	let mut next_pr_number: i64 = 0;
-	next_pr_number += 1;
-	let pr = &setup_pull_request(
-		&common_setup,
-		&repo,
-		&pr_head_sha,
-		pr_branch,
-		next_pr_number,
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"PUT",
+			format!("/repos/{}/pulls/2/merge", repo_full_name),
+		))
+		.times(0)
+		.respond_with(status_code(500)),
 	);
 
-	let config = MainConfig {
-		installation_login: owner.login.clone(),
-		webhook_secret: "does not matter".to_owned(),
-		webhook_port: "does not matter".to_string(),
-		db_path: db_dir.path().to_path_buf(),
-		repos_path: git_daemon_dir.path().to_path_buf(),
-		private_key: private_key.clone(),
-		webhook_proxy_url: None,
-		disable_org_check: false,
-		github_api_url: github_api_url.clone(),
-		github_app_id: *github_app_id,
-		merge_command_delay: 0,
-		companion_status_settle_delay: 0,
-		github_source_prefix: "https://github.com".into(),
-		github_source_suffix: "".into(),
-	};
-	let github_bot = GithubBot::new(&config);
-	let db = DB::open_default(&config.db_path).unwrap();
-	let state = AppState {
-		db,
-		github_bot,
-		config,
-	};
-
-	let _ = handle_payload(
-		github::Payload::IssueComment {
-			action: github::IssueCommentAction::Created,
-			comment: github::Comment {
-				body: "bot merge".to_string(),
-				user: Some(owner.clone()),
-			},
-			issue: github::WebhookIssueComment {
-				number: pr.number,
-				html_url: pr.html_url.clone(),
-				repository_url: repo.html_url.clone(),
-				pull_request: Some(PlaceholderDeserializationItem {}),
-			},
-		},
-		&state,
-	)
-	.await;
+	let state = app_state(main_config(&common_setup));
+	// The futures are boxed since they're too big for the test thread's stack
+	let _ = tokio::join!(
+		Box::pin(handle_payload(bot_comment(owner, &repo, &prs[0], "bot merge"), &state)),
+		Box::pin(async {
+			// Arrives while the first pull request is being merged
+			delay_for(Duration::from_millis(200)).await;
+			handle_payload(
+				bot_comment(owner, &repo, &prs[1], "bot merge"),
+				&state,
+			).await
+		})
+	);
 
-	assert_snapshot!(read_snapshot(
-		log_dir.path().to_path_buf(),
-		&[&pr_head_sha]
-	));
+	let queue =
+		db::get_merge_queue(&state.db, &owner.login, &repo.name, base_branch)
+			.unwrap();
+	assert_eq!(
+		queue
+			.entries
+			.iter()
+			.map(|entry| entry.number)
+			.collect::<Vec<_>>(),
+		vec![1, 2]
+	);
+	for pr in &prs {
+		assert!(db::get_merge_request(
+			&state.db,
+			&owner.login,
+			&repo.name,
+			pr.number
+		)
+		.unwrap()
+		.is_some());
+	}
 }
@@ -0,0 +1,399 @@
+use httptest::{matchers::*, responders::*, Expectation};
+use parity_processbot::{
+	db, github, lock,
+	merge_batch::{self, MergeBatch},
+	merge_queue::{self, MergeQueueEntry},
+	webhook::{self, AppState, MergeRequest, MergeState},
+};
+use std::{sync::Arc, time::Duration};
+use tokio::time::delay_for;
+
+mod helpers;
+
+use helpers::{cmd::*, setup::*};
+
+// Sets up pull requests whose checks have passed, registers their merge
+// requests and queues them in batches of up to `batch_size`
+fn setup_batch(
+	common_setup: &CommonSetupOutput,
+	batch_size: usize,
+	files: &[&str],
+) -> AppState {
+	let CommonSetupOutput {
+		owner,
+		repo_dir,
+		repo_full_name,
+		initial_branch: base_branch,
+		..
+	} = common_setup;
+
+	let repo = repository(common_setup);
+	let mut config = main_config(common_setup);
+	config
+		.batch_merge_repositories
+		.insert(repo_full_name.clone(), batch_size);
+	let state = app_state(config);
+	setup_bot_clone(common_setup);
+
+	for (index, file) in files.iter().enumerate() {
+		let number = index as i64 + 1;
+		let pr_branch = format!("contributor_patches_{}", number);
+		let pr_head_sha =
+			create_pr_branch(repo_dir, base_branch, &pr_branch, file);
+		// The batches are built from the pull requests' refs
+		exec(
+			"git",
+			&[
+				"update-ref",
+				&format!("refs/pull/{}/head", number),
+				&pr_head_sha,
+			],
+			Some(repo_dir),
+			None,
+		);
+		setup_commit(common_setup, &pr_head_sha);
+		let pr = setup_pull_request(
+			common_setup,
+			&repo,
+			&pr_head_sha,
+			&pr_branch,
+			number,
+		);
+
+		db::put_merge_request(
+			&state.db,
+			&MergeRequest {
+				sha: pr_head_sha,
+				was_updated: false,
+				owner: owner.login.clone(),
+				repo: repo.name.clone(),
+				number: pr.number,
+				html_url: pr.html_url.clone(),
+				requested_by: owner.login.clone(),
+				dependencies: None,
+				merge_method: None,
+				requested_at: None,
+				state: MergeState::Queued,
+			},
+		)
+		.unwrap();
+		merge_queue::enqueue(
+			&state.db,
+			&owner.login,
+			&repo.name,
+			base_branch,
+			MergeQueueEntry {
+				number: pr.number,
+				html_url: pr.html_url,
+				requested_by: owner.login.clone(),
+				is_priority: false,
+			},
+		)
+		.unwrap();
+	}
+	exec(
+		"git",
+		&["checkout", base_branch],
+		Some(repo_dir),
+		Some(CmdConfiguration::IgnoreStderrStartingWith(&[
+			"Switched to branch",
+		])),
+	);
+
+	state
+}
+
+fn setup_failed_commit(common_setup: &CommonSetupOutput, sha: &str) {
+	let CommonSetupOutput {
+		repo_full_name,
+		github_api,
+		..
+	} = common_setup;
+
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/commits/{}/status", repo_full_name, sha),
+		))
+		.times(0..)
+		.respond_with(json_encoded(github::CombinedStatus {
+			statuses: vec![github::Status {
+				id: 1,
+				context: "does not matter".to_string(),
+				description: Some("does not matter".to_string()),
+				state: github::StatusState::Failure,
+			}],
+		})),
+	);
+}
+
+fn current_batch(
+	common_setup: &CommonSetupOutput,
+	state: &AppState,
+) -> MergeBatch {
+	let CommonSetupOutput {
+		owner,
+		repo_name,
+		initial_branch: base_branch,
+		..
+	} = common_setup;
+	db::get_merge_batch(&state.db, &owner.login, repo_name, base_branch)
+		.unwrap()
+		.unwrap()
+}
+
+fn queued_numbers(
+	common_setup: &CommonSetupOutput,
+	state: &AppState,
+) -> Vec<i64> {
+	let CommonSetupOutput {
+		owner,
+		repo_name,
+		initial_branch: base_branch,
+		..
+	} = common_setup;
+	db::get_merge_queue(&state.db, &owner.login, repo_name, base_branch)
+		.unwrap()
+		.entries
+		.iter()
+		.map(|entry| entry.number)
+		.collect()
+}
+
+#[tokio::test]
+async fn batch_is_built_from_the_ready_pull_requests() {
+	build_batch("master").await;
+}
+
+#[tokio::test]
+async fn batch_is_built_for_a_non_master_base_branch() {
+	build_batch("main").await;
+}
+
+async fn build_batch(base_branch: &str) {
+	let common_setup = common_setup(base_branch);
+	let CommonSetupOutput {
+		owner,
+		repo_name,
+		repo_dir,
+		..
+	} = &common_setup;
+	let state = setup_batch(&common_setup, 2, &["foo", "bar", "baz"]);
+
+	Box::pin(merge_batch::process_queue(
+		&state,
+		&owner.login,
+		repo_name,
+		base_branch,
+	))
+	.await
+	.unwrap();
+
+	// Only up to the batch size is taken from the queue
+	let batch = current_batch(&common_setup, &state);
+	assert_eq!(batch.numbers, vec![1, 2]);
+	assert!(batch.pending.is_empty());
+	assert_eq!(
+		get_cmd_output(
+			"git",
+			&["rev-parse", &format!("processbot/batch/{}", base_branch)],
+			Some(repo_dir)
+		),
+		batch.candidate_sha
+	);
+	assert_eq!(
+		get_cmd_output(
+			"git",
+			&[
+				"ls-tree",
+				"--name-only",
+				&batch.candidate_sha,
+				"foo",
+				"bar",
+				"baz"
+			],
+			Some(repo_dir)
+		),
+		"bar\nfoo"
+	);
+	assert_eq!(queued_numbers(&common_setup, &state), vec![1, 2, 3]);
+}
+
+#[tokio::test]
+async fn failed_batch_is_bisected_until_its_culprit_is_cancelled() {
+	let common_setup = common_setup("master");
+	let CommonSetupOutput {
+		owner, repo_name, ..
+	} = &common_setup;
+	let state = Arc::new(setup_batch(&common_setup, 2, &["foo", "bar"]));
+
+	Box::pin(merge_batch::process_queue(
+		&state,
+		&owner.login,
+		repo_name,
+		"master",
+	))
+	.await
+	.unwrap();
+	let batch = current_batch(&common_setup, &state);
+	assert_eq!(batch.numbers, vec![1, 2]);
+
+	// The first half is tested next while the second one is left pending
+	setup_failed_commit(&common_setup, &batch.candidate_sha);
+	Box::pin(merge_batch::check_batch(&state, &batch))
+		.await
+		.unwrap();
+	let batch = current_batch(&common_setup, &state);
+	assert_eq!(batch.numbers, vec![1]);
+	assert_eq!(batch.pending, vec![vec![2]]);
+
+	// The culprit is isolated in a batch of its own, so its merge is cancelled,
+	// but not while its pull request is being processed elsewhere
+	setup_failed_commit(&common_setup, &batch.candidate_sha);
+	let mut check = None;
+	lock::with_locks(
+		vec![lock::pull_request_key(&owner.login, repo_name, 1)],
+		async {
+			check = Some(tokio::spawn({
+				let state = state.clone();
+				let batch = batch.clone();
+				async move {
+					Box::pin(merge_batch::check_batch(&state, &batch)).await
+				}
+			}));
+			delay_for(Duration::from_millis(200)).await;
+			assert!(db::get_merge_request(
+				&state.db,
+				&owner.login,
+				repo_name,
+				1
+			)
+			.unwrap()
+			.is_some());
+			assert_eq!(current_batch(&common_setup, &state).numbers, vec![1]);
+		},
+	)
+	.await;
+	check.unwrap().await.unwrap().unwrap();
+
+	assert!(db::get_merge_request(&state.db, &owner.login, repo_name, 1)
+		.unwrap()
+		.is_none());
+	assert_eq!(queued_numbers(&common_setup, &state), vec![2]);
+	let batch = current_batch(&common_setup, &state);
+	assert_eq!(batch.numbers, vec![2]);
+	assert!(batch.pending.is_empty());
+}
+
+#[tokio::test]
+async fn pull_request_which_cant_be_batched_is_cancelled_once_it_is_not_being_processed(
+) {
+	let common_setup = common_setup("master");
+	let CommonSetupOutput {
+		owner, repo_name, ..
+	} = &common_setup;
+	let state = Arc::new(setup_batch(&common_setup, 2, &["foo", "bar"]));
+
+	// The first pull request's branch was pushed to after its merge was
+	// requested, therefore it can't be batched
+	let mut mr = db::get_merge_request(&state.db, &owner.login, repo_name, 1)
+		.unwrap()
+		.unwrap();
+	mr.sha = "0".repeat(40);
+	db::put_merge_request(&state.db, &mr).unwrap();
+
+	let mut process = None;
+	lock::with_locks(
+		vec![lock::pull_request_key(&owner.login, repo_name, 1)],
+		async {
+			process = Some(tokio::spawn({
+				let state = state.clone();
+				let owner = owner.login.clone();
+				let repo_name = *repo_name;
+				async move {
+					Box::pin(merge_batch::process_queue(
+						&state, &owner, repo_name, "master",
+					))
+					.await
+				}
+			}));
+			delay_for(Duration::from_millis(200)).await;
+			assert!(db::get_merge_request(
+				&state.db,
+				&owner.login,
+				repo_name,
+				1
+			)
+			.unwrap()
+			.is_some());
+			assert_eq!(queued_numbers(&common_setup, &state), vec![1, 2]);
+		},
+	)
+	.await;
+	process.unwrap().await.unwrap().unwrap();
+
+	assert!(db::get_merge_request(&state.db, &owner.login, repo_name, 1)
+		.unwrap()
+		.is_none());
+	assert_eq!(queued_numbers(&common_setup, &state), vec![2]);
+	let batch = current_batch(&common_setup, &state);
+	assert_eq!(batch.numbers, vec![2]);
+}
+
+#[tokio::test]
+async fn statuses_of_a_batch_and_of_its_pull_requests_are_processed_concurrently(
+) {
+	let common_setup = common_setup("master");
+	let CommonSetupOutput {
+		owner, repo_name, ..
+	} = &common_setup;
+	let state = Arc::new(setup_batch(&common_setup, 2, &["foo", "bar"]));
+
+	Box::pin(merge_batch::process_queue(
+		&state,
+		&owner.login,
+		repo_name,
+		"master",
+	))
+	.await
+	.unwrap();
+	let batch = current_batch(&common_setup, &state);
+	setup_failed_commit(&common_setup, &batch.candidate_sha);
+
+	// Each pull request's status takes its own lock and then the queue's, while
+	// the batch's status takes all of them at once, so none of them should end
+	// up waiting on another
+	let shas = [1, 2]
+		.iter()
+		.map(|number| {
+			db::get_merge_request(&state.db, &owner.login, repo_name, *number)
+				.unwrap()
+				.unwrap()
+				.sha
+		})
+		.chain(std::iter::once(batch.candidate_sha.clone()))
+		.collect::<Vec<_>>();
+	let checks = shas
+		.into_iter()
+		.map(|sha| {
+			tokio::spawn({
+				let state = state.clone();
+				async move {
+					Box::pin(webhook::checks_and_status(&state, &sha)).await
+				}
+			})
+		})
+		.collect::<Vec<_>>();
+	for check in checks {
+		tokio::time::timeout(Duration::from_secs(10), check)
+			.await
+			.expect("checks_and_status deadlocked")
+			.unwrap()
+			.unwrap();
+	}
+
+	let batch = current_batch(&common_setup, &state);
+	assert_eq!(batch.numbers, vec![1]);
+	assert_eq!(batch.pending, vec![vec![2]]);
+	assert_eq!(queued_numbers(&common_setup, &state), vec![1, 2]);
+}
@@ -0,0 +1,241 @@
+use httptest::{matchers::*, responders::*, Expectation};
+use parity_processbot::{
+	companion, github, repository_config::REPOSITORY_CONFIG_PATH,
+	webhook::handle_payload,
+};
+use std::{collections::HashSet, fs, path::Path};
+
+mod helpers;
+
+use helpers::{cmd::*, setup::*};
+
+// Commit `contents` to `file` on the base branch so that the pull requests'
+// branches are behind it and return the commit's SHA
+fn advance_base_branch(
+	repo_dir: &Path,
+	base_branch: &str,
+	file: &str,
+	contents: &str,
+) -> String {
+	exec(
+		"git",
+		&["checkout", base_branch],
+		Some(repo_dir),
+		Some(CmdConfiguration::IgnoreStderrStartingWith(&[
+			"Switched to branch",
+			"Already on",
+		])),
+	);
+	let path = repo_dir.join(file);
+	fs::create_dir_all(path.parent().unwrap()).unwrap();
+	fs::write(path, contents).unwrap();
+	exec("git", &["add", "."], Some(repo_dir), None);
+	exec(
+		"git",
+		&["commit", "-m", "change base branch"],
+		Some(repo_dir),
+		None,
+	);
+	get_cmd_output("git", &["rev-parse", "HEAD"], Some(repo_dir))
+}
+
+// The commit at the head of `branch` followed by its parents
+fn head_and_parents(repo_dir: &Path, branch: &str) -> Vec<String> {
+	get_cmd_output(
+		"git",
+		&["rev-list", "--parents", "-n", "1", branch],
+		Some(repo_dir),
+	)
+	.split_whitespace()
+	.map(|sha| sha.to_owned())
+	.collect()
+}
+
+#[tokio::test]
+async fn rebase_command_merges_the_base_branch_by_default() {
+	let base_branch = "master";
+	let common_setup = common_setup(base_branch);
+	let CommonSetupOutput {
+		owner, repo_dir, ..
+	} = &common_setup;
+
+	let pr_branch = "contributor_patches";
+	let pr_head_sha = create_pr_branch(repo_dir, base_branch, pr_branch, "foo");
+	let base_head_sha = advance_base_branch(
+		repo_dir,
+		base_branch,
+		"bar",
+		"the base branch has changed",
+	);
+
+	let repo = repository(&common_setup);
+	let pr =
+		setup_pull_request(&common_setup, &repo, &pr_head_sha, pr_branch, 1);
+	setup_bot_clone(&common_setup);
+
+	// The repository doesn't configure a rebase_strategy, therefore the branch
+	// must not be rewritten
+	let state = app_state(main_config(&common_setup));
+	Box::pin(handle_payload(
+		bot_comment(owner, &repo, &pr, "bot rebase"),
+		&state,
+	))
+	.await
+	.1
+	.unwrap();
+
+	let commits = head_and_parents(repo_dir, pr_branch);
+	assert_eq!(commits[1..], [pr_head_sha, base_head_sha]);
+}
+
+#[tokio::test]
+async fn update_command_merges_a_non_master_base_branch() {
+	let base_branch = "main";
+	let common_setup = common_setup(base_branch);
+	let CommonSetupOutput {
+		owner, repo_dir, ..
+	} = &common_setup;
+
+	let pr_branch = "contributor_patches";
+	let pr_head_sha = create_pr_branch(repo_dir, base_branch, pr_branch, "foo");
+	let base_head_sha = advance_base_branch(
+		repo_dir,
+		base_branch,
+		"bar",
+		"the base branch has changed",
+	);
+
+	let repo = repository(&common_setup);
+	let pr =
+		setup_pull_request(&common_setup, &repo, &pr_head_sha, pr_branch, 1);
+	setup_bot_clone(&common_setup);
+
+	let state = app_state(main_config(&common_setup));
+	Box::pin(handle_payload(
+		bot_comment(owner, &repo, &pr, "bot update"),
+		&state,
+	))
+	.await
+	.1
+	.unwrap();
+
+	let commits = head_and_parents(repo_dir, pr_branch);
+	assert_eq!(commits[1..], [pr_head_sha, base_head_sha]);
+}
+
+#[tokio::test]
+async fn rebase_command_rebases_onto_a_non_master_base_branch() {
+	let base_branch = "main";
+	let common_setup = common_setup(base_branch);
+	let CommonSetupOutput {
+		owner,
+		repo_dir,
+		repo_full_name,
+		github_api,
+		..
+	} = &common_setup;
+
+	let pr_branch = "contributor_patches";
+	let pr_head_sha = create_pr_branch(repo_dir, base_branch, pr_branch, "foo");
+	// The configuration is committed to the base branch so that its head, which
+	// the repository configuration is cached by, is specific to this test
+	let repository_config = "rebase_strategy = \"rebase\"";
+	let base_head_sha = advance_base_branch(
+		repo_dir,
+		base_branch,
+		REPOSITORY_CONFIG_PATH,
+		repository_config,
+	);
+
+	let repo = repository(&common_setup);
+	let pr =
+		setup_pull_request(&common_setup, &repo, &pr_head_sha, pr_branch, 1);
+	setup_bot_clone(&common_setup);
+
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/git/ref/heads/{}", repo_full_name, base_branch),
+		))
+		.times(0..)
+		.respond_with(json_encoded(github::Ref {
+			object: github::RefObject {
+				sha: base_head_sha.clone(),
+			},
+		})),
+	);
+	// Takes precedence over the configuration-less repository of common_setup
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/contents/{}",
+				repo_full_name, REPOSITORY_CONFIG_PATH
+			),
+		))
+		.times(0..)
+		.respond_with(json_encoded(github::Contents {
+			content: base64::encode(repository_config),
+		})),
+	);
+
+	let state = app_state(main_config(&common_setup));
+	Box::pin(handle_payload(
+		bot_comment(owner, &repo, &pr, "bot rebase"),
+		&state,
+	))
+	.await
+	.1
+	.unwrap();
+
+	let commits = head_and_parents(repo_dir, pr_branch);
+	assert_eq!(commits[1..], [base_head_sha]);
+	assert_eq!(
+		get_cmd_output(
+			"git",
+			&["log", "-1", "--format=%s", pr_branch],
+			Some(repo_dir)
+		),
+		"change file"
+	);
+}
+
+#[tokio::test]
+async fn companion_update_merges_a_non_master_base_branch() {
+	let base_branch = "main";
+	let common_setup = common_setup(base_branch);
+	let CommonSetupOutput {
+		owner,
+		repo_name,
+		repo_dir,
+		..
+	} = &common_setup;
+
+	let pr_branch = "contributor_patches";
+	let pr_head_sha = create_pr_branch(repo_dir, base_branch, pr_branch, "foo");
+	let base_head_sha = advance_base_branch(
+		repo_dir,
+		base_branch,
+		"bar",
+		"the base branch has changed",
+	);
+	setup_bot_clone(&common_setup);
+
+	let state = app_state(main_config(&common_setup));
+	let updated_sha = companion::update_pr_branch(
+		&state,
+		&owner.login,
+		repo_name,
+		&owner.login,
+		repo_name,
+		pr_branch,
+		base_branch,
+		&HashSet::new(),
+		1,
+	)
+	.await
+	.unwrap();
+
+	let commits = head_and_parents(repo_dir, pr_branch);
+	assert_eq!(commits, [updated_sha, pr_head_sha, base_head_sha]);
+}